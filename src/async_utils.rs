@@ -1,4 +1,5 @@
 use {
+    crate::checksum::{ObjectChecksums, PartChecksums},
     async_compression::tokio::write::GzipEncoder,
     futures::{
         future::{Pending, pending},
@@ -8,16 +9,22 @@ use {
         future::Future,
         io::{Error as IOError, IoSlice},
         pin::Pin,
+        sync::{Arc, Mutex},
         task::{Context, Poll},
         time::Duration,
     },
     tokio::{
         fs::File as TokioFile,
-        io::AsyncWrite,
+        io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+        sync::{mpsc::Receiver, oneshot},
         time::{Sleep, sleep},
     },
 };
 
+/// The longest partial line we're willing to hold while waiting for its newline. Anything longer is passed through as
+/// if it were complete so a misbehaving writer can't exhaust memory.
+const MAX_PARTIAL_LINE: usize = 1 << 20;
+
 /// A union that allows us to either sleep or wait forever.
 pub(crate) enum MaybeTimeout {
     Pending(Pin<Box<Pending<()>>>),
@@ -87,10 +94,61 @@ where
     }
 }
 
-/// A union type for holding either a plain Tokio file or a Tokio file wrapped in a Gzip encoder.
+/// A Tokio file that computes part checksums of everything written to it, if asked to.
+pub(crate) struct ChecksumFile {
+    file: TokioFile,
+    checksums: Option<PartChecksums>,
+}
+
+impl ChecksumFile {
+    pub fn new(file: TokioFile, checksums: Option<PartChecksums>) -> Self {
+        Self {
+            file,
+            checksums,
+        }
+    }
+
+    /// Return the file and the checksums of what was written to it.
+    pub fn into_parts(self) -> (TokioFile, Option<ObjectChecksums>) {
+        (self.file, self.checksums.map(PartChecksums::finish))
+    }
+}
+
+impl AsyncWrite for ChecksumFile {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, IOError>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.file).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n_written)), Some(checksums)) = (&result, &mut this.checksums) {
+            checksums.update(&buf[..*n_written]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IOError>> {
+        Pin::new(&mut self.get_mut().file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IOError>> {
+        Pin::new(&mut self.get_mut().file).poll_shutdown(cx)
+    }
+}
+
+/// A union type for holding either a plain file or a file wrapped in a Gzip encoder.
 pub(crate) enum MaybeCompressedFile {
-    Gzip(GzipEncoder<TokioFile>),
-    Uncompressed(TokioFile),
+    Gzip(GzipEncoder<ChecksumFile>),
+    Uncompressed(ChecksumFile),
+}
+
+impl MaybeCompressedFile {
+    /// Flush everything written so far, including anything the encoder is holding, to disk.
+    pub async fn sync_data(&mut self) -> Result<(), IOError> {
+        self.flush().await?;
+        let file = match self {
+            Self::Gzip(g) => g.get_mut(),
+            Self::Uncompressed(u) => u,
+        };
+        file.file.sync_data().await
+    }
 }
 
 impl AsyncWrite for MaybeCompressedFile {
@@ -157,3 +215,165 @@ impl AsyncWrite for MaybeCompressedFile {
         }
     }
 }
+
+/// A chunk of input sent to a [`ChannelReader`], optionally with a way to tell the producer when it has been consumed.
+pub(crate) trait Chunk {
+    fn into_parts(self) -> (Vec<u8>, Option<Ack>);
+}
+
+impl Chunk for Vec<u8> {
+    fn into_parts(self) -> (Vec<u8>, Option<Ack>) {
+        (self, None)
+    }
+}
+
+/// When a chunk's producer wants to hear about it. If the chunk is lost instead, the sender is dropped.
+pub(crate) enum Ack {
+    /// Once the chunk has been written to the batch's file and synced to disk.
+    Synced(oneshot::Sender<()>),
+
+    /// Once the batch holding the chunk has been uploaded (`true`), or has failed to upload and been kept (`false`).
+    Uploaded(oneshot::Sender<bool>),
+}
+
+/// A chunk whose producer is waiting to hear that it has been handled.
+pub(crate) struct AckedChunk {
+    pub data: Vec<u8>,
+    pub ack: Ack,
+}
+
+impl Chunk for AckedChunk {
+    fn into_parts(self) -> (Vec<u8>, Option<Ack>) {
+        (self.data, Some(self.ack))
+    }
+}
+
+/// Acknowledgements for chunks a [`ChannelReader`] has handed out that are waiting on the batch they were written to.
+#[derive(Clone, Default)]
+pub(crate) struct PendingAcks {
+    synced: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+    uploaded: Arc<Mutex<Vec<oneshot::Sender<bool>>>>,
+}
+
+impl PendingAcks {
+    fn push(&self, ack: Ack) {
+        match ack {
+            Ack::Synced(ack) => self.synced.lock().unwrap_or_else(|e| e.into_inner()).push(ack),
+            Ack::Uploaded(ack) => self.uploaded.lock().unwrap_or_else(|e| e.into_inner()).push(ack),
+        }
+    }
+
+    /// Take the acknowledgements to send once the current batch's file has been synced.
+    pub fn take_synced(&self) -> Vec<oneshot::Sender<()>> {
+        std::mem::take(&mut *self.synced.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Take the acknowledgements to send once the current batch has been uploaded or has failed.
+    pub fn take_uploaded(&self) -> Vec<oneshot::Sender<bool>> {
+        std::mem::take(&mut *self.uploaded.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// An `AsyncRead` over a channel of byte chunks. This lets producers that aren't simple streams (followed files,
+/// sockets, etc.) feed the batching loop. End-of-file is reported once every sender has been dropped.
+///
+/// A chunk's acknowledgement is moved to the reader's [`PendingAcks`] as soon as the chunk has been fully handed out.
+/// The batching loop writes everything it reads before acting on them, so the chunk is in the batch by then.
+pub(crate) struct ChannelReader<T: Chunk = Vec<u8>> {
+    rx: Receiver<T>,
+    chunk: Vec<u8>,
+    pos: usize,
+    ack: Option<Ack>,
+    acks: PendingAcks,
+}
+
+impl<T: Chunk> ChannelReader<T> {
+    pub fn new(rx: Receiver<T>) -> Self {
+        Self {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+            ack: None,
+            acks: PendingAcks::default(),
+        }
+    }
+
+    /// Return the acknowledgements waiting on the batch the reader's chunks were written to.
+    pub fn acks(&self) -> PendingAcks {
+        self.acks.clone()
+    }
+
+    /// Note that the current chunk has been handed out in full.
+    fn handed_out(&mut self) {
+        if let Some(ack) = self.ack.take() {
+            self.acks.push(ack);
+        }
+    }
+}
+
+impl<T: Chunk> AsyncRead for ChannelReader<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), IOError>> {
+        loop {
+            if self.pos < self.chunk.len() {
+                let n = buf.remaining().min(self.chunk.len() - self.pos);
+                let start = self.pos;
+                buf.put_slice(&self.chunk[start..start + n]);
+                self.pos += n;
+                if self.pos == self.chunk.len() {
+                    self.handed_out();
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            self.handed_out();
+
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    let (data, ack) = chunk.into_parts();
+                    self.chunk = data;
+                    self.pos = 0;
+                    self.ack = ack;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Accumulates bytes from a single writer and hands back only complete lines. This keeps records from multiple
+/// concurrent writers from interleaving mid-line when they're merged into the same batch.
+#[derive(Default)]
+pub(crate) struct LineBuffer {
+    partial: Vec<u8>,
+}
+
+impl LineBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `data` to the buffer, returning every complete line accumulated so far (if any).
+    pub fn push(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        self.partial.extend_from_slice(data);
+
+        let end = match self.partial.iter().rposition(|&b| b == b'\n') {
+            Some(pos) => pos + 1,
+            None if self.partial.len() >= MAX_PARTIAL_LINE => self.partial.len(),
+            None => return None,
+        };
+
+        let remainder = self.partial.split_off(end);
+        Some(std::mem::replace(&mut self.partial, remainder))
+    }
+
+    /// Return whatever is left over when the writer goes away, terminated with a newline.
+    pub fn finish(mut self) -> Option<Vec<u8>> {
+        if self.partial.is_empty() {
+            None
+        } else {
+            self.partial.push(b'\n');
+            Some(self.partial)
+        }
+    }
+}
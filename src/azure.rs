@@ -0,0 +1,533 @@
+use {
+    crate::{MAX_KEY_RENAMES, MAX_PART_SIZE, disambiguate_key, error::StorageRequestError, error::VerificationFailed},
+    anyhow::{Result as AnyResult, anyhow},
+    aws_smithy_types::base64,
+    bytes::{Bytes, BytesMut},
+    futures::stream::{self, StreamExt, TryStreamExt},
+    hmac::{Hmac, Mac},
+    http_body_util::StreamBody,
+    hyper::body::Frame,
+    log::{debug, info, warn},
+    reqwest::{Client, Method, RequestBuilder, Response, Url, header::HeaderMap},
+    serde::Deserialize,
+    sha2::Sha256,
+    std::{
+        fmt::{Debug, Formatter, Result as FmtResult},
+        io::{Error as IOError, SeekFrom},
+        path::Path,
+        time::Duration,
+    },
+    time::OffsetDateTime,
+    tokio::{
+        fs::File,
+        io::{AsyncReadExt, AsyncSeekExt},
+        time::sleep,
+    },
+};
+
+/// The prefix for Azure Blob Storage destinations: `azblob://account/container/path-template`.
+pub(crate) const AZURE_PROTO_PREFIX: &str = "azblob://";
+
+/// The Blob service REST API version we speak.
+const API_VERSION: &str = "2021-08-06";
+
+/// The endpoint for an account's blobs if none is configured. `{account}` is replaced with the account name.
+const DEFAULT_ENDPOINT: &str = "https://{account}.blob.core.windows.net";
+
+/// The number of times a request is sent before giving up, if Azure is busy or can't be reached.
+const MAX_REQUEST_ATTEMPTS: u32 = 3;
+
+/// The most blocks a blob can be committed from, which limits a blob staged in [`MAX_PART_SIZE`] blocks to
+/// [`MAX_BLOB_SIZE`] bytes.
+const MAX_BLOCKS: u64 = 50_000;
+
+/// The largest batch that can be written to Azure.
+pub(crate) const MAX_BLOB_SIZE: u64 = MAX_BLOCKS * MAX_PART_SIZE;
+
+/// The number of blocks of a blob staged at once.
+const MAX_CONCURRENT_BLOCKS: usize = 4;
+
+/// The size of the chunks a file is read in as it's sent.
+const READ_CHUNK_SIZE: usize = 64 << 10;
+
+/// How to reach and authenticate to Azure Blob Storage. Every Azure destination uses the same settings.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AzureOptions {
+    /// The blob service endpoint, with `{account}` standing for the account name, e.g.
+    /// `http://127.0.0.1:10000/{account}` for Azurite. Defaults to `https://{account}.blob.core.windows.net`.
+    pub endpoint: Option<String>,
+
+    /// The storage account's shared key, in base64.
+    pub account_key: Option<String>,
+
+    /// A shared access signature, without the leading `?`.
+    pub sas_token: Option<String>,
+}
+
+impl AzureOptions {
+    /// Check that there are credentials to use, and that the account key is base64.
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.account_key, &self.sas_token) {
+            (None, None) => Err("Azure destinations need an account key or a SAS token".to_string()),
+            (Some(key), _) if base64::decode(key).is_err() => Err("The Azure account key must be base64".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How requests are authorized.
+#[derive(Clone)]
+enum Credential {
+    /// Signed with the account's shared key.
+    SharedKey(Vec<u8>),
+
+    /// A shared access signature added to every URL.
+    Sas(String),
+}
+
+impl Debug for Credential {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        // Keep secrets out of logs.
+        match self {
+            Self::SharedKey(_) => f.write_str("SharedKey"),
+            Self::Sas(_) => f.write_str("Sas"),
+        }
+    }
+}
+
+/// An Azure Blob Storage container to write to.
+#[derive(Clone, Debug)]
+pub(crate) struct AzureTarget {
+    pub account: String,
+    pub container: String,
+
+    /// The URL of the container.
+    url: Url,
+    credential: Credential,
+    client: Client,
+}
+
+/// The result of a successful request: the response headers we use.
+struct Sent {
+    headers: HeaderMap,
+}
+
+/// The body of a request: bytes in memory, or a range of a file, which is read as it's sent.
+enum Body<'a> {
+    Bytes(Bytes),
+    File {
+        path: &'a Path,
+        start: u64,
+        len: u64,
+    },
+}
+
+impl Body<'_> {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(bytes) => bytes.len() as u64,
+            Self::File {
+                len,
+                ..
+            } => *len,
+        }
+    }
+
+    /// Start sending the body; a file is opened afresh each time, so a retried request sends it from the start.
+    async fn open(&self) -> Result<reqwest::Body, IOError> {
+        let (path, start, len) = match self {
+            Self::Bytes(bytes) => return Ok(bytes.clone().into()),
+            Self::File {
+                path,
+                start,
+                len,
+            } => (path, *start, *len),
+        };
+
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let chunks = stream::try_unfold(file.take(len), |mut reader| async move {
+            let mut buf = BytesMut::with_capacity(READ_CHUNK_SIZE);
+            let n_read = reader.read_buf(&mut buf).await?;
+            Ok::<_, IOError>((n_read > 0).then(|| (Frame::data(buf.freeze()), reader)))
+        });
+        Ok(reqwest::Body::wrap(StreamBody::new(chunks)))
+    }
+}
+
+impl AzureTarget {
+    /// Set up a target for `container` in `account`. The options have been validated.
+    pub fn new(account: &str, container: &str, options: &AzureOptions, client: Client) -> AnyResult<Self> {
+        let endpoint = options.endpoint.as_deref().unwrap_or(DEFAULT_ENDPOINT).replace("{account}", account);
+        let mut url = Url::parse(&endpoint)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid Azure endpoint {endpoint:?}"))?
+            .pop_if_empty()
+            .push(container);
+
+        let credential = match (&options.account_key, &options.sas_token) {
+            (Some(key), _) => Credential::SharedKey(base64::decode(key)?),
+            (None, Some(sas)) => Credential::Sas(sas.trim_start_matches('?').to_string()),
+            (None, None) => return Err(anyhow!("No credentials for Azure account {account}")),
+        };
+
+        Ok(Self {
+            account: account.to_string(),
+            container: container.to_string(),
+            url,
+            credential,
+            client,
+        })
+    }
+
+    /// The URL of a blob, with `query` added.
+    fn blob_url(&self, blob: &str, query: &[(&str, &str)]) -> Url {
+        let mut url = self.url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.extend(blob.split('/'));
+        }
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        url
+    }
+
+    /// Send a request, signing it or adding the SAS token. Requests that fail because Azure is busy or can't be
+    /// reached are retried. `headers` are the `x-ms-*` headers and `If-None-Match`; `body` is sent as is.
+    async fn send(
+        &self,
+        method: Method,
+        mut url: Url,
+        headers: &[(&str, String)],
+        content_type: Option<&str>,
+        body: Body<'_>,
+    ) -> Result<Sent, StorageRequestError> {
+        if let Credential::Sas(sas) = &self.credential {
+            let query = match url.query() {
+                Some(query) => format!("{query}&{sas}"),
+                None => sas.clone(),
+            };
+            url.set_query(Some(&query));
+        }
+
+        let mut attempt = 1;
+        loop {
+            let date = http_date(OffsetDateTime::now_utc());
+            let mut request = self
+                .client
+                .request(method.clone(), url.clone())
+                .header("x-ms-date", &date)
+                .header("x-ms-version", API_VERSION)
+                .header("Content-Length", body.len());
+            let mut signed_headers = vec![("x-ms-date", date.clone()), ("x-ms-version", API_VERSION.to_string())];
+            for (name, value) in headers {
+                request = request.header(*name, value);
+                signed_headers.push((name, value.clone()));
+            }
+            if let Some(content_type) = content_type {
+                request = request.header("Content-Type", content_type);
+            }
+            if let Credential::SharedKey(key) = &self.credential {
+                let to_sign = string_to_sign(&method, &url, &self.account, body.len(), content_type, &signed_headers);
+                request =
+                    request.header("Authorization", format!("SharedKey {}:{}", self.account, sign(key, &to_sign)));
+            }
+
+            match check(request, &body).await {
+                Err(e) if attempt < MAX_REQUEST_ATTEMPTS && e.is_retryable() => {
+                    debug!("Retrying Azure request to {} after {e}", url.path());
+                    sleep(Duration::from_millis(200 << attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Upload a file as a block blob. A file no bigger than a part goes up in one request; a larger one is staged
+    /// in blocks of [`MAX_PART_SIZE`] bytes, [`MAX_CONCURRENT_BLOCKS`] at a time, and committed with a block list, as
+    /// for an S3 multipart upload. The file is streamed rather than read into memory. With `no_overwrite`, the blob
+    /// isn't written if it exists. Returns the ETag and version id.
+    async fn upload(
+        &self,
+        path: &Path,
+        blob: &str,
+        size: u64,
+        host_id: &str,
+        no_overwrite: bool,
+    ) -> AnyResult<(Option<String>, Option<String>)> {
+        let mut headers = vec![("x-ms-meta-hostid", host_id.to_string())];
+        if no_overwrite {
+            headers.push(("If-None-Match", "*".to_string()));
+        }
+
+        let sent = if size <= MAX_PART_SIZE {
+            info!("Performing single upload for {path:?} of size {size:?}");
+            let body = Body::File {
+                path,
+                start: 0,
+                len: size,
+            };
+            headers.push(("x-ms-blob-type", "BlockBlob".to_string()));
+            self.send(Method::PUT, self.blob_url(blob, &[]), &headers, Some("application/octet-stream"), body).await?
+        } else {
+            info!("Performing block upload for {path:?} of size {size}");
+            let block_ids: Vec<_> = (0..size.div_ceil(MAX_PART_SIZE)).map(block_id).collect();
+            let stages = block_ids.iter().enumerate().map(|(index, id)| async move {
+                let start = index as u64 * MAX_PART_SIZE;
+                let body = Body::File {
+                    path,
+                    start,
+                    len: MAX_PART_SIZE.min(size - start),
+                };
+                let url = self.blob_url(blob, &[("comp", "block"), ("blockid", id)]);
+                self.send(Method::PUT, url, &[], None, body).await?;
+                AnyResult::<()>::Ok(())
+            });
+            // Collected first so the stream's type doesn't depend on the closure's borrows.
+            let stages: Vec<_> = stages.collect();
+            stream::iter(stages).buffer_unordered(MAX_CONCURRENT_BLOCKS).try_collect::<()>().await?;
+
+            // Uncommitted blocks are discarded by Azure after a week, so a failed upload needs no cleanup.
+            debug!("Committing {} blocks of {blob}", block_ids.len());
+            let url = self.blob_url(blob, &[("comp", "blocklist")]);
+            let body = Body::Bytes(Bytes::from(block_list(&block_ids)));
+            self.send(Method::PUT, url, &headers, Some("application/xml"), body).await?
+        };
+
+        let header = |name| sent.headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        Ok((header("etag"), header("x-ms-version-id")))
+    }
+}
+
+/// Write a finished batch to `blob` in an Azure container. With `no_overwrite`, an existing blob is never replaced:
+/// `-1`, `-2`, ... is added to the blob name as for S3. Returns the blob name used, the ETag and the version id.
+pub(crate) async fn write_batch(
+    target: &AzureTarget,
+    path: &Path,
+    blob: &str,
+    size: u64,
+    host_id: &str,
+    no_overwrite: bool,
+) -> AnyResult<(String, Option<String>, Option<String>)> {
+    let mut name = blob.to_string();
+    let mut renames = 0;
+    loop {
+        match target.upload(path, &name, size, host_id, no_overwrite).await {
+            Ok((e_tag, version_id)) => return Ok((name, e_tag, version_id)),
+            Err(e) if no_overwrite && renames < MAX_KEY_RENAMES && is_exists(&e) => {
+                renames += 1;
+                let new_name = disambiguate_key(blob, renames);
+                let container = target.container.as_str();
+                warn!(
+                    event = "object_exists", bucket = container, key = name.as_str(), new_key = new_name.as_str();
+                    "{name} already exists in Azure container {container}; writing to {new_name} instead"
+                );
+                name = new_name;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Check that the blob written is `size` bytes.
+pub(crate) async fn verify_batch(target: &AzureTarget, blob: &str, size: u64) -> AnyResult<()> {
+    let sent = target.send(Method::HEAD, target.blob_url(blob, &[]), &[], None, Body::Bytes(Bytes::new())).await?;
+    let actual = sent.headers.get("content-length").and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if actual != size.to_string() {
+        return Err(VerificationFailed {
+            what: "size",
+            expected: size.to_string(),
+            actual: actual.to_string(),
+        }
+        .into());
+    }
+
+    debug!("Verified {blob} in Azure container {}: {size} bytes", target.container);
+    Ok(())
+}
+
+/// Send a request, turning an error status into a [`StorageRequestError`] classed by Azure's error code.
+async fn check(request: RequestBuilder, body: &Body<'_>) -> Result<Sent, StorageRequestError> {
+    let body = body.open().await.map_err(|e| StorageRequestError {
+        service: "Azure",
+        class: "io".to_string(),
+        status: None,
+        message: e.to_string(),
+    })?;
+    let response: Response = request.body(body).send().await.map_err(|e| StorageRequestError {
+        service: "Azure",
+        class: if e.is_timeout() {
+            "timeout"
+        } else {
+            "dispatch"
+        }
+        .to_string(),
+        status: None,
+        message: e.to_string(),
+    })?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(Sent {
+            headers: response.headers().clone(),
+        });
+    }
+
+    let code = response.headers().get("x-ms-error-code").and_then(|v| v.to_str().ok()).map(str::to_string);
+    let message = response.text().await.unwrap_or_default();
+    Err(StorageRequestError {
+        service: "Azure",
+        class: code.unwrap_or_else(|| status.as_u16().to_string()),
+        status: Some(status.as_u16()),
+        message,
+    })
+}
+
+/// Whether an upload failed because the blob exists and we asked not to overwrite it.
+fn is_exists(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<StorageRequestError>()
+        .is_some_and(|e| matches!(e.class.as_str(), "BlobAlreadyExists" | "ConditionNotMet") || e.status == Some(412))
+}
+
+/// Format a time as an HTTP date for `x-ms-date`, e.g. `Sun, 18 Oct 2026 12:00:00 GMT`.
+fn http_date(at: OffsetDateTime) -> String {
+    format!(
+        "{:.3}, {:02} {:.3} {} {:02}:{:02}:{:02} GMT",
+        at.weekday().to_string(),
+        at.day(),
+        at.month().to_string(),
+        at.year(),
+        at.hour(),
+        at.minute(),
+        at.second()
+    )
+}
+
+/// The id of a block. Every block id in a blob must be the same length.
+fn block_id(index: u64) -> String {
+    base64::encode(format!("{index:08}"))
+}
+
+/// The body of a Put Block List request committing `block_ids` in order.
+fn block_list(block_ids: &[String]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>");
+    for id in block_ids {
+        xml.push_str(&format!("<Latest>{id}</Latest>"));
+    }
+    xml.push_str("</BlockList>");
+    xml
+}
+
+/// The string a Shared Key request signature covers: the method, the standard headers (only the ones we send are
+/// filled in), the `x-ms-*` headers, and the account and path with the query parameters.
+fn string_to_sign(
+    method: &Method,
+    url: &Url,
+    account: &str,
+    content_length: u64,
+    content_type: Option<&str>,
+    headers: &[(&str, String)],
+) -> String {
+    // An empty body is signed with an empty Content-Length.
+    let content_length = if content_length == 0 {
+        String::new()
+    } else {
+        content_length.to_string()
+    };
+    let if_none_match = headers.iter().find(|(name, _)| *name == "If-None-Match").map_or("", |(_, v)| v.as_str());
+    let mut to_sign =
+        format!("{method}\n\n\n{content_length}\n\n{}\n\n\n\n{if_none_match}\n\n\n", content_type.unwrap_or_default());
+
+    let mut ms_headers: Vec<_> = headers.iter().filter(|(name, _)| name.starts_with("x-ms-")).collect();
+    ms_headers.sort();
+    for (name, value) in ms_headers {
+        to_sign.push_str(&format!("{name}:{value}\n"));
+    }
+
+    to_sign.push_str(&format!("/{account}{}", url.path()));
+    let mut query: Vec<_> = url.query_pairs().map(|(name, value)| (name.to_lowercase(), value.into_owned())).collect();
+    query.sort();
+    for (name, value) in query {
+        to_sign.push_str(&format!("\n{name}:{value}"));
+    }
+    to_sign
+}
+
+/// Sign a string with the account key for a Shared Key `Authorization` header.
+fn sign(key: &[u8], to_sign: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(to_sign.as_bytes());
+    base64::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{AzureOptions, AzureTarget, Body, block_id, block_list, http_date, string_to_sign},
+        http_body_util::BodyExt,
+        reqwest::{Client, Method},
+        std::fs::write,
+        tempfile::tempdir,
+        time::macros::datetime,
+    };
+
+    #[tokio::test]
+    async fn test_file_body() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("batch");
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        write(&path, &data).unwrap();
+
+        // A block is read from its range of the file, and read again from the start if it's sent again.
+        let body = Body::File {
+            path: &path,
+            start: 70_000,
+            len: 100_000,
+        };
+        assert_eq!(body.len(), 100_000);
+        for _ in 0..2 {
+            let sent = body.open().await.unwrap().collect().await.unwrap().to_bytes();
+            assert_eq!(sent, data[70_000..170_000]);
+        }
+    }
+
+    #[test]
+    fn test_string_to_sign() {
+        let options = AzureOptions {
+            endpoint: Some("http://127.0.0.1:10000/{account}".to_string()),
+            account_key: Some("a2V5".to_string()),
+            sas_token: None,
+        };
+        options.validate().unwrap();
+        assert!(AzureOptions::default().validate().is_err());
+
+        // Azurite puts the account in the path, which is then signed after the account.
+        let target = AzureTarget::new("devstoreaccount1", "logs", &options, Client::new()).unwrap();
+        let url = target.blob_url("web/2026/web1 #1.log", &[("comp", "block"), ("blockid", &block_id(0))]);
+        assert_eq!(
+            url.as_str(),
+            "http://127.0.0.1:10000/devstoreaccount1/logs/web/2026/web1%20%231.log?comp=block&blockid=MDAwMDAwMDA%3D"
+        );
+
+        let date = http_date(datetime!(2026-10-18 12:00:00 UTC));
+        assert_eq!(date, "Sun, 18 Oct 2026 12:00:00 GMT");
+        let headers =
+            [("x-ms-version", "2021-08-06".to_string()), ("x-ms-date", date), ("If-None-Match", "*".to_string())];
+        assert_eq!(
+            string_to_sign(&Method::PUT, &url, "devstoreaccount1", 3, None, &headers),
+            "PUT\n\n\n3\n\n\n\n\n\n*\n\n\n\
+             x-ms-date:Sun, 18 Oct 2026 12:00:00 GMT\nx-ms-version:2021-08-06\n\
+             /devstoreaccount1/devstoreaccount1/logs/web/2026/web1%20%231.log\nblockid:MDAwMDAwMDA=\ncomp:block"
+        );
+
+        // Block ids are all the same length, and committed in order.
+        assert_eq!(block_id(12).len(), block_id(0).len());
+        assert_eq!(
+            block_list(&[block_id(0), block_id(1)]),
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList><Latest>MDAwMDAwMDA=</Latest>\
+             <Latest>MDAwMDAwMDE=</Latest></BlockList>"
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use {
+    crate::error::ChecksumMismatch,
+    aws_sdk_s3::types::ChecksumAlgorithm as S3ChecksumAlgorithm,
+    aws_smithy_checksums::{ChecksumAlgorithm as SmithyChecksumAlgorithm, http::HttpChecksum},
+    aws_smithy_types::base64,
+    bytes::Bytes,
+    clap::ValueEnum,
+    serde::{Deserialize, Serialize},
+    std::{cmp::min, mem::replace},
+};
+
+/// An algorithm for end-to-end checksums. Each part's checksum is sent with it and checked by S3 as it arrives; the
+/// checksum S3 reports for the whole object is checked by us once the upload is complete.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ChecksumAlgorithm {
+    Crc32c,
+    Crc32,
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn as_s3(self) -> S3ChecksumAlgorithm {
+        match self {
+            Self::Crc32c => S3ChecksumAlgorithm::Crc32C,
+            Self::Crc32 => S3ChecksumAlgorithm::Crc32,
+            Self::Sha1 => S3ChecksumAlgorithm::Sha1,
+            Self::Sha256 => S3ChecksumAlgorithm::Sha256,
+        }
+    }
+
+    fn hasher(self) -> Box<dyn HttpChecksum> {
+        match self {
+            Self::Crc32c => SmithyChecksumAlgorithm::Crc32c,
+            Self::Crc32 => SmithyChecksumAlgorithm::Crc32,
+            Self::Sha1 => SmithyChecksumAlgorithm::Sha1,
+            Self::Sha256 => SmithyChecksumAlgorithm::Sha256,
+        }
+        .into_impl()
+    }
+}
+
+/// Set the `checksum_*` field matching a [`ChecksumAlgorithm`] on a request or part builder.
+macro_rules! with_checksum {
+    ($builder:expr, $algorithm:expr, $value:expr) => {
+        match $algorithm {
+            $crate::checksum::ChecksumAlgorithm::Crc32c => $builder.checksum_crc32_c($value),
+            $crate::checksum::ChecksumAlgorithm::Crc32 => $builder.checksum_crc32($value),
+            $crate::checksum::ChecksumAlgorithm::Sha1 => $builder.checksum_sha1($value),
+            $crate::checksum::ChecksumAlgorithm::Sha256 => $builder.checksum_sha256($value),
+        }
+    };
+}
+
+/// Get the `checksum_*` field matching a [`ChecksumAlgorithm`] from an S3 response.
+macro_rules! returned_checksum {
+    ($output:expr, $algorithm:expr) => {
+        match $algorithm {
+            $crate::checksum::ChecksumAlgorithm::Crc32c => $output.checksum_crc32_c(),
+            $crate::checksum::ChecksumAlgorithm::Crc32 => $output.checksum_crc32(),
+            $crate::checksum::ChecksumAlgorithm::Sha1 => $output.checksum_sha1(),
+            $crate::checksum::ChecksumAlgorithm::Sha256 => $output.checksum_sha256(),
+        }
+    };
+}
+
+pub(crate) use {returned_checksum, with_checksum};
+
+/// Checksums of each part of a file, computed as the file is written. Parts are `part_size` bytes, matching the way
+/// the file will be split for a multipart upload.
+pub(crate) struct PartChecksums {
+    algorithm: ChecksumAlgorithm,
+    part_size: u64,
+    current: Box<dyn HttpChecksum>,
+    current_len: u64,
+    parts: Vec<Bytes>,
+}
+
+impl PartChecksums {
+    pub fn new(algorithm: ChecksumAlgorithm, part_size: u64) -> Self {
+        Self {
+            algorithm,
+            part_size,
+            current: algorithm.hasher(),
+            current_len: 0,
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = min(self.part_size - self.current_len, data.len() as u64) as usize;
+            self.current.update(&data[..n]);
+            self.current_len += n as u64;
+            data = &data[n..];
+
+            if self.current_len == self.part_size {
+                let full = replace(&mut self.current, self.algorithm.hasher());
+                self.parts.push(full.finalize());
+                self.current_len = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> ObjectChecksums {
+        if self.current_len > 0 || self.parts.is_empty() {
+            self.parts.push(self.current.finalize());
+        }
+
+        ObjectChecksums {
+            algorithm: self.algorithm,
+            parts: self.parts,
+        }
+    }
+}
+
+/// The checksums of a file's parts, as computed while it was written.
+#[derive(Clone, Debug)]
+pub(crate) struct ObjectChecksums {
+    pub algorithm: ChecksumAlgorithm,
+    parts: Vec<Bytes>,
+}
+
+impl ObjectChecksums {
+    /// The base64 checksum of a part, numbered from 1 as S3 does.
+    pub fn part(&self, part_number: i32) -> Option<String> {
+        let index = usize::try_from(part_number).ok()?.checked_sub(1)?;
+        self.parts.get(index).map(base64::encode)
+    }
+
+    /// The checksum S3 should report for the object: the checksum of the data for a single upload, or for a
+    /// multipart upload, the checksum of the parts' checksums followed by `-` and the number of parts.
+    pub fn expected(&self, multipart: bool) -> String {
+        if multipart {
+            let mut hasher = self.algorithm.hasher();
+            for part in &self.parts {
+                hasher.update(part);
+            }
+            format!("{}-{}", base64::encode(hasher.finalize()), self.parts.len())
+        } else {
+            self.part(1).unwrap_or_default()
+        }
+    }
+
+    /// Check the checksum S3 reported for the object. S3 doesn't always report one (e.g. through some proxies), so a
+    /// missing checksum is accepted.
+    pub fn verify(&self, multipart: bool, returned: Option<&str>) -> Result<(), ChecksumMismatch> {
+        let expected = self.expected(multipart);
+        match returned {
+            Some(actual) if actual != expected => Err(ChecksumMismatch {
+                expected,
+                actual: actual.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChecksumAlgorithm, PartChecksums};
+
+    #[test]
+    fn test_part_checksums() {
+        // A single part matches the checksum of the data.
+        let mut checksums = PartChecksums::new(ChecksumAlgorithm::Sha256, 4);
+        checksums.update(b"abc");
+        let single = checksums.finish();
+        assert_eq!(single.expected(false), "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=");
+        single.verify(false, Some("ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=")).unwrap();
+        single.verify(false, None).unwrap();
+        assert!(single.verify(false, Some("AAAA")).is_err());
+
+        // Writes are split at part boundaries regardless of how they arrive.
+        let mut split = PartChecksums::new(ChecksumAlgorithm::Crc32c, 4);
+        split.update(b"abcdef");
+        split.update(b"ghij");
+        let split = split.finish();
+
+        let mut whole = PartChecksums::new(ChecksumAlgorithm::Crc32c, 4);
+        whole.update(b"abcd");
+        whole.update(b"efgh");
+        whole.update(b"ij");
+        let whole = whole.finish();
+
+        assert_eq!(split.parts.len(), 3);
+        assert_eq!(split.part(3), whole.part(3));
+        assert_eq!(split.expected(true), whole.expected(true));
+        assert!(split.expected(true).ends_with("-3"));
+        assert_eq!(split.part(4), None);
+    }
+}
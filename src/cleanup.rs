@@ -0,0 +1,223 @@
+use {
+    crate::{
+        BucketKind, Location, S3Clients,
+        config::Config,
+        error::{InvalidS3URL, S3RequestError},
+        expand_template,
+        fallback::FALLBACK_KEY_MARKER,
+        get_host_id, load_sdk_config, mark_key, parse_destination, spool,
+    },
+    anyhow::Result as AnyResult,
+    aws_sdk_s3::Client,
+    log::{debug, error, info, warn},
+    regex::Regex,
+    std::{
+        collections::HashSet,
+        time::{Duration, SystemTime},
+    },
+};
+
+/// How old an incomplete upload must be before `--cleanup-uploads` aborts it, if no threshold is configured.
+pub(crate) const DEFAULT_ABORT_AFTER: Duration = Duration::from_secs(86400);
+
+/// The keys a destination template produces on this host.
+#[derive(Debug)]
+struct HostKeys {
+    /// The part of the template before its first variable; every key starts with it.
+    prefix: String,
+    keys: Regex,
+}
+
+impl HostKeys {
+    /// Build a matcher for the keys `pattern` produces with this `host_id`. S3 doesn't report the tags or metadata of
+    /// an incomplete upload, so the key is all we have to go on; a template without {host_id} could match another
+    /// host's uploads, so `None` is returned for it.
+    fn new(pattern: &str, host_id: &str) -> Result<Option<Self>, InvalidS3URL> {
+        // Mark each variable with NULs (which can't appear in a template) so the literal parts can be escaped.
+        let marked = expand_template(pattern, |name| Some(format!("\0{name}\0")))?;
+        let mut segments = marked.split('\0');
+        let prefix = segments.next().unwrap_or_default().to_string();
+        let mut keys = format!("^{}", regex::escape(&prefix));
+        let mut has_host_id = false;
+
+        while let Some(name) = segments.next() {
+            if name == "host_id" {
+                keys.push_str(&regex::escape(host_id));
+                has_host_id = true;
+            } else {
+                keys.push_str("[^/]*");
+            }
+            keys.push_str(&regex::escape(segments.next().unwrap_or_default()));
+        }
+        keys.push('$');
+
+        if !has_host_id {
+            return Ok(None);
+        }
+
+        let keys = Regex::new(&keys).map_err(|e| InvalidS3URL::InvalidTemplateSyntax(e.to_string()))?;
+        Ok(Some(Self {
+            prefix,
+            keys,
+        }))
+    }
+}
+
+/// Abort the incomplete multipart uploads for `pattern` in `bucket` that this host started more than `older_than`
+/// ago, except those in `resuming`. These are left behind if we die partway through an upload; unless their spool
+/// file survived to be resumed, we can't finish them, and S3 charges for their parts until they are aborted. Returns
+/// the number of uploads aborted.
+pub(crate) async fn abort_stale_uploads(
+    s3: &Client,
+    bucket: &str,
+    expected_owner: Option<&str>,
+    pattern: &str,
+    host_id: &str,
+    older_than: Duration,
+    resuming: &HashSet<String>,
+) -> AnyResult<usize> {
+    let expected_owner = expected_owner.map(str::to_string);
+    let Some(host_keys) = HostKeys::new(pattern, host_id)? else {
+        warn!("Not cleaning up incomplete uploads in s3://{bucket}/{pattern}: the template doesn't use {{host_id}}");
+        return Ok(0);
+    };
+
+    // Directory buckets only list by prefixes ending in a slash; the keys are matched in full anyway.
+    let mut prefix = host_keys.prefix.as_str();
+    if BucketKind::of(bucket) == BucketKind::Directory {
+        prefix = prefix.rfind('/').map_or("", |end| &prefix[..=end]);
+    }
+
+    let cutoff = SystemTime::now() - older_than;
+    let mut aborted = 0;
+    let mut key_marker = None;
+    let mut upload_id_marker = None;
+
+    debug!("Looking for incomplete uploads under s3://{bucket}/{prefix}");
+    loop {
+        let output = s3
+            .list_multipart_uploads()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_expected_bucket_owner(expected_owner.clone())
+            .set_key_marker(key_marker)
+            .set_upload_id_marker(upload_id_marker)
+            .send()
+            .await
+            .map_err(S3RequestError::new)?;
+
+        for upload in output.uploads() {
+            let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) else {
+                continue;
+            };
+            let initiated = upload.initiated().and_then(|at| SystemTime::try_from(*at).ok());
+            if !host_keys.keys.is_match(key) || initiated.is_none_or(|at| at > cutoff) || resuming.contains(upload_id) {
+                continue;
+            }
+
+            let request = s3.abort_multipart_upload().bucket(bucket).key(key).upload_id(upload_id);
+            match request.set_expected_bucket_owner(expected_owner.clone()).send().await {
+                Ok(_) => {
+                    info!(
+                        event = "multipart_aborted", bucket, key, upload_id, reason = "stale";
+                        "Aborted incomplete multipart upload of s3://{bucket}/{key} started at {:?}",
+                        upload.initiated()
+                    );
+                    aborted += 1;
+                }
+                Err(e) => {
+                    error!("Unable to abort multipart upload of s3://{bucket}/{key}: {}", S3RequestError::new(e));
+                }
+            }
+        }
+
+        if !output.is_truncated().unwrap_or_default() {
+            break;
+        }
+        key_marker = output.next_key_marker;
+        upload_id_marker = output.next_upload_id_marker;
+    }
+
+    Ok(aborted)
+}
+
+/// Clean up incomplete uploads for each pipeline's destinations and fallbacks and exit, for `--cleanup-uploads`.
+/// Returns the process exit code.
+pub(crate) async fn run(config: Config) -> i32 {
+    let host_id = get_host_id().await;
+    let mut clients = S3Clients::new(load_sdk_config(config.assume_role.as_ref()).await);
+    let older_than = config.abort_uploads_older_than.unwrap_or(DEFAULT_ABORT_AFTER);
+    let mut seen = HashSet::new();
+    let mut failed = false;
+
+    // Leave uploads that the next run will resume, or that another process is still making, alone.
+    let mut resuming = HashSet::new();
+    let mut temp_dirs: Vec<_> = config.pipelines.iter().filter_map(|pipeline| pipeline.temp_dir.clone()).collect();
+    temp_dirs.sort();
+    temp_dirs.dedup();
+    for dir in temp_dirs {
+        resuming.extend(spool::saved_upload_ids(&dir).await);
+    }
+
+    for pipeline in &config.pipelines {
+        let destinations = pipeline.destinations().into_iter().map(|destination| (destination, false));
+        let fallbacks = pipeline.fallback_destinations.iter().map(|destination| (destination.clone(), true));
+        for (destination, fallback) in destinations.chain(fallbacks) {
+            // Validation has already checked the URL. Only S3 destinations have multipart uploads to clean up.
+            let Ok((Location::S3(bucket), mut pattern)) = parse_destination(&destination.destination) else {
+                continue;
+            };
+            if fallback {
+                pattern = mark_key(&pattern, FALLBACK_KEY_MARKER);
+            }
+            if !seen.insert((bucket.clone(), pattern.clone())) {
+                continue;
+            }
+
+            let owner = pipeline.expected_bucket_owner.as_deref();
+            let result = match clients.for_bucket(&bucket, owner, destination.bucket_region.as_deref()).await {
+                Ok(s3) => abort_stale_uploads(&s3, &bucket, owner, &pattern, &host_id, older_than, &resuming).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(aborted) => info!("Aborted {aborted} incomplete uploads in s3://{bucket}/{pattern}"),
+                Err(e) => {
+                    error!("Unable to clean up incomplete uploads in s3://{bucket}/{pattern}: {e}");
+                    failed = true;
+                }
+            }
+        }
+    }
+
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HostKeys;
+
+    #[test]
+    fn test_host_keys() {
+        let host_keys = HostKeys::new("logs/{year}/{month}/{host_id}.{unique}.log", "web1").unwrap().unwrap();
+        assert_eq!(host_keys.prefix, "logs/");
+        assert!(host_keys.keys.is_match("logs/2026/10/web1.ABCDEF.log"));
+
+        // Other hosts' keys, and keys from other templates, don't match.
+        assert!(!host_keys.keys.is_match("logs/2026/10/web10.ABCDEF.log"));
+        assert!(!host_keys.keys.is_match("logs/2026/10/x/web1.ABCDEF.log"));
+        assert!(!host_keys.keys.is_match("logs/2026/10/web1.ABCDEF.log.gz"));
+
+        // Regex characters in the template are literal.
+        let dotted = HostKeys::new("a.b/{{x}}/{host_id}", "h").unwrap().unwrap();
+        assert_eq!(dotted.prefix, "a.b/{x}/");
+        assert!(dotted.keys.is_match("a.b/{x}/h"));
+        assert!(!dotted.keys.is_match("aXb/{x}/h"));
+
+        // Without {host_id}, we can't tell whose uploads are whose.
+        assert!(HostKeys::new("logs/{unique}.log", "web1").unwrap().is_none());
+    }
+}
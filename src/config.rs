@@ -0,0 +1,968 @@
+use {
+    crate::{
+        BucketKind, Location, S3_MAXIMUM_SIZE, TEMPLATE_VARIABLES,
+        azure::{AZURE_PROTO_PREFIX, AzureOptions, MAX_BLOB_SIZE},
+        checksum::ChecksumAlgorithm,
+        error::ConfigError,
+        gcs::GcsOptions,
+        http_ingest::HttpIngestOptions,
+        ledger::LedgerOptions,
+        manifest::MANIFEST_VARIABLES,
+        parse_destination, parse_s3_url,
+        statsd::StatsdOptions,
+        syslog::{SYSLOG_VARIABLES, SyslogOptions},
+        template_variable_names,
+    },
+    aws_sdk_s3::types::{ObjectCannedAcl, ServerSideEncryption, StorageClass},
+    byte_unit::Byte,
+    humantime::parse_duration,
+    serde::{Deserialize, Deserializer, de::Error as DeError},
+    std::{
+        collections::HashSet,
+        ffi::OsString,
+        fs::read_to_string,
+        net::SocketAddr,
+        path::{Path, PathBuf},
+        str::FromStr,
+        time::Duration,
+    },
+};
+
+#[cfg(unix)]
+use crate::unix_socket::UnixSocketOptions;
+
+/// The default maximum size to buffer before flushing to S3.
+pub(crate) const DEFAULT_MAX_SIZE: &str = "1MiB";
+
+/// The default maximum duration to buffer before flushing to S3.
+pub(crate) const DEFAULT_MAX_DURATION: &str = "1h";
+
+/// The default maximum size of an HTTP ingest request body.
+pub(crate) const DEFAULT_HTTP_MAX_BODY: &str = "10MiB";
+
+/// The complete configuration: one or more pipelines sharing a host id, S3 client and upload budget.
+#[derive(Debug)]
+pub(crate) struct Config {
+    /// The number of batches that may be uploading at once, across all pipelines.
+    pub max_concurrent_uploads: usize,
+    pub pipelines: Vec<PipelineConfig>,
+
+    /// Where to serve Prometheus metrics, if anywhere.
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Where to push StatsD metrics, if anywhere.
+    pub statsd: Option<StatsdOptions>,
+
+    /// Where to record uploaded batches, if anywhere.
+    pub ledger: Option<LedgerOptions>,
+
+    /// Abort this host's incomplete multipart uploads older than this at startup, if set.
+    pub abort_uploads_older_than: Option<Duration>,
+
+    /// A role to assume for every S3 request, if any.
+    pub assume_role: Option<AssumeRoleOptions>,
+
+    /// How to reach Azure Blob Storage, for `azblob://` destinations.
+    pub azure: Option<AzureOptions>,
+
+    /// How to reach Google Cloud Storage, for `gs://` destinations.
+    pub gcs: Option<GcsOptions>,
+
+    /// Where the configuration was read from, if it came from a file and can be reloaded.
+    pub source: Option<ConfigSource>,
+}
+
+/// Settings from the command line, used where a configuration file doesn't specify its own.
+#[derive(Clone, Debug)]
+pub(crate) struct ConfigDefaults {
+    pub temp_dir: PathBuf,
+    pub max_concurrent_uploads: usize,
+    pub metrics_listen: Option<SocketAddr>,
+    pub statsd: Option<StatsdOptions>,
+    pub ledger: Option<LedgerOptions>,
+    pub abort_uploads_older_than: Option<Duration>,
+    pub assume_role: Option<AssumeRoleOptions>,
+    pub azure: Option<AzureOptions>,
+    pub gcs: Option<GcsOptions>,
+}
+
+/// A role to assume, e.g. to deliver logs to a bucket in another account.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AssumeRoleOptions {
+    pub role_arn: String,
+    pub external_id: Option<String>,
+
+    /// The role session name. Defaults to `stream-logs-to-s3`.
+    pub session_name: Option<String>,
+}
+
+/// A configuration file along with the command line defaults it was read with.
+#[derive(Clone, Debug)]
+pub(crate) struct ConfigSource {
+    path: PathBuf,
+    defaults: ConfigDefaults,
+}
+
+/// A single input feeding batches to a single destination.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PipelineConfig {
+    pub name: String,
+
+    /// Where to write: `s3://bucket/path-template` (or an S3 access point ARN followed by `/path-template`),
+    /// `file:///path-template`, `azblob://account/container/path-template` or `gs://bucket/path-template`.
+    pub destination: String,
+
+    #[serde(default)]
+    pub input: InputConfig,
+
+    /// Maximum size to buffer before flushing, in bytes.
+    #[serde(rename = "size", default = "default_max_size", deserialize_with = "deserialize_size")]
+    pub max_size: u64,
+
+    /// Maximum duration to buffer before flushing.
+    #[serde(rename = "duration", default = "default_max_duration", deserialize_with = "deserialize_duration")]
+    pub max_duration: Duration,
+
+    /// Whether to compress output using gzip.
+    #[serde(rename = "gzip", default)]
+    pub compress: bool,
+
+    /// The algorithm for end-to-end upload checksums, if any.
+    #[serde(default)]
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+
+    /// Whether to check each uploaded object with HeadObject.
+    #[serde(default)]
+    pub verify: bool,
+
+    /// Whether to upload under another key rather than overwrite an existing object.
+    #[serde(default)]
+    pub no_overwrite: bool,
+
+    /// Temporary directory to use for buffering. Defaults to the top-level setting.
+    #[serde(rename = "tempdir", default)]
+    pub temp_dir: Option<PathBuf>,
+
+    /// The template for manifest objects in the destination bucket, if any.
+    #[serde(default)]
+    pub manifest: Option<String>,
+
+    /// The canned ACL for uploaded objects, e.g. `bucket-owner-full-control`.
+    #[serde(default)]
+    pub acl: Option<String>,
+
+    /// The account id that must own the destination bucket.
+    #[serde(default)]
+    pub expected_bucket_owner: Option<String>,
+
+    /// The region the destination bucket is in, if it shouldn't be discovered.
+    #[serde(default)]
+    pub bucket_region: Option<String>,
+
+    /// The server-side encryption for uploaded objects, e.g. `aws:kms`. Defaults to `AES256`.
+    #[serde(default)]
+    pub server_side_encryption: Option<String>,
+
+    /// The KMS key for `aws:kms` encryption. Defaults to the bucket's key.
+    #[serde(default)]
+    pub sse_kms_key_id: Option<String>,
+
+    /// The storage class for uploaded objects, e.g. `STANDARD_IA`.
+    #[serde(default)]
+    pub storage_class: Option<String>,
+
+    /// More destinations every batch is written to, e.g. a bucket in another region.
+    #[serde(rename = "extra_destination", default)]
+    pub extra_destinations: Vec<DestinationConfig>,
+
+    /// The number of destinations a batch must reach before its temp file is deleted. Defaults to all of them.
+    #[serde(default)]
+    pub min_destinations: Option<usize>,
+
+    /// Where to send batches, in order of preference, while the main destination is unavailable.
+    #[serde(rename = "fallback_destination", default)]
+    pub fallback_destinations: Vec<DestinationConfig>,
+
+    /// The number of consecutive uploads the main destination must refuse before batches go to a fallback.
+    #[serde(default)]
+    pub fallback_after: Option<u32>,
+
+    /// How long to keep using the fallbacks before trying the main destination again.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub fallback_retry: Option<Duration>,
+}
+
+/// A destination with the settings that can differ between a pipeline's destinations.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DestinationConfig {
+    pub destination: String,
+    pub bucket_region: Option<String>,
+    pub server_side_encryption: Option<String>,
+    pub sse_kms_key_id: Option<String>,
+    pub storage_class: Option<String>,
+}
+
+impl PipelineConfig {
+    /// Every destination batches are written to, starting with the main one.
+    pub fn destinations(&self) -> Vec<DestinationConfig> {
+        let main = DestinationConfig {
+            destination: self.destination.clone(),
+            bucket_region: self.bucket_region.clone(),
+            server_side_encryption: self.server_side_encryption.clone(),
+            sse_kms_key_id: self.sse_kms_key_id.clone(),
+            storage_class: self.storage_class.clone(),
+        };
+        [main].into_iter().chain(self.extra_destinations.iter().cloned()).collect()
+    }
+}
+
+/// Where a pipeline's log data comes from.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) enum InputConfig {
+    /// Our standard input.
+    #[default]
+    Stdin,
+
+    /// A file (usually a FIFO), read until end-of-file.
+    File {
+        path: String,
+    },
+
+    /// A regular file, followed like `tail -F`.
+    Follow {
+        path: String,
+        state: Option<PathBuf>,
+    },
+
+    /// A Unix domain socket.
+    #[cfg(unix)]
+    Unix(UnixSocketOptions),
+
+    /// Syslog over UDP and/or TCP.
+    Syslog(SyslogOptions),
+
+    /// HTTP POSTs to /ingest.
+    Http(HttpIngestOptions),
+
+    /// The stdout and stderr of a command we launch.
+    Command {
+        #[serde(deserialize_with = "deserialize_command")]
+        command: Vec<OsString>,
+    },
+}
+
+/// The layout of a configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    tempdir: Option<PathBuf>,
+    max_concurrent_uploads: Option<usize>,
+    metrics_listen: Option<SocketAddr>,
+    statsd: Option<StatsdOptions>,
+    ledger: Option<LedgerOptions>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    abort_uploads_older_than: Option<Duration>,
+    assume_role: Option<AssumeRoleOptions>,
+    azure: Option<AzureOptions>,
+    gcs: Option<GcsOptions>,
+    #[serde(rename = "pipeline", default)]
+    pipelines: Vec<PipelineConfig>,
+}
+
+impl Config {
+    /// Read a TOML configuration file, using `defaults` for settings the file doesn't specify.
+    pub fn load(path: &Path, defaults: &ConfigDefaults) -> Result<Self, ConfigError> {
+        let text = read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let mut config = Self::parse(&text, defaults)?;
+        config.source = Some(ConfigSource {
+            path: path.to_path_buf(),
+            defaults: defaults.clone(),
+        });
+        Ok(config)
+    }
+
+    /// Read the configuration file again, returning the new configuration. The result has not been validated.
+    pub fn reload(source: &ConfigSource) -> Result<Self, ConfigError> {
+        Self::load(&source.path, &source.defaults)
+    }
+
+    /// Parse the contents of a TOML configuration file.
+    pub fn parse(text: &str, defaults: &ConfigDefaults) -> Result<Self, ConfigError> {
+        let file: ConfigFile = toml::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        let temp_dir = file.tempdir.unwrap_or_else(|| defaults.temp_dir.clone());
+        let mut pipelines = file.pipelines;
+        for pipeline in pipelines.iter_mut() {
+            pipeline.temp_dir.get_or_insert_with(|| temp_dir.clone());
+        }
+
+        Ok(Self {
+            max_concurrent_uploads: file.max_concurrent_uploads.unwrap_or(defaults.max_concurrent_uploads),
+            pipelines,
+            metrics_listen: file.metrics_listen.or(defaults.metrics_listen),
+            statsd: file.statsd.or_else(|| defaults.statsd.clone()),
+            ledger: file.ledger.or_else(|| defaults.ledger.clone()),
+            abort_uploads_older_than: file.abort_uploads_older_than.or(defaults.abort_uploads_older_than),
+            assume_role: file.assume_role.or_else(|| defaults.assume_role.clone()),
+            azure: file.azure.or_else(|| defaults.azure.clone()),
+            gcs: file.gcs.or_else(|| defaults.gcs.clone()),
+            source: None,
+        })
+    }
+
+    /// Check that the configuration can be run: pipeline names are unique, destinations and templates are valid, and
+    /// no two pipelines want the same input.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.pipelines.is_empty() {
+            return Err(ConfigError::Invalid("No pipelines configured".to_string()));
+        }
+
+        if self.max_concurrent_uploads == 0 {
+            return Err(ConfigError::Invalid("max_concurrent_uploads must be at least 1".to_string()));
+        }
+
+        if let Some(ledger) = &self.ledger {
+            if ledger.file.is_none() && ledger.s3.is_none() {
+                return Err(ConfigError::Invalid("ledger needs a file, an s3 prefix, or both".to_string()));
+            }
+
+            if let Some(url) = &ledger.s3 {
+                parse_s3_url(url).map_err(|e| ConfigError::Invalid(format!("Invalid ledger S3 URL: {e}")))?;
+            }
+
+            if let Some(acl) = &ledger.acl
+                && !ObjectCannedAcl::values().contains(&acl.as_str())
+            {
+                let expected = ObjectCannedAcl::values();
+                return Err(ConfigError::Invalid(format!("Unknown ledger ACL {acl:?}; expected one of {expected:?}")));
+            }
+
+            if let Some(sse) = &ledger.server_side_encryption
+                && !ServerSideEncryption::values().contains(&sse.as_str())
+            {
+                let expected = ServerSideEncryption::values();
+                return Err(ConfigError::Invalid(format!(
+                    "Unknown ledger encryption {sse:?}; expected one of {expected:?}"
+                )));
+            }
+
+            if ledger.sse_kms_key_id.is_some()
+                && !ledger.server_side_encryption.as_deref().is_some_and(|sse| sse.starts_with("aws:kms"))
+            {
+                return Err(ConfigError::Invalid(
+                    "The ledger's sse_kms_key_id needs aws:kms or aws:kms:dsse encryption".to_string(),
+                ));
+            }
+        }
+
+        if let Some(gcs) = &self.gcs {
+            gcs.validate().map_err(ConfigError::Invalid)?;
+        }
+
+        let mut names = HashSet::new();
+        let mut inputs = HashSet::new();
+
+        for pipeline in &self.pipelines {
+            let invalid = |msg: String| ConfigError::InvalidPipeline(pipeline.name.clone(), msg);
+
+            if !names.insert(pipeline.name.as_str()) {
+                return Err(invalid("Duplicate pipeline name".to_string()));
+            }
+
+            if pipeline.destination.is_empty() {
+                return Err(invalid("Missing write destination.".to_string()));
+            }
+
+            let destinations = pipeline.destinations();
+            for destination in destinations.iter().chain(&pipeline.fallback_destinations) {
+                validate_destination(destination, pipeline).map_err(invalid)?;
+            }
+
+            // A pipeline writing nowhere in S3 can't use S3's options, and manifests go to the main destination.
+            let is_s3 = |url: &str| matches!(parse_destination(url), Ok((Location::S3(_), _)));
+            let s3_only = [
+                ("checksum_algorithm", pipeline.checksum_algorithm.is_some()),
+                ("acl", pipeline.acl.is_some()),
+                ("expected_bucket_owner", pipeline.expected_bucket_owner.is_some()),
+            ];
+            if !destinations.iter().chain(&pipeline.fallback_destinations).any(|d| is_s3(&d.destination))
+                && let Some((option, _)) = s3_only.iter().find(|(_, set)| *set)
+            {
+                return Err(invalid(format!("{option} can only be used with an S3 destination")));
+            }
+            if pipeline.manifest.is_some() && !is_s3(&pipeline.destination) {
+                return Err(invalid("manifest can only be used with an S3 destination".to_string()));
+            }
+
+            let is_azure = |d: &DestinationConfig| d.destination.starts_with(AZURE_PROTO_PREFIX);
+            if destinations.iter().chain(&pipeline.fallback_destinations).any(is_azure) {
+                self.azure.clone().unwrap_or_default().validate().map_err(invalid)?;
+            }
+
+            if let Some(min) = pipeline.min_destinations
+                && (min == 0 || min > destinations.len())
+            {
+                return Err(invalid(format!("min_destinations must be from 1 to {}", destinations.len())));
+            }
+
+            if pipeline.fallback_after == Some(0) {
+                return Err(invalid("fallback_after must be at least 1".to_string()));
+            }
+
+            if let InputConfig::Syslog(options) = &pipeline.input
+                && options.max_partitions == 0
+            {
+                return Err(invalid("max_partitions must be at least 1".to_string()));
+            }
+
+            if let Some(manifest) = &pipeline.manifest {
+                let names = template_variable_names(manifest).map_err(|e| invalid(format!("Invalid manifest: {e}")))?;
+                if let Some(name) = names.iter().find(|name| !MANIFEST_VARIABLES.contains(&name.as_str())) {
+                    return Err(invalid(format!("Manifest template cannot use {{{name}}}")));
+                }
+
+                // Otherwise, hosts writing to the same bucket would overwrite each other's manifests.
+                if !names.iter().any(|name| name == "host_id") {
+                    return Err(invalid("Manifest template must use {host_id}".to_string()));
+                }
+            }
+
+            if let Some(acl) = &pipeline.acl
+                && !ObjectCannedAcl::values().contains(&acl.as_str())
+            {
+                return Err(invalid(format!("Unknown ACL {acl:?}; expected one of {:?}", ObjectCannedAcl::values())));
+            }
+
+            if pipeline.max_size > S3_MAXIMUM_SIZE.as_u64() {
+                return Err(invalid(format!("Maximum size cannot be greater than {S3_MAXIMUM_SIZE:?}")));
+            }
+
+            for key in pipeline.input.resource_keys() {
+                if !inputs.insert(key.clone()) {
+                    return Err(invalid(format!("Input {key} is used by more than one pipeline")));
+                }
+            }
+        }
+
+        if self.abort_uploads_older_than.is_some() {
+            self.validate_cleanup()?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that this host's incomplete uploads can be told apart from other hosts', for `abort_uploads_older_than`
+    /// and `--cleanup-uploads`. S3 doesn't report the tags or metadata of an incomplete upload, so they're found by
+    /// key, and every S3 destination's template must use {host_id}.
+    pub fn validate_cleanup(&self) -> Result<(), ConfigError> {
+        for pipeline in &self.pipelines {
+            let destinations = pipeline.destinations();
+            for destination in destinations.iter().chain(&pipeline.fallback_destinations) {
+                let Ok((Location::S3(_), pattern)) = parse_destination(&destination.destination) else {
+                    continue;
+                };
+                if !template_variable_names(&pattern).is_ok_and(|names| names.iter().any(|name| name == "host_id")) {
+                    return Err(ConfigError::InvalidPipeline(
+                        pipeline.name.clone(),
+                        format!(
+                            "Cleaning up incomplete uploads needs {{host_id}} in every S3 destination, not {}",
+                            destination.destination
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that `self` can replace the running configuration `previous` without a restart. Pipelines may be added
+    /// and their destinations and batching settings changed, but an existing pipeline can't be removed or have its
+    /// input changed since that would mean closing an input that may still have data in flight.
+    pub fn validate_reload(&self, previous: &Config) -> Result<(), ConfigError> {
+        self.validate()?;
+
+        if self.metrics_listen != previous.metrics_listen {
+            return Err(ConfigError::Invalid("Changing metrics_listen requires a restart".to_string()));
+        }
+
+        if self.statsd != previous.statsd {
+            return Err(ConfigError::Invalid("Changing statsd requires a restart".to_string()));
+        }
+
+        if self.ledger != previous.ledger {
+            return Err(ConfigError::Invalid("Changing ledger requires a restart".to_string()));
+        }
+
+        if self.assume_role != previous.assume_role {
+            return Err(ConfigError::Invalid("Changing assume_role requires a restart".to_string()));
+        }
+
+        if self.azure != previous.azure {
+            return Err(ConfigError::Invalid("Changing azure requires a restart".to_string()));
+        }
+
+        if self.gcs != previous.gcs {
+            return Err(ConfigError::Invalid("Changing gcs requires a restart".to_string()));
+        }
+
+        for old in &previous.pipelines {
+            match self.pipelines.iter().find(|new| new.name == old.name) {
+                None => {
+                    return Err(ConfigError::InvalidPipeline(
+                        old.name.clone(),
+                        "Removing a pipeline requires a restart".to_string(),
+                    ));
+                }
+                Some(new) if new.input != old.input => {
+                    return Err(ConfigError::InvalidPipeline(
+                        old.name.clone(),
+                        "Changing a pipeline's input requires a restart".to_string(),
+                    ));
+                }
+                Some(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check one of a pipeline's destinations: its URL and template, and that its settings suit the kind of destination.
+/// The template may only use the variables every batch has and those the pipeline's input provides.
+fn validate_destination(destination: &DestinationConfig, pipeline: &PipelineConfig) -> Result<(), String> {
+    let (location, pattern) =
+        parse_destination(&destination.destination).map_err(|e| format!("Invalid destination: {e}"))?;
+    let names = template_variable_names(&pattern).map_err(|e| e.to_string())?;
+    let provided = pipeline.input.template_variables();
+    if let Some(name) =
+        names.iter().find(|name| !TEMPLATE_VARIABLES.contains(&name.as_str()) && !provided.contains(&name.as_str()))
+    {
+        return Err(format!("Template variable {{{name}}} is unknown or not provided by this pipeline's input"));
+    }
+
+    let bucket_kind = match &location {
+        Location::S3(bucket) => Some(BucketKind::of(bucket)),
+        Location::Azure {
+            ..
+        }
+        | Location::Gcs(_)
+        | Location::File => None,
+    };
+    match bucket_kind {
+        None => {
+            let s3_only = [
+                ("bucket_region", destination.bucket_region.is_some()),
+                ("server_side_encryption", destination.server_side_encryption.is_some()),
+                ("sse_kms_key_id", destination.sse_kms_key_id.is_some()),
+                ("storage_class", destination.storage_class.is_some()),
+            ];
+            if let Some((option, _)) = s3_only.iter().find(|(_, set)| *set) {
+                return Err(format!("{option} can only be used with an S3 destination"));
+            }
+        }
+        Some(BucketKind::AccessPoint(_) | BucketKind::MultiRegionAccessPoint)
+            if destination.bucket_region.is_some() =>
+        {
+            return Err("bucket_region can't be given for an access point ARN".to_string());
+        }
+        Some(BucketKind::Directory) if pipeline.acl.is_some() => {
+            return Err("Directory buckets don't support ACLs".to_string());
+        }
+        Some(BucketKind::Directory)
+            if destination
+                .storage_class
+                .as_deref()
+                .is_some_and(|class| class != StorageClass::ExpressOnezone.as_str()) =>
+        {
+            return Err("Directory buckets only support the EXPRESS_ONEZONE storage class".to_string());
+        }
+        _ => (),
+    }
+
+    // Azure commits a blob from a limited number of blocks.
+    if matches!(location, Location::Azure { .. }) && pipeline.max_size > MAX_BLOB_SIZE {
+        let max = Byte::from_u64(MAX_BLOB_SIZE);
+        return Err(format!("Maximum size cannot be greater than {max:?} for an Azure destination"));
+    }
+
+    if let Some(sse) = &destination.server_side_encryption
+        && !ServerSideEncryption::values().contains(&sse.as_str())
+    {
+        return Err(format!("Unknown encryption {sse:?}; expected one of {:?}", ServerSideEncryption::values()));
+    }
+
+    if destination.sse_kms_key_id.is_some()
+        && !destination.server_side_encryption.as_deref().is_some_and(|sse| sse.starts_with("aws:kms"))
+    {
+        return Err("sse_kms_key_id needs aws:kms or aws:kms:dsse encryption".to_string());
+    }
+
+    if let Some(class) = &destination.storage_class
+        && !StorageClass::values().contains(&class.as_str())
+    {
+        return Err(format!("Unknown storage class {class:?}; expected one of {:?}", StorageClass::values()));
+    }
+
+    Ok(())
+}
+
+impl InputConfig {
+    /// The template variables this input attaches to its batches, beyond those every batch has.
+    fn template_variables(&self) -> &'static [&'static str] {
+        match self {
+            Self::Command {
+                ..
+            } => &["stream"],
+            Self::Syslog(_) => SYSLOG_VARIABLES,
+            _ => &[],
+        }
+    }
+
+    /// Identify the external resources this input claims, so two pipelines can't both try to read stdin or listen on
+    /// the same port.
+    fn resource_keys(&self) -> Vec<String> {
+        match self {
+            // A command inherits our stdin, so it can't share it with a pipeline reading stdin.
+            Self::Stdin
+            | Self::Command {
+                ..
+            } => vec!["stdin".to_string()],
+            Self::File {
+                path,
+            }
+            | Self::Follow {
+                path,
+                ..
+            } => vec![format!("file {path}")],
+            #[cfg(unix)]
+            Self::Unix(options) => vec![format!("unix socket {:?}", options.path)],
+            Self::Syslog(options) => options
+                .udp
+                .iter()
+                .map(|addr| format!("udp {addr}"))
+                .chain(options.tcp.iter().map(|addr| format!("tcp {addr}")))
+                .collect(),
+            Self::Http(options) => vec![format!("tcp {}", options.addr)],
+        }
+    }
+}
+
+fn default_max_size() -> u64 {
+    Byte::from_str(DEFAULT_MAX_SIZE).unwrap().as_u64()
+}
+
+fn default_max_duration() -> Duration {
+    parse_duration(DEFAULT_MAX_DURATION).unwrap()
+}
+
+pub(crate) fn default_http_max_body() -> u64 {
+    Byte::from_str(DEFAULT_HTTP_MAX_BODY).unwrap().as_u64()
+}
+
+/// Deserialize a size such as "10MiB" into a byte count.
+pub(crate) fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    Byte::from_str(&s).map(|b| b.as_u64()).map_err(|e| DeError::custom(format!("invalid size {s:?}: {e}")))
+}
+
+/// Deserialize a duration such as "1hour 12min 5s".
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map_err(|e| DeError::custom(format!("invalid duration {s:?}: {e}")))
+}
+
+/// Deserialize an optional duration such as "1day".
+fn deserialize_optional_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    deserialize_duration(deserializer).map(Some)
+}
+
+/// Deserialize a command line given as a list of strings.
+fn deserialize_command<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<OsString>, D::Error> {
+    let command = Vec::<String>::deserialize(deserializer)?;
+    if command.is_empty() {
+        return Err(DeError::custom("command cannot be empty"));
+    }
+    Ok(command.into_iter().map(OsString::from).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{ChecksumAlgorithm, Config, ConfigDefaults, InputConfig},
+        std::{path::Path, time::Duration},
+    };
+
+    fn defaults() -> ConfigDefaults {
+        ConfigDefaults {
+            temp_dir: "/tmp".into(),
+            max_concurrent_uploads: 4,
+            metrics_listen: None,
+            statsd: None,
+            ledger: None,
+            abort_uploads_older_than: None,
+            assume_role: None,
+            azure: None,
+            gcs: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            max_concurrent_uploads = 2
+            abort_uploads_older_than = "1day"
+            assume_role = { role_arn = "arn:aws:iam::111122223333:role/log-delivery", external_id = "logs" }
+            ledger = { file = "/var/log/slts-ledger.jsonl", s3 = "s3://audit/ledger/", acl = "bucket-owner-read" }
+
+            [[pipeline]]
+            name = "access"
+            destination = "s3://bucket/access/{host_id}/{unique}.log.gz"
+            size = "10MiB"
+            duration = "5m"
+            gzip = true
+            checksum_algorithm = "crc32c"
+            verify = true
+            no_overwrite = true
+            acl = "bucket-owner-full-control"
+            expected_bucket_owner = "111122223333"
+            bucket_region = "eu-west-1"
+            storage_class = "STANDARD_IA"
+            min_destinations = 2
+            fallback_after = 5
+            fallback_retry = "10min"
+            manifest = "_manifest/{host_id}/{year}/{month}/{day}/{hour}.json"
+            input = { type = "follow", path = "/var/log/httpd/access_log" }
+
+            [[pipeline.extra_destination]]
+            destination = "s3://bucket-dr/access/{host_id}/{unique}.log.gz"
+            bucket_region = "us-west-2"
+            server_side_encryption = "aws:kms"
+            sse_kms_key_id = "alias/logs"
+
+            [[pipeline.extra_destination]]
+            destination = "file:///var/log/archive/{host_id}/{unique}.log.gz"
+
+            [[pipeline.fallback_destination]]
+            destination = "s3://bucket-fallback/access/{host_id}/{unique}.log.gz"
+
+            [[pipeline]]
+            name = "syslog"
+            destination = "s3://bucket/syslog/{host_id}/{facility}/{unique}.log"
+            tempdir = "/var/spool/slts"
+
+            [pipeline.input]
+            type = "syslog"
+            udp = ["127.0.0.1:514"]
+            "#,
+            &defaults(),
+        )
+        .unwrap();
+
+        config.validate().unwrap();
+        assert_eq!(config.max_concurrent_uploads, 2);
+        assert_eq!(config.abort_uploads_older_than, Some(Duration::from_secs(86400)));
+        let assume_role = config.assume_role.as_ref().unwrap();
+        assert_eq!(assume_role.role_arn, "arn:aws:iam::111122223333:role/log-delivery");
+        assert_eq!(assume_role.external_id.as_deref(), Some("logs"));
+        assert_eq!(assume_role.session_name, None);
+        let ledger = config.ledger.as_ref().unwrap();
+        assert_eq!(ledger.file.as_deref(), Some(Path::new("/var/log/slts-ledger.jsonl")));
+        assert_eq!(ledger.s3.as_deref(), Some("s3://audit/ledger/"));
+        assert_eq!(ledger.acl.as_deref(), Some("bucket-owner-read"));
+        let access = &config.pipelines[0];
+        assert_eq!(access.max_size, 10 << 20);
+        assert_eq!(access.max_duration, Duration::from_secs(300));
+        assert!(access.compress);
+        assert_eq!(access.checksum_algorithm, Some(ChecksumAlgorithm::Crc32c));
+        assert!(access.verify);
+        assert!(access.no_overwrite);
+        assert_eq!(access.acl.as_deref(), Some("bucket-owner-full-control"));
+        assert_eq!(access.expected_bucket_owner.as_deref(), Some("111122223333"));
+        assert_eq!(access.bucket_region.as_deref(), Some("eu-west-1"));
+        assert_eq!(access.manifest.as_deref(), Some("_manifest/{host_id}/{year}/{month}/{day}/{hour}.json"));
+        assert_eq!(access.temp_dir.as_deref(), Some(Path::new("/tmp")));
+        assert_eq!(access.min_destinations, Some(2));
+        let destinations = access.destinations();
+        assert_eq!(destinations.len(), 3);
+        assert_eq!(destinations[0].storage_class.as_deref(), Some("STANDARD_IA"));
+        assert_eq!(destinations[1].bucket_region.as_deref(), Some("us-west-2"));
+        assert_eq!(destinations[1].sse_kms_key_id.as_deref(), Some("alias/logs"));
+        assert_eq!(destinations[2].destination, "file:///var/log/archive/{host_id}/{unique}.log.gz");
+        assert_eq!(access.fallback_destinations.len(), 1);
+        assert_eq!(access.fallback_after, Some(5));
+        assert_eq!(access.fallback_retry, Some(Duration::from_secs(600)));
+        assert!(
+            matches!(&access.input, InputConfig::Follow { path, state: None } if path == "/var/log/httpd/access_log")
+        );
+
+        let syslog = &config.pipelines[1];
+        assert_eq!(syslog.max_size, 1 << 20);
+        assert_eq!(syslog.temp_dir.as_deref(), Some(Path::new("/var/spool/slts")));
+        assert!(matches!(&syslog.input, InputConfig::Syslog(options) if options.udp.len() == 1 && !options.json));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let parse = |text: &str| Config::parse(text, &defaults()).unwrap().validate();
+
+        assert!(parse("").is_err());
+        assert!(parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket\"").is_err());
+        assert!(parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/{nope\"").is_err());
+
+        // Templates can only use the variables the input provides.
+        assert!(parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/{hots_id}\"").is_err());
+        assert!(parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/{stream}\"").is_err());
+        assert!(parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/{facility}/{host_id}\"").is_err());
+        assert!(
+            parse(
+                "[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/{facility}/{unique}\"\n\
+                 input = { type = \"syslog\", udp = [\"127.0.0.1:5514\"] }"
+            )
+            .is_ok()
+        );
+
+        // A manifest can't be split by {unique} or a record's variables, and must belong to one host.
+        assert!(
+            parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/a\"\nmanifest = \"_m/{unique}.json\"")
+                .is_err()
+        );
+        assert!(
+            parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/a\"\nmanifest = \"_m/{hour}.json\"")
+                .is_err()
+        );
+
+        // ACLs must be canned ACLs S3 knows.
+        assert!(parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/a\"\nacl = \"everyone\"").is_err());
+
+        // An access point ARN names its own region, and directory buckets have no ACLs and only one storage class.
+        assert!(
+            parse(
+                "[[pipeline]]\nname = \"a\"\n\
+                 destination = \"arn:aws:s3:us-west-2:123456789012:accesspoint/logs/a\"\nbucket_region = \"us-east-1\""
+            )
+            .is_err()
+        );
+        assert!(
+            parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://logs--usw2-az1--x-s3/a\"\nacl = \"private\"")
+                .is_err()
+        );
+        let directory = "[[pipeline]]\nname = \"a\"\ndestination = \"s3://logs--usw2-az1--x-s3/a\"\n";
+        assert!(parse(&format!("{directory}storage_class = \"GLACIER\"")).is_err());
+        assert!(parse(&format!("{directory}storage_class = \"EXPRESS_ONEZONE\"")).is_ok());
+
+        // File destinations take no S3 options.
+        assert!(parse("[[pipeline]]\nname = \"a\"\ndestination = \"file:///var/log/archive/a.log\"").is_ok());
+        assert!(
+            parse("[[pipeline]]\nname = \"a\"\ndestination = \"file:///var/log/archive/a.log\"\nacl = \"private\"")
+                .is_err()
+        );
+
+        // Extra destinations are checked like the main one, and at least one destination must be required.
+        let fan_out = "[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/a\"\n";
+        assert!(parse(&format!("{fan_out}[[pipeline.extra_destination]]\ndestination = \"s3://dr\"")).is_err());
+        assert!(
+            parse(&format!(
+                "{fan_out}[[pipeline.extra_destination]]\ndestination = \"file:///a.log\"\nstorage_class = \"GLACIER\""
+            ))
+            .is_err()
+        );
+        assert!(parse(&format!("{fan_out}sse_kms_key_id = \"alias/logs\"")).is_err());
+        assert!(parse(&format!("{fan_out}min_destinations = 0")).is_err());
+        assert!(parse(&format!("{fan_out}min_destinations = 2")).is_err());
+
+        // So are fallback destinations.
+        assert!(parse(&format!("{fan_out}[[pipeline.fallback_destination]]\ndestination = \"s3://fb/a\"")).is_ok());
+        assert!(parse(&format!("{fan_out}[[pipeline.fallback_destination]]\ndestination = \"fb\"")).is_err());
+        assert!(parse(&format!("{fan_out}fallback_after = 0")).is_err());
+
+        // A syslog input must be allowed at least one batch.
+        assert!(
+            parse(
+                "[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/{hostname}/{unique}\"\n\
+                 input = { type = \"syslog\", udp = [\"127.0.0.1:5514\"], max_partitions = 0 }"
+            )
+            .is_err()
+        );
+
+        // Azure destinations need credentials, take no S3 options, and can't be written in too many blocks.
+        let azure = "[[pipeline]]\nname = \"a\"\ndestination = \"azblob://logsacct/logs/a.log\"\n";
+        assert!(parse(azure).is_err());
+        assert!(parse(&format!("azure = {{ account_key = \"a2V5\" }}\n{azure}")).is_ok());
+        assert!(parse(&format!("azure = {{ account_key = \"not base64!\" }}\n{azure}")).is_err());
+        assert!(parse(&format!("azure = {{ account_key = \"a2V5\" }}\n{azure}size = \"488 GiB\"")).is_ok());
+        assert!(parse(&format!("azure = {{ account_key = \"a2V5\" }}\n{azure}size = \"489 GiB\"")).is_err());
+        assert!(
+            parse(&format!("azure = {{ sas_token = \"sv=2021-08-06&sig=x\" }}\n{azure}acl = \"private\"")).is_err()
+        );
+
+        // As are GCS destinations, whose credentials can't be both a key file and none.
+        let gcs = "[[pipeline]]\nname = \"a\"\ndestination = \"gs://logs/a.log\"\n";
+        assert!(parse(gcs).is_ok());
+        assert!(parse(&format!("{gcs}storage_class = \"GLACIER\"")).is_err());
+        assert!(parse(&format!("gcs = {{ credentials_file = \"/etc/gcs.json\", anonymous = true }}\n{gcs}")).is_err());
+
+        // Incomplete uploads can only be cleaned up for templates that tell hosts apart.
+        let cleanup = "abort_uploads_older_than = \"1day\"\n[[pipeline]]\nname = \"a\"\n";
+        assert!(parse(&format!("{cleanup}destination = \"s3://bucket/{{host_id}}/{{unique}}\"")).is_ok());
+        assert!(parse(&format!("{cleanup}destination = \"s3://bucket/{{unique}}\"")).is_err());
+        assert!(parse(&format!("{cleanup}destination = \"file:///var/log/{{unique}}\"")).is_ok());
+
+        // Two pipelines can't both read stdin.
+        assert!(
+            parse(
+                "[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/a\"\n\
+                 [[pipeline]]\nname = \"b\"\ndestination = \"s3://bucket/b\""
+            )
+            .is_err()
+        );
+
+        // Nor can they share a name.
+        assert!(
+            parse(
+                "[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/a\"\n\
+                 [[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/b\"\ninput = { type = \"file\", path = \"x\" }"
+            )
+            .is_err()
+        );
+
+        // Ledger objects can be encrypted with KMS, as long as the key goes with KMS encryption.
+        let pipeline = "[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/a\"\n";
+        let ledger = "ledger = { s3 = \"s3://audit/ledger/\", ";
+        assert!(
+            parse(&format!("{ledger}server_side_encryption = \"aws:kms\", sse_kms_key_id = \"k\" }}\n{pipeline}"))
+                .is_ok()
+        );
+        assert!(parse(&format!("{ledger}server_side_encryption = \"rot13\" }}\n{pipeline}")).is_err());
+        assert!(parse(&format!("{ledger}sse_kms_key_id = \"k\" }}\n{pipeline}")).is_err());
+    }
+
+    #[test]
+    fn test_validate_reload() {
+        let parse = |text: &str| Config::parse(text, &defaults()).unwrap();
+        let old = parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/a\"");
+
+        // New destinations, limits and pipelines are fine.
+        let new = parse(
+            "[[pipeline]]\nname = \"a\"\ndestination = \"s3://other/a\"\nsize = \"5MiB\"\n\
+             [[pipeline]]\nname = \"b\"\ndestination = \"s3://bucket/b\"\ninput = { type = \"file\", path = \"x\" }",
+        );
+        new.validate_reload(&old).unwrap();
+
+        // Removing a pipeline or changing its input is not.
+        let removed = parse("[[pipeline]]\nname = \"b\"\ndestination = \"s3://bucket/b\"");
+        assert!(removed.validate_reload(&old).is_err());
+        let changed = parse(
+            "[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/a\"\ninput = { type = \"file\", path = \"x\" }",
+        );
+        assert!(changed.validate_reload(&old).is_err());
+
+        // Nor is a template using a variable the input doesn't provide.
+        let unknown = parse("[[pipeline]]\nname = \"a\"\ndestination = \"s3://bucket/{facility}/a\"");
+        assert!(unknown.validate_reload(&old).is_err());
+    }
+}
@@ -1,6 +1,11 @@
-use std::{
-    error::Error,
-    fmt::{Debug, Display, Formatter, Result as FmtResult},
+use {
+    aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError},
+    std::{
+        error::Error,
+        fmt::{Debug, Display, Formatter, Result as FmtResult},
+        io::Error as IOError,
+        path::PathBuf,
+    },
 };
 
 /// An error type for why we rejected a user's S3 URL.
@@ -25,6 +30,132 @@ impl Display for InvalidS3URL {
 
 impl Error for InvalidS3URL {}
 
+/// An error type for why we rejected a configuration file.
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Io(PathBuf, IOError),
+    Parse(String),
+    Invalid(String),
+    InvalidPipeline(String, String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Io(path, e) => write!(f, "Unable to read configuration file {}: {}", path.display(), e),
+            Self::Parse(msg) => write!(f, "Invalid configuration file: {}", msg),
+            Self::Invalid(msg) => write!(f, "Invalid configuration: {}", msg),
+            Self::InvalidPipeline(name, msg) => write!(f, "Invalid pipeline {:?}: {}", name, msg),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// A failed S3 request, tagged with a short class (the S3 error code, or the kind of SDK failure) for metrics.
+#[derive(Debug)]
+pub(crate) struct S3RequestError {
+    pub class: String,
+    source: Box<dyn Error + Send + Sync + 'static>,
+}
+
+impl S3RequestError {
+    pub fn new<E, R>(e: SdkError<E, R>) -> Self
+    where
+        E: Error + ProvideErrorMetadata + Send + Sync + 'static,
+        R: Debug + Send + Sync + 'static,
+    {
+        let class = match &e {
+            SdkError::ServiceError(context) => context.err().code().unwrap_or("service").to_string(),
+            SdkError::TimeoutError(_) => "timeout".to_string(),
+            SdkError::DispatchFailure(_) => "dispatch".to_string(),
+            SdkError::ResponseError(_) => "response".to_string(),
+            SdkError::ConstructionFailure(_) => "construction".to_string(),
+            _ => "sdk".to_string(),
+        };
+
+        Self {
+            class,
+            source: Box::new(e),
+        }
+    }
+}
+
+impl Display for S3RequestError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", DisplayErrorContext(&*self.source))
+    }
+}
+
+impl Error for S3RequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// A failed request to a storage service we reach over its REST API rather than an SDK, e.g. Azure Blob Storage,
+/// tagged with a short class (the service's error code, or the kind of failure) for metrics.
+#[derive(Debug)]
+pub(crate) struct StorageRequestError {
+    /// The service, for messages, e.g. `Azure`.
+    pub service: &'static str,
+    pub class: String,
+
+    /// The HTTP status, if a response was received.
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl Display for StorageRequestError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self.status {
+            Some(status) => write!(f, "{} request failed with status {}: {}", self.service, status, self.message),
+            None => write!(f, "{} request failed: {}", self.service, self.message),
+        }
+    }
+}
+
+impl StorageRequestError {
+    /// Whether the request might succeed if sent again: the service was busy or couldn't be reached.
+    pub fn is_retryable(&self) -> bool {
+        self.status.is_none_or(|status| status == 408 || status == 429 || status >= 500)
+    }
+}
+
+impl Error for StorageRequestError {}
+
+/// The checksum S3 reported for an uploaded object doesn't match the one we computed while writing it.
+#[derive(Debug)]
+pub(crate) struct ChecksumMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Checksum mismatch: expected {}, S3 reported {}", self.expected, self.actual)
+    }
+}
+
+impl Error for ChecksumMismatch {}
+
+/// An uploaded object didn't match the spool file when we checked it with HeadObject.
+#[derive(Debug)]
+pub(crate) struct VerificationFailed {
+    /// What didn't match, e.g. `size`.
+    pub what: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Display for VerificationFailed {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Uploaded object {} is {}; expected {}", self.what, self.actual, self.expected)
+    }
+}
+
+impl Error for VerificationFailed {}
+
 /// Error type for non-Unix platforms representing a bad file type
 #[cfg(not(unix))]
 #[derive(Debug)]
@@ -0,0 +1,144 @@
+use {
+    log::{info, warn},
+    std::{
+        sync::{Mutex, MutexGuard},
+        time::{Duration, Instant},
+    },
+};
+
+/// The default number of uploads in a row the main destination must refuse before batches go to a fallback.
+pub(crate) const DEFAULT_FALLBACK_AFTER: u32 = 3;
+
+/// The default time to keep using the fallbacks before trying the main destination again.
+pub(crate) const DEFAULT_FALLBACK_RETRY: &str = "5min";
+
+/// What is inserted into the file name of each object sent to a fallback, before its extension.
+pub(crate) const FALLBACK_KEY_MARKER: &str = "-fallback";
+
+/// The error classes that mean the main destination is unavailable, e.g. after a bucket policy mistake, rather than
+/// having a passing problem.
+const UNAVAILABLE_ERRORS: &[&str] = &[
+    "AccessDenied",
+    "NoSuchBucket",
+    // Azure Blob Storage
+    "AuthorizationFailure",
+    "AuthorizationPermissionMismatch",
+    "ContainerNotFound",
+    // Google Cloud Storage
+    "forbidden",
+    "notFound",
+];
+
+/// Tracks whether a pipeline's main destination is available, to decide when its batches go to a fallback destination
+/// instead and when to switch back.
+#[derive(Debug)]
+pub(crate) struct Fallback {
+    pipeline: String,
+    after: u32,
+    retry: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The number of uploads in a row the main destination has refused.
+    failures: u32,
+
+    /// While falling back, when to try the main destination again.
+    retry_at: Option<Instant>,
+}
+
+impl Fallback {
+    pub fn new(pipeline: &str, after: u32, retry: Duration) -> Self {
+        Self {
+            pipeline: pipeline.to_string(),
+            after,
+            retry,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The state is always consistent between statements; keep going after a panic.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether the next batch should be sent to the main destination. While falling back, one batch is sent there
+    /// every `retry` to see whether it has recovered.
+    pub fn use_main(&self) -> bool {
+        let mut state = self.lock();
+        let now = Instant::now();
+        match state.retry_at {
+            Some(at) if now < at => false,
+            Some(_) => {
+                state.retry_at = Some(now + self.retry);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Note that the main destination took a batch, switching back to it if we were falling back.
+    pub fn main_succeeded(&self) {
+        let mut state = self.lock();
+        if state.retry_at.take().is_some() {
+            let pipeline = self.pipeline.as_str();
+            info!(
+                event = "fallback_ended", pipeline;
+                "The main destination of pipeline {pipeline} has recovered; switching back to it"
+            );
+        }
+        state.failures = 0;
+    }
+
+    /// Note that the main destination failed to take a batch with an error of class `error_class`. Returns whether
+    /// the batch should go to a fallback: once the main destination has refused `after` uploads in a row, until it
+    /// recovers.
+    pub fn main_failed(&self, error_class: &str) -> bool {
+        let mut state = self.lock();
+        if UNAVAILABLE_ERRORS.contains(&error_class) {
+            state.failures = state.failures.saturating_add(1);
+            if state.failures >= self.after && state.retry_at.is_none() {
+                let (pipeline, failures) = (self.pipeline.as_str(), state.failures);
+                warn!(
+                    event = "fallback_started", pipeline, failures, error_class;
+                    "The main destination of pipeline {pipeline} has refused {failures} uploads in a row \
+                     ({error_class}); sending batches to the fallback destinations"
+                );
+                state.retry_at = Some(Instant::now() + self.retry);
+            }
+        }
+        state.retry_at.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {super::Fallback, std::time::Duration};
+
+    #[test]
+    fn test_fallback() {
+        let fallback = Fallback::new("web", 2, Duration::from_secs(3600));
+        assert!(fallback.use_main());
+
+        // Passing problems don't count, and success resets the count.
+        assert!(!fallback.main_failed("AccessDenied"));
+        fallback.main_succeeded();
+        assert!(!fallback.main_failed("AccessDenied"));
+        assert!(!fallback.main_failed("timeout"));
+
+        // Falling back lasts until the retry time.
+        assert!(fallback.main_failed("NoSuchBucket"));
+        assert!(!fallback.use_main());
+        assert!(fallback.main_failed("timeout"));
+
+        // Once it's up, one batch tries the main destination; if that works, we switch back.
+        let fallback = Fallback::new("web", 1, Duration::ZERO);
+        assert!(fallback.main_failed("AccessDenied"));
+        assert!(fallback.use_main());
+        assert!(fallback.main_failed("AccessDenied"));
+        assert!(fallback.use_main());
+        fallback.main_succeeded();
+        assert!(!fallback.main_failed("timeout"));
+    }
+}
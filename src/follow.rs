@@ -0,0 +1,546 @@
+use {
+    crate::async_utils::{Ack, AckedChunk},
+    log::{debug, error, info, warn},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::VecDeque,
+        fs::Metadata,
+        io::{Error as IOError, ErrorKind, SeekFrom},
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    },
+    tokio::{
+        fs::{File, metadata, read_dir, rename, write},
+        io::{AsyncReadExt, AsyncSeekExt},
+        sync::{
+            mpsc::Sender,
+            oneshot::{self, error::TryRecvError},
+        },
+        time::sleep,
+    },
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+
+/// How long to wait after hitting end-of-file before checking the file again.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often we persist the uploaded offset to the state file while data is flowing.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The size of reads to use when tailing the file.
+const FOLLOW_READ_SIZE: usize = 65536;
+
+/// The identity of a file on disk. Rotation is detected by this changing for the path we're following.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+struct FileIdentity {
+    dev: u64,
+    inode: u64,
+}
+
+impl FileIdentity {
+    #[cfg(unix)]
+    fn from_metadata(m: &Metadata) -> Self {
+        Self {
+            dev: m.dev(),
+            inode: m.ino(),
+        }
+    }
+
+    /// Without inodes, we can only detect truncation, not rename-based rotation.
+    #[cfg(not(unix))]
+    fn from_metadata(_m: &Metadata) -> Self {
+        Self::default()
+    }
+}
+
+/// The persisted position within the followed file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+struct FollowState {
+    #[serde(flatten)]
+    identity: FileIdentity,
+    offset: u64,
+}
+
+/// Follows a regular file in the manner of `tail -F`, sending complete lines to the batching loop.
+///
+/// After end-of-file, the path is polled. If the file at the path has a different identity (rename-based rotation),
+/// the remainder of the old file is drained before the new file is opened from the start. If the file shrinks
+/// (truncation), reading restarts from the beginning.
+///
+/// The offset just past the last line whose batch has been uploaded is persisted to `state_path` so a restart resumes
+/// there. If the file was rotated while we weren't running, the old file is looked for beside it by its identity and
+/// its remainder is read first. If a batch can't be uploaded, its lines stay in the batch's kept temp file; the range is
+/// logged as a gap and the offset keeps advancing past later batches. If lines are lost without reaching a batch (e.g.
+/// the batching loop has gone away), the offset stops advancing, so they are read again on the next start.
+pub(crate) struct FileFollower {
+    path: PathBuf,
+    state_path: PathBuf,
+    sender: Sender<AckedChunk>,
+    identity: FileIdentity,
+    offset: u64,
+    partial: Vec<u8>,
+
+    /// The positions before and after each chunk that has been sent but not yet uploaded, in the order they were sent.
+    in_flight: VecDeque<(FollowState, FollowState, oneshot::Receiver<bool>)>,
+
+    /// Set once a chunk has been lost without reaching a batch; the persisted position no longer advances.
+    stalled: bool,
+
+    /// The position after the last chunk that has been uploaded.
+    uploaded: Option<FollowState>,
+    last_saved: Option<FollowState>,
+    last_save_time: Instant,
+}
+
+impl FileFollower {
+    pub fn new(path: PathBuf, state_path: PathBuf, sender: Sender<AckedChunk>) -> Self {
+        Self {
+            path,
+            state_path,
+            sender,
+            identity: FileIdentity::default(),
+            offset: 0,
+            partial: Vec::new(),
+            in_flight: VecDeque::new(),
+            stalled: false,
+            uploaded: None,
+            last_saved: None,
+            last_save_time: Instant::now(),
+        }
+    }
+
+    /// Follow the file until the receiving end of the channel is closed.
+    pub async fn run(mut self) {
+        let mut saved = self.load_state().await;
+        self.uploaded = saved;
+        self.last_saved = saved;
+
+        loop {
+            let mut file = self.open_with_retry().await;
+            let m = match file.metadata().await {
+                Ok(m) => m,
+                Err(e) => {
+                    error!("Unable to stat {:?}: {e}", self.path);
+                    sleep(FOLLOW_POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+
+            let identity = FileIdentity::from_metadata(&m);
+            self.offset = match saved.take() {
+                // Resume only if this is the same file we were reading before and it hasn't been truncated.
+                Some(s) if s.identity == identity && s.offset <= m.len() => s.offset,
+                Some(s) if s.identity != identity => match self.drain_rotated(s).await {
+                    Ok(true) => 0,
+                    Ok(false) => {
+                        debug!("Receiver closed; no longer following {:?}", self.path);
+                        self.save_state().await;
+                        return;
+                    }
+                    Err(e) => {
+                        error!("Unable to read the rest of the file rotated from {:?}: {e}", self.path);
+                        0
+                    }
+                },
+                _ => 0,
+            };
+            self.identity = identity;
+
+            if self.offset > 0 {
+                info!("Resuming {:?} at offset {}", self.path, self.offset);
+                if let Err(e) = file.seek(SeekFrom::Start(self.offset)).await {
+                    error!("Unable to seek {:?} to {}: {e}", self.path, self.offset);
+                    self.offset = 0;
+                }
+            }
+
+            match self.follow_file(&mut file).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    debug!("Receiver closed; no longer following {:?}", self.path);
+                    self.save_state().await;
+                    return;
+                }
+                Err(e) => {
+                    error!("Error while following {:?}: {e}", self.path);
+                    sleep(FOLLOW_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Read from the file until it is rotated. Returns `Ok(true)` when the file has been rotated or truncated and
+    /// should be reopened, and `Ok(false)` when the receiver has gone away.
+    async fn follow_file(&mut self, file: &mut File) -> Result<bool, IOError> {
+        let mut buf = vec![0u8; FOLLOW_READ_SIZE];
+
+        loop {
+            let n_read = file.read(&mut buf).await?;
+            if n_read > 0 {
+                if !self.send_lines(&buf[..n_read], false).await {
+                    return Ok(false);
+                }
+                self.maybe_save_state().await;
+                continue;
+            }
+
+            // End-of-file. Persist what has been uploaded, then see whether the file has been rotated or truncated.
+            self.save_state().await;
+            if self.sender.is_closed() {
+                return Ok(false);
+            }
+
+            match metadata(&self.path).await {
+                Ok(m) if FileIdentity::from_metadata(&m) != self.identity => {
+                    // Renamed away. Anything written to the old file between our last read and the rename is still
+                    // readable from our handle.
+                    info!("{:?} has been rotated; reopening", self.path);
+                    return self.drain(file).await;
+                }
+                Ok(m) if m.len() < self.offset + self.partial.len() as u64 => {
+                    info!("{:?} has been truncated; reading from the start", self.path);
+                    self.partial.clear();
+                    self.offset = 0;
+                    file.seek(SeekFrom::Start(0)).await?;
+                }
+                Ok(_) => sleep(FOLLOW_POLL_INTERVAL).await,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    // Removed and not yet recreated. Keep draining our handle until something appears at the path.
+                    sleep(FOLLOW_POLL_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Send everything left in `file`, including a final line without a newline. Returns false if the receiver has
+    /// gone away.
+    async fn drain(&mut self, file: &mut File) -> Result<bool, IOError> {
+        let mut buf = vec![0u8; FOLLOW_READ_SIZE];
+        loop {
+            let n_read = file.read(&mut buf).await?;
+            if n_read == 0 {
+                return Ok(self.send_lines(&[], true).await);
+            }
+            if !self.send_lines(&buf[..n_read], false).await {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Read the rest of the file we were following when we last stopped, which has since been rotated away from our
+    /// path. Returns false if the receiver has gone away.
+    async fn drain_rotated(&mut self, saved: FollowState) -> Result<bool, IOError> {
+        let Some(rotated) = self.find_file(saved.identity).await? else {
+            warn!(
+                "{:?} was rotated while we weren't following it and the old file can't be found; anything written to \
+                 it after offset {} has been skipped",
+                self.path, saved.offset
+            );
+            return Ok(true);
+        };
+
+        let mut file = File::open(&rotated).await?;
+        if file.metadata().await?.len() <= saved.offset {
+            return Ok(true);
+        }
+
+        info!("{:?} was rotated to {rotated:?}; reading the rest of it from offset {}", self.path, saved.offset);
+        file.seek(SeekFrom::Start(saved.offset)).await?;
+        self.identity = saved.identity;
+        self.offset = saved.offset;
+        self.drain(&mut file).await
+    }
+
+    /// Look for the file with `identity` in the directory holding our path.
+    async fn find_file(&self, identity: FileIdentity) -> Result<Option<PathBuf>, IOError> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut entries = read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Ok(m) = entry.metadata().await
+                && m.is_file()
+                && FileIdentity::from_metadata(&m) == identity
+            {
+                return Ok(Some(entry.path()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Send all complete lines in `data` (along with any partial line left over from the previous call). The trailing
+    /// partial line is held back unless `flush` is set. Returns false if the receiver has gone away.
+    async fn send_lines(&mut self, data: &[u8], flush: bool) -> bool {
+        self.partial.extend_from_slice(data);
+
+        let end = if flush {
+            self.partial.len()
+        } else {
+            match self.partial.iter().rposition(|&b| b == b'\n') {
+                Some(pos) => pos + 1,
+                None => return true,
+            }
+        };
+
+        if end == 0 {
+            return true;
+        }
+
+        let remainder = self.partial.split_off(end);
+        let lines = std::mem::replace(&mut self.partial, remainder);
+        let n_bytes = lines.len() as u64;
+        let (ack, uploaded) = oneshot::channel();
+        let chunk = AckedChunk {
+            data: lines,
+            ack: Ack::Uploaded(ack),
+        };
+        if self.sender.send(chunk).await.is_err() {
+            return false;
+        }
+
+        let start = FollowState {
+            identity: self.identity,
+            offset: self.offset,
+        };
+        self.offset += n_bytes;
+        if !self.stalled {
+            let end = FollowState {
+                identity: self.identity,
+                offset: self.offset,
+            };
+            self.in_flight.push_back((start, end, uploaded));
+        }
+        true
+    }
+
+    /// Open the file, waiting for it to appear if it doesn't exist yet.
+    async fn open_with_retry(&self) -> File {
+        let mut logged = false;
+        loop {
+            match File::open(&self.path).await {
+                Ok(f) => return f,
+                Err(e) => {
+                    if !logged {
+                        warn!("Unable to open {:?} ({e}); waiting for it to appear", self.path);
+                        logged = true;
+                    }
+                    sleep(FOLLOW_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Read the persisted state, if any.
+    async fn load_state(&self) -> Option<FollowState> {
+        let data = match tokio::fs::read(&self.state_path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Unable to read follow state from {:?}: {e}", self.state_path);
+                return None;
+            }
+        };
+
+        match serde_json::from_slice(&data) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!("Ignoring invalid follow state in {:?}: {e}", self.state_path);
+                None
+            }
+        }
+    }
+
+    /// Persist the state if it has changed and we haven't done so recently.
+    async fn maybe_save_state(&mut self) {
+        if self.last_save_time.elapsed() >= STATE_SAVE_INTERVAL {
+            self.save_state().await;
+        }
+    }
+
+    /// Note which chunks have been uploaded or failed, then persist the position after the last of them. This writes
+    /// to a temporary file and renames it into place so a crash never leaves a half-written state file behind.
+    async fn save_state(&mut self) {
+        // Consecutive failed chunks of the same file are logged as one gap.
+        let mut gap: Option<(FollowState, FollowState)> = None;
+        while let Some((start, end, uploaded)) = self.in_flight.front_mut() {
+            let (start, end) = (*start, *end);
+            match uploaded.try_recv() {
+                Ok(true) => (),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => {
+                    // The chunk was dropped without reaching a batch that was sent or kept.
+                    warn!(
+                        "A batch from {:?} was lost; lines after offset {} will be read again on restart",
+                        self.path,
+                        self.uploaded.map_or(0, |s| s.offset)
+                    );
+                    self.in_flight.clear();
+                    self.stalled = true;
+                    break;
+                }
+                Ok(false) => match &mut gap {
+                    Some((_, gap_end)) if *gap_end == start => *gap_end = end,
+                    _ => {
+                        if let Some((gap_start, gap_end)) = gap.replace((start, end)) {
+                            self.log_gap(gap_start, gap_end);
+                        }
+                    }
+                },
+            }
+            self.uploaded = Some(end);
+            self.in_flight.pop_front();
+        }
+        if let Some((gap_start, gap_end)) = gap {
+            self.log_gap(gap_start, gap_end);
+        }
+
+        let Some(state) = self.uploaded else {
+            return;
+        };
+        if self.last_saved == Some(state) {
+            return;
+        }
+
+        match write_state(&self.state_path, &state).await {
+            Ok(()) => {
+                self.last_saved = Some(state);
+                self.last_save_time = Instant::now();
+            }
+            Err(e) => error!("Unable to save follow state to {:?}: {e}", self.state_path),
+        }
+    }
+
+    /// Report lines whose batch failed to upload. They aren't read again; the failed batch's temp file holds them.
+    fn log_gap(&self, start: FollowState, end: FollowState) {
+        let path = self.path.as_path();
+        let (start, end) = (start.offset, end.offset);
+        warn!(
+            event = "follow_gap", path:? = path, start, end;
+            "Lines from {path:?} between offsets {start} and {end} weren't uploaded; they are left in the failed batch's \
+             temp file"
+        );
+    }
+}
+
+async fn write_state(state_path: &Path, state: &FollowState) -> Result<(), IOError> {
+    let mut temp_path = state_path.as_os_str().to_os_string();
+    temp_path.push(".tmp");
+    write(&temp_path, serde_json::to_vec(state)?).await?;
+    rename(&temp_path, state_path).await
+}
+
+/// Return the default location of the state file for following `input`: a file in the temporary directory named after
+/// the input path.
+pub(crate) fn default_state_path(temp_dir: &Path, input: &str) -> PathBuf {
+    let absolute = std::path::absolute(input).unwrap_or_else(|_| PathBuf::from(input));
+    let name: String = absolute
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    temp_dir.join(format!("stream-logs-to-s3{name}.offset"))
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::FileFollower,
+        crate::async_utils::{Ack, AckedChunk},
+        std::{io::Write, time::Duration},
+        tokio::{
+            sync::mpsc::{Receiver, channel},
+            time::timeout,
+        },
+    };
+
+    /// Receive the next chunk, reporting whether its batch was uploaded, or that it was lost if `uploaded` is `None`.
+    async fn recv_string(rx: &mut Receiver<AckedChunk>, uploaded: Option<bool>) -> String {
+        let chunk = timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        let Ack::Uploaded(ack) = chunk.ack else {
+            panic!("Followed lines should be acknowledged once uploaded");
+        };
+        if let Some(uploaded) = uploaded {
+            ack.send(uploaded).unwrap();
+        }
+        String::from_utf8(chunk.data).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_follow_rotation_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("app.log");
+        let state_path = dir.path().join("app.offset");
+        std::fs::write(&log_path, "one\ntwo\npart").unwrap();
+
+        let (tx, mut rx) = channel(16);
+        let follower = tokio::spawn(FileFollower::new(log_path.clone(), state_path.clone(), tx).run());
+        assert_eq!(recv_string(&mut rx, Some(true)).await, "one\ntwo\n");
+
+        // Finish the partial line, then rotate by renaming and creating a new file.
+        let mut f = std::fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+        f.write_all(b"ial\n").unwrap();
+        assert_eq!(recv_string(&mut rx, Some(true)).await, "partial\n");
+        std::fs::rename(&log_path, dir.path().join("app.log.1")).unwrap();
+        std::fs::write(&log_path, "three\n").unwrap();
+        assert_eq!(recv_string(&mut rx, Some(true)).await, "three\n");
+
+        drop(rx);
+        follower.await.unwrap();
+
+        // Restarting resumes after the last uploaded line. This time, the line is lost before it reaches a batch.
+        let mut f = std::fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+        f.write_all(b"four\n").unwrap();
+        let (tx, mut rx) = channel(16);
+        let follower = tokio::spawn(FileFollower::new(log_path.clone(), state_path.clone(), tx).run());
+        assert_eq!(recv_string(&mut rx, None).await, "four\n");
+        drop(rx);
+        follower.await.unwrap();
+
+        // Rotate while stopped. The lost line is read again from the old file before the new one.
+        std::fs::rename(&log_path, dir.path().join("app.log.2")).unwrap();
+        std::fs::write(&log_path, "five\n").unwrap();
+        let (tx, mut rx) = channel(16);
+        let follower = tokio::spawn(FileFollower::new(log_path.clone(), state_path.clone(), tx).run());
+        assert_eq!(recv_string(&mut rx, Some(true)).await, "four\n");
+        assert_eq!(recv_string(&mut rx, Some(true)).await, "five\n");
+        drop(rx);
+        follower.abort();
+    }
+
+    #[tokio::test]
+    async fn test_follow_advances_past_failed_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("app.log");
+        let state_path = dir.path().join("app.offset");
+        std::fs::write(&log_path, "one\n").unwrap();
+
+        // The first batch fails, but the offset still advances once a later batch is uploaded.
+        let (tx, mut rx) = channel(16);
+        let follower = tokio::spawn(FileFollower::new(log_path.clone(), state_path.clone(), tx).run());
+        assert_eq!(recv_string(&mut rx, Some(false)).await, "one\n");
+        let mut f = std::fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+        f.write_all(b"two\n").unwrap();
+        assert_eq!(recv_string(&mut rx, Some(true)).await, "two\n");
+        drop(rx);
+        follower.await.unwrap();
+
+        // Restarting doesn't read either line again.
+        f.write_all(b"three\n").unwrap();
+        let (tx, mut rx) = channel(16);
+        let follower = tokio::spawn(FileFollower::new(log_path.clone(), state_path.clone(), tx).run());
+        assert_eq!(recv_string(&mut rx, Some(true)).await, "three\n");
+        drop(rx);
+        follower.abort();
+    }
+}
@@ -0,0 +1,289 @@
+use {
+    crate::{
+        async_utils::{Ack, AckedChunk},
+        config::{default_http_max_body, deserialize_size},
+    },
+    bytes::Bytes,
+    flate2::read::GzDecoder,
+    http_body_util::{BodyExt, Full, LengthLimitError, Limited},
+    hyper::{
+        Method, Request, Response, StatusCode,
+        body::Incoming,
+        header::{ALLOW, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HeaderValue},
+        server::conn::http1,
+        service::service_fn,
+    },
+    hyper_util::rt::TokioIo,
+    log::{debug, error, info},
+    serde::Deserialize,
+    std::{convert::Infallible, io::Read, net::SocketAddr, sync::Arc},
+    tokio::{
+        net::TcpListener,
+        sync::{mpsc::Sender, oneshot},
+        task::spawn_blocking,
+    },
+};
+
+/// The path clients POST log lines to.
+const INGEST_PATH: &str = "/ingest";
+
+/// The path for health checks.
+const HEALTHZ_PATH: &str = "/healthz";
+
+/// Settings for the HTTP ingest server.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HttpIngestOptions {
+    pub addr: SocketAddr,
+    #[serde(rename = "max_body", default = "default_http_max_body", deserialize_with = "deserialize_size")]
+    pub max_body_size: u64,
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+}
+
+/// Accept `POST /ingest` requests until the receiving end of the channel is closed.
+///
+/// Each request body is newline-delimited text, optionally gzip-encoded. The response is sent only after the batching
+/// loop has written the body to the spool file and synced it to disk, so a client that sees a 2xx can discard its
+/// copy.
+pub(crate) async fn serve(listener: TcpListener, options: HttpIngestOptions, sender: Sender<AckedChunk>) {
+    let options = Arc::new(options);
+    info!("Listening for HTTP ingest on http://{}{INGEST_PATH}", options.addr);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = sender.closed() => return,
+            result = listener.accept() => match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept HTTP connection: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let options = options.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| handle(req, options.clone(), sender.clone()));
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                debug!("HTTP connection from {peer} ended with error: {e}");
+            }
+        });
+    }
+}
+
+/// Route a request.
+async fn handle(
+    req: Request<Incoming>,
+    options: Arc<HttpIngestOptions>,
+    sender: Sender<AckedChunk>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET | &Method::HEAD, HEALTHZ_PATH) => {
+            if sender.is_closed() {
+                text_response(StatusCode::SERVICE_UNAVAILABLE, "shutting down\n")
+            } else {
+                text_response(StatusCode::OK, "ok\n")
+            }
+        }
+        (_, HEALTHZ_PATH) => method_not_allowed("GET, HEAD"),
+        (&Method::POST, INGEST_PATH) => ingest(req, &options, &sender).await,
+        (_, INGEST_PATH) => method_not_allowed("POST"),
+        _ => text_response(StatusCode::NOT_FOUND, "not found\n"),
+    };
+
+    Ok(response)
+}
+
+/// Handle `POST /ingest`.
+async fn ingest(
+    req: Request<Incoming>,
+    options: &HttpIngestOptions,
+    sender: &Sender<AckedChunk>,
+) -> Response<Full<Bytes>> {
+    if let Some(token) = &options.bearer_token {
+        let authorized = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()));
+        if !authorized {
+            let mut response = text_response(StatusCode::UNAUTHORIZED, "unauthorized\n");
+            response.headers_mut().insert("www-authenticate", HeaderValue::from_static("Bearer"));
+            return response;
+        }
+    }
+
+    let gzip = match req.headers().get(CONTENT_ENCODING).map(|v| v.to_str().unwrap_or("").trim().to_ascii_lowercase()) {
+        None => false,
+        Some(encoding) if encoding == "identity" => false,
+        Some(encoding) if encoding == "gzip" || encoding == "x-gzip" => true,
+        Some(_) => return text_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported content-encoding\n"),
+    };
+
+    // Reject oversized bodies up front when the client tells us the length.
+    let declared_length = req.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if declared_length.is_some_and(|len| len > options.max_body_size) {
+        return text_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large\n");
+    }
+
+    let body = match Limited::new(req.into_body(), options.max_body_size as usize).collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => {
+            return text_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large\n");
+        }
+        Err(e) => {
+            debug!("Failed to read HTTP request body: {e}");
+            return text_response(StatusCode::BAD_REQUEST, "unable to read request body\n");
+        }
+    };
+
+    let mut data = if gzip {
+        // Apply the same limit to the decompressed size so a small body can't expand without bound. Decompressing
+        // can take a while, so it's kept off the runtime's thread.
+        let limit = options.max_body_size;
+        let decoded = spawn_blocking(move || {
+            let mut decoded = Vec::new();
+            GzDecoder::new(&body[..]).take(limit + 1).read_to_end(&mut decoded).map(|_| decoded)
+        });
+        match decoded.await {
+            Ok(Ok(decoded)) if decoded.len() as u64 > limit => {
+                return text_response(StatusCode::PAYLOAD_TOO_LARGE, "decompressed body too large\n");
+            }
+            Ok(Ok(decoded)) => decoded,
+            Ok(Err(e)) => {
+                debug!("Invalid gzip request body: {e}");
+                return text_response(StatusCode::BAD_REQUEST, "invalid gzip body\n");
+            }
+            Err(e) => {
+                error!("Unable to decompress HTTP request body: {e}");
+                return text_response(StatusCode::INTERNAL_SERVER_ERROR, "unable to decompress body\n");
+            }
+        }
+    } else {
+        body.to_vec()
+    };
+
+    if data.is_empty() {
+        return empty_response(StatusCode::NO_CONTENT);
+    }
+
+    // Keep the last line of this request from running into the first line of the next one.
+    if data.last() != Some(&b'\n') {
+        data.push(b'\n');
+    }
+
+    let (ack, acked) = oneshot::channel();
+    if sender
+        .send(AckedChunk {
+            data,
+            ack: Ack::Synced(ack),
+        })
+        .await
+        .is_err()
+    {
+        return text_response(StatusCode::SERVICE_UNAVAILABLE, "shutting down\n");
+    }
+
+    match acked.await {
+        Ok(()) => empty_response(StatusCode::NO_CONTENT),
+        Err(_) => text_response(StatusCode::SERVICE_UNAVAILABLE, "shutting down\n"),
+    }
+}
+
+fn empty_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
+fn text_response(status: StatusCode, body: &'static str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from_static(body.as_bytes())));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    response
+}
+
+fn method_not_allowed(allow: &'static str) -> Response<Full<Bytes>> {
+    let mut response = text_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n");
+    response.headers_mut().insert(ALLOW, HeaderValue::from_static(allow));
+    response
+}
+
+/// Compare two byte strings without revealing where they first differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{HttpIngestOptions, serve},
+        crate::async_utils::ChannelReader,
+        flate2::{Compression, write::GzEncoder},
+        std::{
+            io::Write,
+            sync::{Arc, Mutex},
+        },
+        tokio::{io::AsyncReadExt, net::TcpListener, sync::mpsc::channel},
+    };
+
+    #[tokio::test]
+    async fn test_ingest() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = HttpIngestOptions {
+            addr,
+            max_body_size: 64,
+            bearer_token: Some("secret".to_string()),
+        };
+        let (tx, rx) = channel(16);
+        tokio::spawn(serve(listener, options, tx));
+
+        // Stand in for the batching loop, which acknowledges requests once their bodies are synced to disk.
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let consumer_received = received.clone();
+        tokio::spawn(async move {
+            let mut reader = ChannelReader::new(rx);
+            let acks = reader.acks();
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = reader.read(&mut buf).await.unwrap();
+                consumer_received.lock().unwrap().extend_from_slice(&buf[..n]);
+                for ack in acks.take_synced() {
+                    ack.send(()).unwrap();
+                }
+            }
+        });
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{addr}/ingest");
+        let healthz = client.get(format!("http://{addr}/healthz")).send().await.unwrap();
+        assert_eq!(healthz.status(), 200);
+
+        let response = client.post(&url).body("no token\n").send().await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = client.post(&url).bearer_auth("secret").body("x".repeat(65)).send().await.unwrap();
+        assert_eq!(response.status(), 413);
+
+        let response = client.post(&url).bearer_auth("secret").body("plain").send().await.unwrap();
+        assert_eq!(response.status(), 204);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"gzipped\n").unwrap();
+        let response = client
+            .post(&url)
+            .bearer_auth("secret")
+            .header("content-encoding", "gzip")
+            .body(encoder.finish().unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+
+        assert_eq!(*received.lock().unwrap(), b"plain\ngzipped\n");
+    }
+}
@@ -0,0 +1,371 @@
+use {
+    crate::{error::S3RequestError, parse_s3_url},
+    anyhow::Result as AnyResult,
+    aws_sdk_s3::{
+        Client,
+        primitives::ByteStream,
+        types::{ObjectCannedAcl, ServerSideEncryption},
+    },
+    humantime::format_rfc3339_millis,
+    log::{debug, error, info},
+    serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+    std::{
+        collections::BTreeMap,
+        io::Error as IOError,
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    },
+    tokio::{
+        fs::{File, OpenOptions},
+        io::{AsyncReadExt, AsyncWriteExt},
+        select,
+        sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        task::JoinHandle,
+        time::{Instant, sleep_until},
+    },
+};
+
+/// How many records to collect before writing them to S3.
+const S3_FLUSH_RECORDS: usize = 100;
+
+/// The longest a record waits to be written to S3. A failed write is retried after this long, too.
+const S3_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where to record completed uploads.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LedgerOptions {
+    /// A local file to append one JSON line per upload to.
+    pub file: Option<PathBuf>,
+
+    /// An S3 URL prefix, `s3://bucket/prefix/`. Records are collected and written to a new object under it, in a
+    /// directory per day and host, every [`S3_FLUSH_RECORDS`] records or [`S3_FLUSH_INTERVAL`].
+    pub s3: Option<String>,
+
+    /// The canned ACL for ledger objects, if any.
+    #[serde(default)]
+    pub acl: Option<String>,
+
+    /// The account id that must own the ledger bucket, if any.
+    #[serde(default)]
+    pub expected_bucket_owner: Option<String>,
+
+    /// The server-side encryption for ledger objects (AES256 if not given), and the KMS key for `aws:kms`.
+    #[serde(default)]
+    pub server_side_encryption: Option<String>,
+    #[serde(default)]
+    pub sse_kms_key_id: Option<String>,
+}
+
+/// What was written to a batch, gathered as it is written.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct BatchStats {
+    pub raw_bytes: u64,
+    pub lines: u64,
+    pub first_byte_at: Option<SystemTime>,
+    pub last_byte_at: Option<SystemTime>,
+}
+
+impl BatchStats {
+    pub fn written(&mut self, data: &[u8]) {
+        let now = SystemTime::now();
+        self.raw_bytes += data.len() as u64;
+        self.lines += data.iter().filter(|&&b| b == b'\n').count() as u64;
+        self.first_byte_at.get_or_insert(now);
+        self.last_byte_at = Some(now);
+    }
+}
+
+/// The record of one uploaded batch. Timestamps are RFC 3339 in UTC.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct LedgerRecord {
+    pub pipeline: String,
+    pub host_id: String,
+    pub bucket: String,
+    pub key: String,
+    pub etag: Option<String>,
+    pub version_id: Option<String>,
+
+    /// The size of the data as received.
+    pub raw_bytes: u64,
+
+    /// The size of the object as stored, if it was compressed.
+    pub compressed_bytes: Option<u64>,
+    pub lines: u64,
+
+    /// The SHA-256 of the object as stored, in hex.
+    pub sha256: String,
+    pub first_byte_at: Option<String>,
+    pub last_byte_at: Option<String>,
+    pub uploaded_at: String,
+}
+
+impl LedgerRecord {
+    /// Format a timestamp for a record.
+    pub fn timestamp(time: SystemTime) -> String {
+        format_rfc3339_millis(time).to_string()
+    }
+}
+
+/// Sends records to the ledger writer. Cloned into every pipeline's settings.
+#[derive(Clone, Debug)]
+pub(crate) struct Ledger {
+    records: UnboundedSender<LedgerRecord>,
+}
+
+impl Ledger {
+    pub fn record(&self, record: LedgerRecord) {
+        if let Err(e) = self.records.send(record) {
+            error!("Ledger writer has exited; no record kept for s3://{}/{}", e.0.bucket, e.0.key);
+        }
+    }
+}
+
+/// Start writing ledger records. `s3` must be a client for the ledger bucket's region if `options.s3` is set. The
+/// returned task finishes once every `Ledger` handle has been dropped and the remaining records have been written.
+pub(crate) async fn start(
+    options: &LedgerOptions,
+    host_id: &str,
+    s3: Option<Client>,
+) -> AnyResult<(Ledger, JoinHandle<()>)> {
+    let file = match &options.file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path).await?;
+            info!("Recording uploads in {path:?}");
+            Some((path.clone(), file))
+        }
+        None => None,
+    };
+
+    let s3 = match (&options.s3, s3) {
+        (Some(url), Some(client)) => {
+            let (bucket, mut prefix) = parse_s3_url(url)?;
+            if !prefix.ends_with('/') {
+                prefix.push('/');
+            }
+            info!("Recording uploads under s3://{bucket}/{prefix}");
+            Some(S3Ledger {
+                client,
+                bucket,
+                prefix,
+                host_id: host_id.to_string(),
+                acl: options.acl.as_deref().map(ObjectCannedAcl::from),
+                expected_bucket_owner: options.expected_bucket_owner.clone(),
+                server_side_encryption: options
+                    .server_side_encryption
+                    .as_deref()
+                    .map_or(ServerSideEncryption::Aes256, ServerSideEncryption::from),
+                sse_kms_key_id: options.sse_kms_key_id.clone(),
+                pending: BTreeMap::new(),
+                pending_records: 0,
+                oldest: None,
+            })
+        }
+        _ => None,
+    };
+
+    let (records, receiver) = unbounded_channel();
+    let writer = tokio::spawn(write_records(receiver, file, s3));
+    Ok((
+        Ledger {
+            records,
+        },
+        writer,
+    ))
+}
+
+/// The ledger writer task. Records are written in the order uploads finished.
+async fn write_records(
+    mut receiver: UnboundedReceiver<LedgerRecord>,
+    mut file: Option<(PathBuf, File)>,
+    mut s3: Option<S3Ledger>,
+) {
+    loop {
+        let deadline = s3.as_ref().and_then(|s3| s3.oldest).map(|oldest| oldest + S3_FLUSH_INTERVAL);
+        let record = select! {
+            record = receiver.recv() => match record {
+                Some(record) => record,
+                None => break,
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                if let Some(s3) = &mut s3 {
+                    s3.flush().await;
+                }
+                continue;
+            }
+        };
+
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Unable to serialize ledger record for s3://{}/{}: {e}", record.bucket, record.key);
+                continue;
+            }
+        };
+        line.push(b'\n');
+
+        if let Some((path, file)) = &mut file
+            && let Err(e) = append(file, &line).await
+        {
+            error!(event = "ledger_write_failed", path:? = path; "Unable to write ledger record to {path:?}: {e}");
+        }
+
+        if let Some(s3) = &mut s3 {
+            s3.append(&record.uploaded_at, &line);
+            if s3.pending_records >= S3_FLUSH_RECORDS {
+                s3.flush().await;
+            }
+        }
+    }
+
+    // Write whatever hasn't reached S3 yet.
+    if let Some(s3) = &mut s3 {
+        s3.flush().await;
+    }
+}
+
+/// Append a line to the local ledger and make sure it is on disk before moving on.
+async fn append(file: &mut File, line: &[u8]) -> Result<(), IOError> {
+    file.write_all(line).await?;
+    file.sync_data().await
+}
+
+/// The S3 ledger for this host. S3 objects can't be appended to, so records are collected in memory and each flush
+/// writes them to a new object, `<prefix><YYYY-MM-DD>/<host_id>/<first uploaded_at>-<hash>.jsonl`, for each day they
+/// were uploaded on. Records whose write fails are kept and written with the next flush.
+struct S3Ledger {
+    client: Client,
+    bucket: String,
+    prefix: String,
+    host_id: String,
+    acl: Option<ObjectCannedAcl>,
+    expected_bucket_owner: Option<String>,
+    server_side_encryption: ServerSideEncryption,
+    sse_kms_key_id: Option<String>,
+
+    /// The records not yet written to S3, by the day (`YYYY-MM-DD`) they were uploaded on.
+    pending: BTreeMap<String, Vec<u8>>,
+    pending_records: usize,
+
+    /// When the oldest record in `pending` was added, or when its last write failed.
+    oldest: Option<Instant>,
+}
+
+impl S3Ledger {
+    fn append(&mut self, uploaded_at: &str, line: &[u8]) {
+        // The date is the start of the RFC 3339 timestamp.
+        let day = uploaded_at.get(..10).unwrap_or(uploaded_at);
+        self.pending.entry(day.to_string()).or_default().extend_from_slice(line);
+        self.pending_records += 1;
+        self.oldest.get_or_insert_with(Instant::now);
+    }
+
+    /// Write the pending records to S3, one object per day.
+    async fn flush(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        for (day, contents) in pending {
+            // Name the object after its first record, which no earlier object can start with, and its contents.
+            let first: serde_json::Value = contents
+                .split(|&b| b == b'\n')
+                .next()
+                .and_then(|line| serde_json::from_slice(line).ok())
+                .unwrap_or_default();
+            let first_uploaded_at: String =
+                first["uploaded_at"].as_str().unwrap_or(&day).chars().filter(char::is_ascii_alphanumeric).collect();
+            let hash = format!("{:x}", Sha256::digest(&contents));
+            let key = format!("{}{day}/{}/{first_uploaded_at}-{}.jsonl", self.prefix, self.host_id, &hash[..16]);
+
+            let result = self
+                .client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .content_type("application/x-ndjson")
+                .server_side_encryption(self.server_side_encryption.clone())
+                .set_ssekms_key_id(self.sse_kms_key_id.clone())
+                .set_acl(self.acl.clone())
+                .set_expected_bucket_owner(self.expected_bucket_owner.clone())
+                .body(ByteStream::from(contents.clone()))
+                .send()
+                .await;
+
+            match result {
+                Ok(_) => debug!("Wrote ledger s3://{}/{key}", self.bucket),
+                Err(e) => {
+                    let e = S3RequestError::new(e);
+                    error!(
+                        event = "ledger_write_failed", bucket = self.bucket.as_str(), key = key.as_str(),
+                        error_class = e.class.as_str();
+                        "Unable to write ledger s3://{}/{key}: {e}", self.bucket
+                    );
+                    self.pending.insert(day, contents);
+                }
+            }
+        }
+
+        self.pending_records = 0;
+        self.oldest = (!self.pending.is_empty()).then(Instant::now);
+    }
+}
+
+/// Hash a file from its current position to the end, returning the SHA-256 in hex.
+pub(crate) async fn sha256_file(file: &mut File) -> Result<String, IOError> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 65536];
+    loop {
+        let n_read = file.read(&mut buf).await?;
+        if n_read == 0 {
+            break;
+        }
+        hasher.update(&buf[..n_read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{BatchStats, LedgerRecord, sha256_file},
+        std::io::Write,
+        tempfile::NamedTempFile,
+        tokio::fs::File,
+    };
+
+    #[tokio::test]
+    async fn test_ledger_record() {
+        let mut stats = BatchStats::default();
+        stats.written(b"one\ntwo\n");
+        stats.written(b"three\n");
+        assert_eq!(stats.raw_bytes, 14);
+        assert_eq!(stats.lines, 3);
+        assert!(stats.first_byte_at.unwrap() <= stats.last_byte_at.unwrap());
+
+        let mut temp = NamedTempFile::new().unwrap();
+        temp.write_all(b"abc").unwrap();
+        let mut file = File::open(temp.path()).await.unwrap();
+        let sha256 = sha256_file(&mut file).await.unwrap();
+        assert_eq!(sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let record = LedgerRecord {
+            pipeline: "web".to_string(),
+            host_id: "i-0123".to_string(),
+            bucket: "logs".to_string(),
+            key: "web/1.log".to_string(),
+            etag: Some("d41d8cd98f00b204e9800998ecf8427e".to_string()),
+            version_id: None,
+            raw_bytes: stats.raw_bytes,
+            compressed_bytes: None,
+            lines: stats.lines,
+            sha256,
+            first_byte_at: stats.first_byte_at.map(LedgerRecord::timestamp),
+            last_byte_at: stats.last_byte_at.map(LedgerRecord::timestamp),
+            uploaded_at: "2026-10-18T12:00:00.000Z".to_string(),
+        };
+        let json: serde_json::Value = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["key"], "web/1.log");
+        assert_eq!(json["raw_bytes"], 14);
+        assert_eq!(json["compressed_bytes"], serde_json::Value::Null);
+        assert!(json["first_byte_at"].as_str().unwrap().ends_with('Z'));
+    }
+}
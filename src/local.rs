@@ -0,0 +1,140 @@
+use {
+    crate::{MAX_KEY_RENAMES, disambiguate_key, error::VerificationFailed},
+    anyhow::{Result as AnyResult, anyhow},
+    log::{debug, warn},
+    std::{
+        fs::{File, hard_link},
+        io::{Error as IOError, ErrorKind, copy},
+        path::{Component, Path},
+    },
+    tempfile::{Builder as TempFileBuilder, TempPath},
+    tokio::{fs::create_dir_all, task::spawn_blocking},
+};
+
+/// Write a finished batch, `source`, to `target`, an absolute path, creating its directory if needed. The batch is
+/// linked (or, if the temp dir is on another filesystem, copied) next to `target` and renamed into place, so nothing
+/// ever sees a partial file; `source` is left for the caller. With `no_overwrite`, an existing file is never replaced:
+/// `-1`, `-2`, ... is added to the file name as for S3. Returns the path the batch was written to.
+pub(crate) async fn write_batch(source: &Path, target: &str, no_overwrite: bool) -> AnyResult<String> {
+    // Template variables can come from the records themselves; they mustn't take us outside the template's tree.
+    let path = Path::new(target);
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(anyhow!("Refusing to write to {target:?}: the path must be absolute, without .. components"));
+    }
+
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).await?;
+    }
+
+    let (source, target) = (source.to_path_buf(), target.to_string());
+    Ok(spawn_blocking(move || persist(&source, target, no_overwrite)).await??)
+}
+
+fn persist(source: &Path, target: String, no_overwrite: bool) -> Result<String, IOError> {
+    let mut batch = link_beside(source, Path::new(&target))?;
+    let mut path = target.clone();
+    let mut renames = 0;
+
+    loop {
+        let result = if no_overwrite {
+            batch.persist_noclobber(&path)
+        } else {
+            batch.persist(&path)
+        };
+
+        match result {
+            Ok(()) => {
+                debug!("Renamed batch into place at {path:?}");
+                return Ok(path);
+            }
+
+            Err(e) if no_overwrite && e.error.kind() == ErrorKind::AlreadyExists && renames < MAX_KEY_RENAMES => {
+                renames += 1;
+                let new_path = disambiguate_key(&target, renames);
+                warn!(
+                    event = "object_exists", bucket = "", key = path.as_str(), new_key = new_path.as_str();
+                    "{path} already exists; writing to {new_path} instead"
+                );
+                path = new_path;
+                batch = e.path;
+            }
+
+            Err(e) => return Err(e.error),
+        }
+    }
+}
+
+/// Link a file to a temporary name in the directory of `target`. Links don't cross filesystems, so if that fails, the
+/// file is copied there instead and synced to disk.
+fn link_beside(source: &Path, target: &Path) -> Result<TempPath, IOError> {
+    let dir = target.parent().unwrap_or(Path::new("/"));
+    match TempFileBuilder::new().prefix(".").make_in(dir, |path| hard_link(source, path)) {
+        Ok(linked) => return Ok(linked.into_temp_path()),
+        Err(e) => debug!("Unable to link {source:?} into {dir:?}; copying it: {e}"),
+    }
+
+    let mut copy_file = TempFileBuilder::new().prefix(".").tempfile_in(dir)?;
+    copy(&mut File::open(source)?, copy_file.as_file_mut())?;
+    copy_file.as_file().sync_all()?;
+    Ok(copy_file.into_temp_path())
+}
+
+/// Check that the batch written to `path` is `size` bytes.
+pub(crate) async fn verify_batch(path: &str, size: u64) -> AnyResult<()> {
+    let actual = tokio::fs::metadata(path).await?.len();
+    if actual != size {
+        return Err(VerificationFailed {
+            what: "size",
+            expected: size.to_string(),
+            actual: actual.to_string(),
+        }
+        .into());
+    }
+
+    debug!("Verified {path:?}: {size} bytes");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{verify_batch, write_batch},
+        std::fs::{read, write},
+        tempfile::{NamedTempFile, tempdir},
+    };
+
+    #[tokio::test]
+    async fn test_write_batch() {
+        let dir = tempdir().unwrap();
+        let batch = |data: &[u8]| {
+            let file = NamedTempFile::new_in(dir.path()).unwrap();
+            write(file.path(), data).unwrap();
+            file.into_temp_path()
+        };
+        let source = batch(b"one");
+
+        // Directories are created as needed.
+        let target = dir.path().join("2026/10/web1.log").to_str().unwrap().to_string();
+        assert_eq!(write_batch(&source, &target, true).await.unwrap(), target);
+        assert_eq!(read(&target).unwrap(), b"one");
+
+        // The batch is left for the caller, who may be writing it to other destinations.
+        assert_eq!(read(&source).unwrap(), b"one");
+        verify_batch(&target, 3).await.unwrap();
+        assert!(verify_batch(&target, 4).await.is_err());
+
+        // An existing file is kept with no_overwrite, and replaced without it.
+        let renamed = write_batch(&batch(b"two"), &target, true).await.unwrap();
+        assert!(renamed.ends_with("2026/10/web1-1.log"));
+        assert_eq!(read(&target).unwrap(), b"one");
+        assert_eq!(read(&renamed).unwrap(), b"two");
+
+        assert_eq!(write_batch(&batch(b"three"), &target, false).await.unwrap(), target);
+        assert_eq!(read(&target).unwrap(), b"three");
+
+        // Variables can't lead outside the template's tree.
+        let escaping = dir.path().join("a/../../b.log").to_str().unwrap().to_string();
+        assert!(write_batch(&batch(b"four"), &escaping, false).await.is_err());
+        assert!(write_batch(&batch(b"four"), "relative.log", false).await.is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use {
+    clap::ValueEnum,
+    env_logger::{Builder, fmt::Formatter},
+    log::{
+        Record,
+        kv::{Error as KvError, Key, Value, VisitSource, VisitValue},
+    },
+    serde_json::{Map, Value as JsonValue},
+    std::{io::Write, time::SystemTime},
+};
+
+/// How our own log messages are written to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub(crate) enum LogFormat {
+    /// env_logger's human-readable format.
+    #[default]
+    Text,
+
+    /// One JSON object per line, with the event name and fields as keys.
+    Json,
+}
+
+/// Set up logging. Levels are controlled by `RUST_LOG` as usual.
+pub(crate) fn init(format: LogFormat) {
+    let mut builder = Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(write_json);
+    }
+    builder.init();
+}
+
+/// Write a record as a JSON object: `timestamp`, `level`, `target` and `message`, plus the record's key-values (such
+/// as `event`, `bucket` and `key`).
+fn write_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    writeln!(buf, "{}", to_json(record))
+}
+
+fn to_json(record: &Record) -> JsonValue {
+    let mut object = Map::new();
+    object.insert("timestamp".to_string(), humantime::format_rfc3339_micros(SystemTime::now()).to_string().into());
+    object.insert("level".to_string(), record.level().as_str().to_ascii_lowercase().into());
+    object.insert("target".to_string(), record.target().into());
+    object.insert("message".to_string(), record.args().to_string().into());
+
+    // Visiting can only fail if a visitor returns an error, and ours don't.
+    let _ = record.key_values().visit(&mut JsonFields(&mut object));
+    JsonValue::Object(object)
+}
+
+/// Collects key-values into a JSON object.
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+        let mut json = JsonValue::Null;
+        value.visit(JsonField(&mut json))?;
+        self.0.insert(key.as_str().to_string(), json);
+        Ok(())
+    }
+}
+
+/// Converts a single value to JSON, keeping numbers and booleans typed.
+struct JsonField<'a>(&'a mut JsonValue);
+
+impl<'v> VisitValue<'v> for JsonField<'_> {
+    fn visit_any(&mut self, value: Value) -> Result<(), KvError> {
+        *self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), KvError> {
+        *self.0 = JsonValue::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), KvError> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), KvError> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), KvError> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), KvError> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), KvError> {
+        *self.0 = value.into();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::to_json,
+        log::{Level, Record},
+    };
+
+    #[test]
+    fn test_to_json() {
+        let kvs: &[(&str, log::kv::Value)] = &[
+            ("event", "upload_succeeded".into()),
+            ("bucket", "logs".into()),
+            ("bytes", 1234u64.into()),
+            ("duration_ms", 12.5f64.into()),
+        ];
+        let json = to_json(
+            &Record::builder()
+                .level(Level::Info)
+                .target("stream_logs_to_s3")
+                .args(format_args!("Uploaded"))
+                .key_values(&kvs)
+                .build(),
+        );
+
+        assert_eq!(json["level"], "info");
+        assert_eq!(json["message"], "Uploaded");
+        assert_eq!(json["event"], "upload_succeeded");
+        assert_eq!(json["bucket"], "logs");
+        assert_eq!(json["bytes"], 1234);
+        assert_eq!(json["duration_ms"], 12.5);
+        assert!(json["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+}
@@ -1,41 +1,104 @@
 #![warn(clippy::all)]
 
 mod async_utils;
+mod azure;
+mod checksum;
+mod cleanup;
+mod config;
 mod ec2;
 mod ecs;
 mod error;
+mod fallback;
+mod follow;
+mod gcs;
+mod http_ingest;
+mod ledger;
+mod local;
+mod logging;
+mod manifest;
+mod metrics;
+mod spool;
+mod statsd;
+mod syslog;
+#[cfg(unix)]
+mod unix_socket;
+mod wrapper;
 
 use {
     crate::{
-        async_utils::{MaybeCompressedFile, MaybeTimeout, TaskQueue},
-        error::InvalidS3URL,
+        async_utils::{ChannelReader, ChecksumFile, MaybeCompressedFile, MaybeTimeout, PendingAcks, TaskQueue},
+        azure::{AZURE_PROTO_PREFIX, AzureOptions, AzureTarget},
+        checksum::{ChecksumAlgorithm, ObjectChecksums, PartChecksums, returned_checksum, with_checksum},
+        config::{AssumeRoleOptions, Config, ConfigDefaults, DestinationConfig, InputConfig, PipelineConfig},
+        error::{ConfigError, InvalidS3URL, S3RequestError, VerificationFailed},
+        fallback::{DEFAULT_FALLBACK_AFTER, DEFAULT_FALLBACK_RETRY, FALLBACK_KEY_MARKER, Fallback},
+        follow::{FileFollower, default_state_path},
+        gcs::{GCS_PROTO_PREFIX, GcsOptions, GcsTarget},
+        http_ingest::HttpIngestOptions,
+        ledger::{BatchStats, Ledger, LedgerOptions, LedgerRecord},
+        logging::LogFormat,
+        manifest::{ManifestObject, ManifestWindow, Manifests},
+        metrics::{LoopMetrics, RetryCounter, UploadMetrics},
+        spool::{MultipartState, SavedPart, SpoolLock},
+        statsd::{StatsdFormat, StatsdOptions},
+        syslog::{DEFAULT_MAX_PARTITIONS, SyslogOptions, overflow_vars},
     },
     anyhow::{Result as AnyResult, bail},
     async_compression::{Level, tokio::write::GzipEncoder},
-    aws_config::Region,
-    aws_sdk_s3::types::{BucketLocationConstraint, CompletedMultipartUpload, CompletedPart, ServerSideEncryption},
+    aws_config::{Region, SdkConfig, sts::AssumeRoleProvider},
+    aws_sdk_s3::{
+        config::SharedCredentialsProvider,
+        types::{
+            BucketLocationConstraint, ChecksumMode, ChecksumType, CompletedMultipartUpload, CompletedPart,
+            ObjectCannedAcl, ServerSideEncryption, StorageClass,
+        },
+    },
     aws_smithy_types::byte_stream::{FsBuilder, Length},
     byte_unit::Byte,
-    clap::Parser,
+    clap::{Parser, builder::PossibleValuesParser},
     ec2::get_host_id_from_ec2_metadata,
     ecs::get_host_id_from_ecs_metadata,
-    futures::stream::{FuturesOrdered, StreamExt},
+    futures::{
+        future::join_all,
+        stream::{FuturesOrdered, FuturesUnordered, StreamExt},
+    },
     get_if_addrs::get_if_addrs,
     gethostname::gethostname,
     humantime::parse_duration,
-    log::{debug, error, info},
+    log::{debug, error, info, warn},
     std::{
-        cmp::min, collections::HashMap, error::Error, ffi::OsString, fs::metadata, io::SeekFrom, iter::Extend,
-        net::IpAddr, path::PathBuf, process::exit, str::FromStr, time::Duration,
+        cmp::min,
+        collections::{HashMap, HashSet},
+        env::temp_dir,
+        error::Error,
+        ffi::OsString,
+        fs::metadata,
+        future::{Future, pending},
+        io::SeekFrom,
+        iter::Extend,
+        net::{IpAddr, SocketAddr},
+        path::{Path, PathBuf},
+        pin::Pin,
+        process::exit,
+        str::FromStr,
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
     },
     tempfile::{NamedTempFile, TempPath},
     time::OffsetDateTime,
     tokio::{
         self,
-        fs::File,
+        fs::{File, remove_file},
         io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, stdin},
+        net::TcpListener,
         runtime::Builder as RuntimeBuilder,
         select,
+        sync::{
+            Semaphore,
+            mpsc::{Receiver, Sender, channel},
+            oneshot, watch,
+        },
+        task::JoinHandle,
     },
 };
 
@@ -46,6 +109,7 @@ use {
         unistd::{AccessFlags, access},
     },
     std::os::unix::fs::FileTypeExt,
+    unix_socket::{UnixSocketListener, UnixSocketOptions, parse_socket_mode},
 };
 
 #[cfg(not(unix))]
@@ -61,15 +125,46 @@ const S3_MAXIMUM_SIZE: Byte = Byte::from_u64(5 << 40);
 /// Currently fixed at 10 MiB.
 const MAX_PART_SIZE: u64 = 10 << 20;
 
+/// How many times to try an upload whose data S3 reports as damaged in transit.
+const MAX_UPLOAD_ATTEMPTS: u32 = 3;
+
+/// The role session name used when assuming a role, unless another is given.
+const DEFAULT_ROLE_SESSION_NAME: &str = "stream-logs-to-s3";
+
+/// How many other keys to try when `--no-overwrite` finds an object already at the key.
+const MAX_KEY_RENAMES: u32 = 10;
+
+/// The header in which S3 reports the region a bucket is in.
+const BUCKET_REGION_HEADER: &str = "x-amz-bucket-region";
+
 /// Constant for the AWS region eu-west-1
 const REGION_EU_WEST_1: Region = Region::from_static("eu-west-1");
 
 /// Constant for the AWS region us-east-1
 const REGION_US_EAST_1: Region = Region::from_static("us-east-1");
 
+/// The prefix for ARNs, which can name an access point in place of a bucket.
+const ARN_PREFIX: &str = "arn:";
+
+/// The suffix of S3 Express One Zone directory bucket names.
+const DIRECTORY_BUCKET_SUFFIX: &str = "--x-s3";
+
+/// The prefix for local filesystem destinations.
+const FILE_PROTO_PREFIX: &str = "file://";
+
 /// The prefix for S3 URLs.
 const S3_PROTO_PREFIX: &str = "s3://";
 
+/// The number of chunks a channel-based input (followed file, socket, etc.) can have queued up before it waits on the
+/// batching loop.
+const INPUT_CHANNEL_SIZE: usize = 16;
+
+/// The exit code used when a wrapped command can't be started, matching what shells use for a missing command.
+const COMMAND_FAILED_EXIT_CODE: i32 = 127;
+
+/// The name given to the pipeline built from command line options.
+const DEFAULT_PIPELINE_NAME: &str = "default";
+
 /// How often we log size information.
 const SIZE_REPORTING_INTERVAL: u64 = 10 << 20;
 
@@ -86,6 +181,9 @@ const SIZE_REPORTING_INTERVAL: u64 = 10 << 20;
 /// * {{second}} - The current second as a 2-digit string.\n
 /// * {{unique}} - A unique identifier to ensure filename uniqueness.
 ///
+/// When running a command, {{stream}} is also available. When receiving syslog, {{facility}}, {{severity}} and
+/// {{hostname}} are also available.
+///
 /// To include a raw '{{' or '}}' in the output, double it: '{{{{' / '}}}}'.
 #[derive(Debug, Parser)]
 #[command(
@@ -104,6 +202,15 @@ The path template can include the following variables. Timestamps are generated
 * {second} - The current second as a 2-digit string.
 * {unique} - A unique identifier to ensure filename uniqueness.
 
+When running a command, {stream} is also available: stdout or stderr. Each stream is batched separately.
+
+When receiving syslog, the following variables are also available. Messages are batched separately for each distinct
+combination of the variables used in the template.
+
+* {facility} - The syslog facility name, e.g. local0.
+* {severity} - The syslog severity name, e.g. info.
+* {hostname} - The hostname in the syslog message, or - if absent.
+
 To include a raw '{' or '}' in the output, double it: '{{' / '}}'.
 "#
 )]
@@ -127,56 +234,599 @@ struct Cli {
     #[arg(short = 'i', long)]
     pub input: Option<String>,
 
+    /// Keep reading the input file after end-of-file, like `tail -F`. Rotation (by rename or truncation) is detected
+    /// and the new file is read from the start. The input must be a regular file.
+    #[arg(short = 'F', long, requires = "input")]
+    pub follow: bool,
+
+    /// Where to persist the read offset when following a file so a restart resumes without duplicating or losing
+    /// lines. Defaults to a file in the temporary directory named after the input.
+    #[arg(long, requires = "follow")]
+    pub follow_state: Option<PathBuf>,
+
+    /// Accept log data on a Unix domain socket at this path instead of reading stdin. Multiple writers can connect at
+    /// once; their records are merged without interleaving partial lines.
+    #[cfg(unix)]
+    #[arg(
+        long,
+        conflicts_with_all = ["input", "listen_syslog_udp", "listen_syslog_tcp", "listen_http", "command", "config"]
+    )]
+    pub listen_unix: Option<PathBuf>,
+
+    /// Make the `--listen-unix` socket a datagram socket (e.g. a `/dev/log` replacement). Each datagram is one record.
+    #[cfg(unix)]
+    #[arg(long, requires = "listen_unix")]
+    pub unix_datagram: bool,
+
+    /// Permissions for the `--listen-unix` socket, in octal (e.g. 0660).
+    #[cfg(unix)]
+    #[arg(long, requires = "listen_unix", value_parser = parse_socket_mode)]
+    pub socket_mode: Option<u32>,
+
+    /// Owner (user name or uid) for the `--listen-unix` socket.
+    #[cfg(unix)]
+    #[arg(long, requires = "listen_unix")]
+    pub socket_owner: Option<String>,
+
+    /// Group (group name or gid) for the `--listen-unix` socket.
+    #[cfg(unix)]
+    #[arg(long, requires = "listen_unix")]
+    pub socket_group: Option<String>,
+
+    /// Receive syslog messages (RFC 3164 or RFC 5424) on this UDP address, e.g. `0.0.0.0:514`. May be repeated.
+    #[arg(long, value_name = "ADDR", conflicts_with = "input")]
+    pub listen_syslog_udp: Vec<SocketAddr>,
+
+    /// Receive syslog messages on this TCP address. Both octet-counted and newline-framed messages are accepted. May
+    /// be repeated.
+    #[arg(long, value_name = "ADDR", conflicts_with = "input")]
+    pub listen_syslog_tcp: Vec<SocketAddr>,
+
+    /// Accept log data over HTTP on this address, e.g. `127.0.0.1:8080`. Clients POST newline-delimited bodies,
+    /// optionally gzip-encoded, to /ingest; the response is sent once the data has been written to the spool. GET
+    /// /healthz reports whether the server is up.
+    #[arg(long, value_name = "ADDR", conflicts_with_all = ["input", "listen_syslog_udp", "listen_syslog_tcp"])]
+    pub listen_http: Option<SocketAddr>,
+
+    /// Maximum size of an HTTP ingest request body, both as sent and after decompression.
+    #[arg(long, default_value = "10MiB", value_parser = Byte::from_str)]
+    pub http_max_body: Byte,
+
+    /// Require `Authorization: Bearer <token>` on HTTP ingest requests.
+    #[arg(long, env = "STREAM_LOGS_TO_S3_HTTP_TOKEN", hide_env_values = true)]
+    pub http_bearer_token: Option<String>,
+
+    /// Write syslog messages as JSON lines instead of as received.
+    #[arg(long)]
+    pub syslog_json: bool,
+
+    /// The most syslog batches to build at once, one per combination of the template's syslog variables. Messages
+    /// beyond this are batched with `other` as their {hostname}.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_PARTITIONS)]
+    pub syslog_max_partitions: usize,
+
     /// Compress output using gzip.
     #[arg(short = 'z', long)]
     pub gzip: bool,
 
-    /// The S3 URL to write to, in the format `s3://bucket/path-template`.
-    #[arg()]
-    pub destination: String,
+    /// Compute a checksum of each part as it is written to the temp file and send it with the upload. S3 rejects
+    /// damaged parts, and the checksum S3 reports for the object is checked against ours; either failure retries the
+    /// upload.
+    #[arg(long, value_enum)]
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+
+    /// After each upload, check the object's size (and checksum, with `--checksum-algorithm`) with HeadObject before
+    /// deleting the temp file. A mismatch uploads the batch again.
+    #[arg(long)]
+    pub verify_uploads: bool,
+
+    /// Assume this IAM role for every S3 request, e.g. to deliver logs to a bucket in another account. A configuration
+    /// file may override this.
+    #[arg(long, value_name = "ARN")]
+    pub role_arn: Option<String>,
+
+    /// The external id to give when assuming `--role-arn`.
+    #[arg(long, requires = "role_arn")]
+    pub external_id: Option<String>,
+
+    /// The session name to use when assuming `--role-arn`.
+    #[arg(long, default_value = DEFAULT_ROLE_SESSION_NAME, requires = "role_arn")]
+    pub role_session_name: String,
+
+    /// The Azure Blob Storage endpoint for `azblob://` destinations, with `{account}` standing for the storage account,
+    /// e.g. `http://127.0.0.1:10000/{account}` for Azurite. Defaults to `https://{account}.blob.core.windows.net`.
+    #[arg(long, value_name = "URL")]
+    pub azure_endpoint: Option<String>,
+
+    /// The storage account key for `azblob://` destinations, in base64.
+    #[arg(long, env = "AZURE_STORAGE_KEY", hide_env_values = true)]
+    pub azure_account_key: Option<String>,
+
+    /// A shared access signature for `azblob://` destinations, used if there is no account key.
+    #[arg(long, env = "AZURE_STORAGE_SAS_TOKEN", hide_env_values = true)]
+    pub azure_sas_token: Option<String>,
+
+    /// The Google Cloud Storage JSON API endpoint for `gs://` destinations, e.g. `http://127.0.0.1:4443` for
+    /// fake-gcs-server. Defaults to `https://storage.googleapis.com`.
+    #[arg(long, value_name = "URL")]
+    pub gcs_endpoint: Option<String>,
+
+    /// A service-account key file for `gs://` destinations. Without one, tokens come from the GCE metadata server.
+    #[arg(long, value_name = "FILE", env = "GOOGLE_APPLICATION_CREDENTIALS")]
+    pub gcs_credentials: Option<PathBuf>,
+
+    /// Send `gs://` requests without credentials, e.g. to fake-gcs-server.
+    #[arg(long, conflicts_with = "gcs_credentials")]
+    pub gcs_anonymous: bool,
+
+    /// The canned ACL for uploaded objects, e.g. `bucket-owner-full-control` when writing to another account's bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(ObjectCannedAcl::values()))]
+    pub acl: Option<String>,
+
+    /// The account id that must own the destination bucket. Requests fail if the bucket belongs to anyone else.
+    #[arg(long, value_name = "ACCOUNT_ID")]
+    pub expected_bucket_owner: Option<String>,
+
+    /// The region the destination bucket is in, e.g. "eu-west-1". By default, it's discovered with HeadBucket (or
+    /// GetBucketLocation if that doesn't say), which some IAM policies and S3-compatible stores don't allow. Like
+    /// `--sse`, `--sse-kms-key-id` and `--storage-class`, give this once for every S3 destination or once per
+    /// destination, in order; an empty value leaves that destination at the default.
+    #[arg(long, value_name = "REGION")]
+    pub bucket_region: Vec<String>,
+
+    /// The server-side encryption for uploaded objects; defaults to AES256.
+    #[arg(long, value_parser = PossibleValuesParser::new(ServerSideEncryption::values().iter().copied().chain([""])))]
+    pub sse: Vec<String>,
+
+    /// The KMS key for `--sse aws:kms`; defaults to the bucket's key.
+    #[arg(long, value_name = "KEY_ID")]
+    pub sse_kms_key_id: Vec<String>,
+
+    /// The storage class for uploaded objects, e.g. STANDARD_IA.
+    #[arg(long, value_parser = PossibleValuesParser::new(StorageClass::values().iter().copied().chain([""])))]
+    pub storage_class: Vec<String>,
+
+    /// With more than one destination, the number a batch must reach before its temp file is deleted; defaults to
+    /// all of them.
+    #[arg(long, value_name = "N")]
+    pub min_destinations: Option<usize>,
+
+    /// Where to send batches while the main destination refuses them (AccessDenied or NoSuchBucket). May be given
+    /// more than once; each is tried in order. Options given once apply to these too.
+    #[arg(long, value_name = "DESTINATION")]
+    pub fallback_destination: Vec<String>,
+
+    /// The number of uploads in a row the main destination must refuse before batches go to a fallback; defaults
+    /// to 3.
+    #[arg(long, value_name = "N")]
+    pub fallback_after: Option<u32>,
+
+    /// How long to use the fallbacks before trying the main destination again, e.g. "5min" (the default).
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub fallback_retry: Option<Duration>,
+
+    /// Never overwrite an existing object. Uploads are made with `If-None-Match: *`; if the key is taken (e.g. by
+    /// another host with the same host id), `-1`, `-2`, ... is added to the file name and the upload is retried.
+    #[arg(long)]
+    pub no_overwrite: bool,
+
+    /// At startup, abort this host's incomplete multipart uploads under each destination's prefix that were started
+    /// more than this long ago, e.g. "1day". These are left behind if we die partway through an upload. S3 doesn't
+    /// report an incomplete upload's tags or metadata, so this host's are found by key alone, and every S3
+    /// destination's template must use {host_id}. A configuration file may override this.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub abort_uploads_older_than: Option<Duration>,
+
+    /// Clean up incomplete multipart uploads as for `--abort-uploads-older-than` (defaulting to 1 day) and exit
+    /// without reading any input. Like it, this needs {host_id} in every S3 destination's template.
+    #[arg(long)]
+    pub cleanup_uploads: bool,
+
+    /// Read pipelines from this TOML configuration file instead of taking a single input and destination from the
+    /// command line. See the README for the format.
+    #[arg(
+        short = 'c',
+        long,
+        conflicts_with_all = ["input", "listen_syslog_udp", "listen_syslog_tcp", "listen_http", "command"]
+    )]
+    pub config: Option<PathBuf>,
+
+    /// The number of batches that may be uploading to S3 at once, across all pipelines. A configuration file may
+    /// override this.
+    #[arg(long, default_value = "4")]
+    pub max_concurrent_uploads: usize,
+
+    /// The format of our own log messages on stderr. `json` writes one object per line with a stable `event` name and
+    /// fields such as `bucket`, `key` and `bytes`.
+    #[arg(long, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Serve Prometheus metrics at /metrics on this address, e.g. `127.0.0.1:9464`. A configuration file may override
+    /// this.
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Push StatsD metrics over UDP to this address, e.g. `127.0.0.1:8125`. Metrics are dropped rather than delayed
+    /// if the agent is down. A configuration file may override this.
+    #[arg(long, value_name = "HOST:PORT")]
+    pub statsd: Option<String>,
+
+    /// The StatsD line format. Plain StatsD drops the `host_id`, `pipeline` and `bucket` tags.
+    #[arg(long, value_enum, default_value = "dogstatsd", requires = "statsd")]
+    pub statsd_format: StatsdFormat,
+
+    /// The prefix for StatsD metric names.
+    #[arg(long, default_value = statsd::DEFAULT_PREFIX, requires = "statsd")]
+    pub statsd_prefix: String,
+
+    /// Write a manifest object listing the objects uploaded in each time window, e.g.
+    /// `_manifest/{host_id}/{year}/{month}/{day}/{hour}.json`, to the destination bucket. The template must use
+    /// {host_id} and may use the time variables; the window ends when its name changes. A manifest is written once its
+    /// window has ended and all of its uploads have finished; at exit, open windows get a `.partial` manifest.
+    #[arg(long, value_name = "TEMPLATE")]
+    pub manifest: Option<String>,
+
+    /// Append a JSON record of every uploaded batch to this file. A configuration file may override this.
+    #[arg(long, value_name = "PATH")]
+    pub ledger_file: Option<PathBuf>,
+
+    /// Record every uploaded batch in JSON lines objects under this S3 prefix, in a directory per day and host, e.g.
+    /// `s3://bucket/ledger/`. The objects get `--acl`, `--expected-bucket-owner`, and the main destination's `--sse`
+    /// and `--sse-kms-key-id`. A configuration file may override this.
+    #[arg(long, value_name = "S3_URL")]
+    pub ledger_s3: Option<String>,
+
+    /// Where to write, in the format `s3://bucket/path-template`, or `file:///path-template` for the local
+    /// filesystem. Every batch is written to each destination given.
+    #[arg(value_name = "DESTINATION", required_unless_present = "config", conflicts_with = "config")]
+    pub destinations: Vec<String>,
+
+    /// A command to run instead of reading stdin, given after `--`. Its stdout and stderr are shipped as separate
+    /// batches (see `{stream}`), signals are forwarded to it, and we exit with its exit code after the final batches
+    /// have been sent.
+    #[arg(
+        last = true,
+        value_name = "COMMAND",
+        conflicts_with_all = ["input", "listen_syslog_udp", "listen_syslog_tcp", "listen_http"]
+    )]
+    pub command: Vec<OsString>,
 }
 
-/// Program entrypoint. Parse options and, if they seem reasonable, fire up the main routine (run).
-fn main() {
-    env_logger::init();
-    let args = Cli::parse();
+/// Additional template variables attached to a batch, such as the syslog facility of the records in it.
+type TemplateVars = Vec<(&'static str, String)>;
 
-    let compress = args.gzip;
-    let max_duration = args.duration;
-    let max_size = args.size;
-    if max_size > S3_MAXIMUM_SIZE {
-        eprintln!("Maximum size cannot be greater than {S3_MAXIMUM_SIZE:?}");
-        exit(2);
+/// The settings for a batching loop.
+#[derive(Debug)]
+struct BatchSettings {
+    /// The name of the pipeline this loop belongs to, for logging.
+    name: String,
+    host_id: String,
+    max_size: u64,
+    max_duration: Duration,
+    temp_dir: PathBuf,
+
+    /// Where each batch is written, starting with the main destination.
+    targets: Vec<Target>,
+
+    /// The number of targets a batch must reach before its temp file is deleted.
+    min_targets: usize,
+
+    /// Where to write batches, in order of preference, while the main destination is unavailable.
+    fallbacks: Vec<Target>,
+    fallback: Fallback,
+    compress: bool,
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+
+    /// Whether to check each uploaded object with HeadObject before deleting the temp file.
+    verify: bool,
+
+    /// Whether to upload under another key rather than overwrite an existing object.
+    no_overwrite: bool,
+
+    /// The canned ACL for uploaded objects, if any.
+    acl: Option<ObjectCannedAcl>,
+
+    /// The account id that must own the bucket, if any.
+    expected_bucket_owner: Option<String>,
+
+    /// The upload concurrency budget shared by every pipeline.
+    upload_slots: Arc<Semaphore>,
+
+    /// Where to record uploaded batches, if anywhere.
+    ledger: Option<Ledger>,
+
+    /// The template for the pipeline's manifest objects, in the same bucket, if it writes them.
+    manifest: Option<String>,
+    manifests: Manifests,
+}
+
+impl BatchSettings {
+    /// The names of the variables the targets' and fallbacks' templates use, sorted.
+    fn referenced_variables(&self) -> Result<Vec<String>, InvalidS3URL> {
+        let mut names = Vec::new();
+        for target in self.targets.iter().chain(&self.fallbacks) {
+            names.extend(template_variable_names(&target.object_name_pattern)?);
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
     }
-    let max_size: u64 = max_size.into();
-    let temp_dir: PathBuf = args.tempdir.into();
-    let destination = args.destination;
+}
 
-    if destination.is_empty() {
-        eprintln!("Missing S3 write destination.");
-        exit(2);
+/// One of the places a pipeline's batches are written.
+#[derive(Debug)]
+struct Target {
+    destination: Destination,
+
+    /// The template for object names; for a file destination, an absolute path.
+    object_name_pattern: String,
+}
+
+/// Where a target is.
+#[derive(Debug)]
+enum Destination {
+    /// An S3 bucket, access point or multi-region access point.
+    S3(S3Target),
+
+    /// An Azure Blob Storage container.
+    Azure(AzureTarget),
+
+    /// A Google Cloud Storage bucket.
+    Gcs(GcsTarget),
+
+    /// The local filesystem. Each batch is renamed into place once it's complete.
+    File,
+}
+
+impl Destination {
+    /// The bucket (or Azure container), for logs and metrics. Empty for the local filesystem.
+    fn bucket(&self) -> &str {
+        match self {
+            Self::S3(target) => &target.bucket,
+            Self::Azure(target) => &target.container,
+            Self::Gcs(target) => &target.bucket,
+            Self::File => "",
+        }
     }
 
-    let (bucket, object_name_pattern) = match parse_s3_url(&destination) {
-        Ok((bucket, onp)) => (bucket, onp),
-        Err(_) => {
-            eprintln!("Invalid S3 URL: {destination}");
-            exit(2);
+    /// The URL of an object written here.
+    fn url(&self, object_name: &str) -> String {
+        match self {
+            Self::S3(target) => format!("{S3_PROTO_PREFIX}{}/{object_name}", target.bucket),
+            Self::Azure(target) => format!("{AZURE_PROTO_PREFIX}{}/{}/{object_name}", target.account, target.container),
+            Self::Gcs(target) => format!("{GCS_PROTO_PREFIX}{}/{object_name}", target.bucket),
+            Self::File => format!("{FILE_PROTO_PREFIX}{object_name}"),
         }
-    };
+    }
+}
 
-    let input_file = match args.input {
-        None => None,
-        // Don't attempt to open the file; if it's a FIFO, we will stall until a byte is available.
-        Some(filename) => match likely_can_open_file(&filename) {
-            Ok(()) => Some(filename),
-            Err(e) => {
-                eprintln!("Unable to open {filename:?}: {e:?}");
-                exit(1);
+/// An S3 bucket or access point to write to.
+#[derive(Clone, Debug)]
+struct S3Target {
+    bucket: String,
+
+    /// A client for the bucket's region, shared with every other pipeline writing to that region.
+    s3: aws_sdk_s3::Client,
+
+    /// The bucket's region, if it was configured rather than looked up.
+    bucket_region: Option<String>,
+    server_side_encryption: ServerSideEncryption,
+    sse_kms_key_id: Option<String>,
+    storage_class: Option<StorageClass>,
+}
+
+impl Cli {
+    /// Turn the command line options into a configuration, either by reading the configuration file or by building a
+    /// single pipeline from the input and destination options.
+    fn into_config(self) -> Result<Config, ConfigError> {
+        let temp_dir = PathBuf::from(&self.tempdir);
+        let statsd = self.statsd.map(|addr| StatsdOptions {
+            addr,
+            format: self.statsd_format,
+            prefix: self.statsd_prefix,
+        });
+        let ledger = (self.ledger_file.is_some() || self.ledger_s3.is_some()).then_some(LedgerOptions {
+            file: self.ledger_file,
+            s3: self.ledger_s3,
+            acl: self.acl.clone(),
+            expected_bucket_owner: self.expected_bucket_owner.clone(),
+            server_side_encryption: self.sse.first().filter(|sse| !sse.is_empty()).cloned(),
+            sse_kms_key_id: self.sse_kms_key_id.first().filter(|key| !key.is_empty()).cloned(),
+        });
+        let assume_role = self.role_arn.map(|role_arn| AssumeRoleOptions {
+            role_arn,
+            external_id: self.external_id,
+            session_name: Some(self.role_session_name),
+        });
+        let azure =
+            (self.azure_endpoint.is_some() || self.azure_account_key.is_some() || self.azure_sas_token.is_some())
+                .then_some(AzureOptions {
+                    endpoint: self.azure_endpoint,
+                    account_key: self.azure_account_key,
+                    sas_token: self.azure_sas_token,
+                });
+        let gcs = (self.gcs_endpoint.is_some() || self.gcs_credentials.is_some() || self.gcs_anonymous).then_some(
+            GcsOptions {
+                endpoint: self.gcs_endpoint,
+                credentials_file: self.gcs_credentials,
+                anonymous: self.gcs_anonymous,
+            },
+        );
+
+        if let Some(path) = &self.config {
+            let defaults = ConfigDefaults {
+                temp_dir,
+                max_concurrent_uploads: self.max_concurrent_uploads,
+                metrics_listen: self.metrics_listen,
+                statsd,
+                ledger,
+                abort_uploads_older_than: self.abort_uploads_older_than,
+                assume_role,
+                azure,
+                gcs,
+            };
+            return Config::load(path, &defaults);
+        }
+
+        let syslog = SyslogOptions {
+            udp: self.listen_syslog_udp,
+            tcp: self.listen_syslog_tcp,
+            json: self.syslog_json,
+            max_partitions: self.syslog_max_partitions,
+        };
+
+        #[cfg(unix)]
+        let unix_socket = self.listen_unix.map(|path| {
+            InputConfig::Unix(UnixSocketOptions {
+                path,
+                datagram: self.unix_datagram,
+                mode: self.socket_mode,
+                owner: self.socket_owner,
+                group: self.socket_group,
+            })
+        });
+        #[cfg(not(unix))]
+        let unix_socket = None;
+
+        let count = self.destinations.len();
+        let mut destinations = Vec::with_capacity(count);
+        let mut fallback_destinations = Vec::with_capacity(self.fallback_destination.len());
+        let main_and_fallbacks = self.destinations.iter().enumerate().chain(
+            // Fallbacks only take the options given once.
+            self.fallback_destination.iter().map(|destination| (count, destination)),
+        );
+        let is_s3 = |url: &str| {
+            ![FILE_PROTO_PREFIX, AZURE_PROTO_PREFIX, GCS_PROTO_PREFIX].iter().any(|prefix| url.starts_with(prefix))
+        };
+        for (index, destination) in main_and_fallbacks {
+            let pick = |option: &str, values: &[String]| {
+                match values.len() {
+                    0 => Ok(None),
+                    // Given once, an option applies to every S3 destination.
+                    1 => Ok(Some(values[0].clone()).filter(|_| is_s3(destination))),
+                    n if n == count => Ok(values.get(index).cloned()),
+                    n => Err(ConfigError::Invalid(format!("--{option} was given {n} times for {count} destinations"))),
+                }
+                .map(|value| value.filter(|value| !value.is_empty()))
+            };
+
+            let config = DestinationConfig {
+                destination: destination.clone(),
+                bucket_region: pick("bucket-region", &self.bucket_region)?,
+                server_side_encryption: pick("sse", &self.sse)?,
+                sse_kms_key_id: pick("sse-kms-key-id", &self.sse_kms_key_id)?,
+                storage_class: pick("storage-class", &self.storage_class)?,
+            };
+            if index < count {
+                destinations.push(config);
+            } else {
+                fallback_destinations.push(config);
             }
-        },
+        }
+        let mut destinations = destinations.into_iter();
+        let main = destinations.next().unwrap_or_default();
+
+        let input = if !self.command.is_empty() {
+            InputConfig::Command {
+                command: self.command,
+            }
+        } else if !syslog.is_empty() {
+            InputConfig::Syslog(syslog)
+        } else if let Some(addr) = self.listen_http {
+            InputConfig::Http(HttpIngestOptions {
+                addr,
+                max_body_size: self.http_max_body.into(),
+                bearer_token: self.http_bearer_token,
+            })
+        } else if let Some(path) = self.input {
+            if self.follow {
+                InputConfig::Follow {
+                    path,
+                    state: self.follow_state,
+                }
+            } else {
+                InputConfig::File {
+                    path,
+                }
+            }
+        } else {
+            unix_socket.unwrap_or_default()
+        };
+
+        Ok(Config {
+            max_concurrent_uploads: self.max_concurrent_uploads,
+            pipelines: vec![PipelineConfig {
+                name: DEFAULT_PIPELINE_NAME.to_string(),
+                destination: main.destination,
+                input,
+                max_size: self.size.as_u64(),
+                max_duration: self.duration,
+                compress: self.gzip,
+                checksum_algorithm: self.checksum_algorithm,
+                verify: self.verify_uploads,
+                no_overwrite: self.no_overwrite,
+                acl: self.acl,
+                expected_bucket_owner: self.expected_bucket_owner,
+                bucket_region: main.bucket_region,
+                server_side_encryption: main.server_side_encryption,
+                sse_kms_key_id: main.sse_kms_key_id,
+                storage_class: main.storage_class,
+                extra_destinations: destinations.collect(),
+                min_destinations: self.min_destinations,
+                fallback_destinations,
+                fallback_after: self.fallback_after,
+                fallback_retry: self.fallback_retry,
+                temp_dir: Some(temp_dir),
+                manifest: self.manifest,
+            }],
+            metrics_listen: self.metrics_listen,
+            statsd,
+            ledger,
+            abort_uploads_older_than: self.abort_uploads_older_than,
+            assume_role,
+            azure,
+            gcs,
+            source: None,
+        })
+    }
+}
+
+/// Program entrypoint. Parse options and, if they seem reasonable, fire up the pipelines (run_pipelines).
+fn main() {
+    let args = Cli::parse();
+    logging::init(args.log_format);
+    let cleanup_uploads = args.cleanup_uploads;
+
+    let config = match args.into_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(2);
+        }
     };
 
+    let mut valid = config.validate();
+    if cleanup_uploads {
+        valid = valid.and_then(|()| config.validate_cleanup());
+    }
+    if let Err(e) = valid {
+        eprintln!("{e}");
+        exit(2);
+    }
+
+    for pipeline in config.pipelines.iter().filter(|_| !cleanup_uploads) {
+        // Don't attempt to open the file; if it's a FIFO, we will stall until a byte is available. Followed files may
+        // not have been created yet; we wait for them to appear.
+        if let InputConfig::File {
+            path,
+        } = &pipeline.input
+            && let Err(e) = likely_can_open_file(path)
+        {
+            eprintln!("Unable to open {path:?}: {e:?}");
+            exit(1);
+        }
+    }
+
     let runtime = match RuntimeBuilder::new_current_thread().enable_all().build() {
         Ok(rt) => rt,
         Err(e) => {
@@ -185,94 +835,796 @@ fn main() {
         }
     };
 
-    runtime.block_on(async {
-        let host_id = get_host_id().await;
-        debug!("Using host_id {host_id:?}");
+    if cleanup_uploads {
+        exit(runtime.block_on(cleanup::run(config)));
+    }
 
-        debug!("Getting bucket location");
-        let config = aws_config::load_from_env().await;
-        let s3 = aws_sdk_s3::Client::new(&config);
+    exit(runtime.block_on(run_pipelines(config)));
+}
 
-        let bucket_loc_result = s3.get_bucket_location().bucket(bucket.clone()).send().await;
-        let bucket_region = match bucket_loc_result {
+/// Run every pipeline in the configuration until all of their inputs are exhausted, sharing a host id, S3 clients and
+/// the upload concurrency budget. If the configuration came from a file, SIGHUP reloads it. Returns the process exit
+/// code: the exit code of a wrapped command if one failed, otherwise 1 if any pipeline failed, otherwise 0.
+async fn run_pipelines(config: Config) -> i32 {
+    if let Some(addr) = config.metrics_listen {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                tokio::spawn(metrics::serve(listener));
+            }
             Err(e) => {
-                error!("Unable to determine the location of S3 bucket {bucket}: {e:?}");
-                exit(1);
-            }
-            Ok(output) => match output.location_constraint() {
-                // No location constraint = us-east-1
-                None => REGION_US_EAST_1,
-                // EU = eu-west-1
-                Some(BucketLocationConstraint::Eu) => REGION_EU_WEST_1,
-                Some(loc) => Region::new(loc.as_str().to_string()),
-            },
-        };
+                error!("Unable to listen for metrics on {addr}: {e}");
+                return 1;
+            }
+        }
+    }
 
-        match input_file {
-            Some(filename) => match File::open(filename.clone()).await {
-                Ok(f) => run(
-                    f,
-                    &host_id,
-                    max_size,
-                    max_duration,
-                    &temp_dir,
+    let host_id = get_host_id().await;
+    debug!("Using host_id {host_id:?}");
+
+    if let Some(options) = &config.statsd
+        && let Err(e) = statsd::init(options, &host_id)
+    {
+        error!("Unable to send metrics to StatsD at {}: {e}", options.addr);
+        return 1;
+    }
+
+    let (manifests, manifest_writer) = manifest::start(&host_id);
+    let mut supervisor = Supervisor {
+        host_id,
+        clients: S3Clients::new(load_sdk_config(config.assume_role.as_ref()).await),
+        upload_slots: Arc::new(Semaphore::new(config.max_concurrent_uploads)),
+        ledger: None,
+        manifests,
+        publishers: HashMap::new(),
+        running: FuturesUnordered::new(),
+        config: Config {
+            max_concurrent_uploads: config.max_concurrent_uploads,
+            pipelines: Vec::new(),
+            metrics_listen: config.metrics_listen,
+            statsd: config.statsd.clone(),
+            ledger: config.ledger.clone(),
+            abort_uploads_older_than: config.abort_uploads_older_than,
+            assume_role: config.assume_role.clone(),
+            azure: config.azure.clone(),
+            gcs: config.gcs.clone(),
+            source: config.source.clone(),
+        },
+    };
+
+    let ledger_writer = match &config.ledger {
+        Some(options) => match supervisor.start_ledger(options).await {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                error!("Unable to start the upload ledger: {e}");
+                return 1;
+            }
+        },
+        None => None,
+    };
+
+    let mut all_settings = Vec::with_capacity(config.pipelines.len());
+    for pipeline in &config.pipelines {
+        match supervisor.settings_for(pipeline).await {
+            Ok(settings) => all_settings.push(settings),
+            Err(e) => {
+                error!("{e}");
+                return 1;
+            }
+        }
+    }
+
+    // Finish the multipart uploads an earlier run was in the middle of, and clean up after it, in the background;
+    // this needn't hold up the pipelines.
+    let mut temp_dirs: Vec<PathBuf> = all_settings.iter().map(|settings| settings.temp_dir.clone()).collect();
+    temp_dirs.sort();
+    temp_dirs.dedup();
+    let (resuming, resumed) = supervisor.resume_uploads(&temp_dirs).await;
+
+    if let Some(older_than) = config.abort_uploads_older_than {
+        let resuming = Arc::new(resuming);
+        let mut seen = HashSet::new();
+        let targets = all_settings.iter().flat_map(|s| s.targets.iter().chain(&s.fallbacks).map(move |t| (s, t)));
+        for (settings, target) in targets {
+            // Only S3 destinations have multipart uploads to clean up.
+            let Destination::S3(S3Target {
+                bucket,
+                s3,
+                ..
+            }) = &target.destination
+            else {
+                continue;
+            };
+            let (bucket, pattern) = (bucket.clone(), target.object_name_pattern.clone());
+            if !seen.insert((bucket.clone(), pattern.clone())) {
+                continue;
+            }
+
+            let (s3, host_id, resuming) = (s3.clone(), settings.host_id.clone(), resuming.clone());
+            let owner = settings.expected_bucket_owner.clone();
+            tokio::spawn(async move {
+                let result = cleanup::abort_stale_uploads(
+                    &s3,
                     &bucket,
-                    bucket_region,
-                    &object_name_pattern,
-                    compress,
-                )
-                .await
-                .unwrap(),
-                Err(e) => error!("Unable to open {filename:?}: {e:?}"),
+                    owner.as_deref(),
+                    &pattern,
+                    &host_id,
+                    older_than,
+                    &resuming,
+                );
+                if let Err(e) = result.await {
+                    warn!("Unable to clean up incomplete uploads in s3://{bucket}/{pattern}: {e}");
+                }
+            });
+        }
+    }
+
+    for (pipeline, settings) in config.pipelines.into_iter().zip(all_settings) {
+        supervisor.start(pipeline, settings);
+    }
+
+    let mut reload_signal = ReloadSignal::new(supervisor.config.source.is_some());
+    let mut failed = false;
+    let mut command_exit_code = 0;
+
+    loop {
+        select! {
+            finished = supervisor.running.next() => match finished {
+                None => break,
+                Some((name, Ok(None))) => debug!("Pipeline {name} finished"),
+                Some((name, Ok(Some(exit_code)))) => {
+                    debug!("Pipeline {name} finished; command exited with {exit_code}");
+                    if command_exit_code == 0 {
+                        command_exit_code = exit_code;
+                    }
+                }
+                Some((name, Err(e))) => {
+                    error!("Pipeline {name} failed: {e}");
+                    failed = true;
+                }
             },
-            None => run(
-                stdin(),
-                &host_id,
-                max_size,
-                max_duration,
-                &temp_dir,
-                &bucket,
-                bucket_region,
-                &object_name_pattern,
-                compress,
-            )
-            .await
-            .unwrap(),
+
+            _ = reload_signal.recv() => supervisor.reload().await,
+        }
+    }
+
+    for handle in resumed {
+        if let Err(e) = handle.await {
+            error!("Resumed upload failed: {e}");
+        }
+    }
+
+    // Every upload has finished; let the manifest and ledger writers catch up.
+    drop(supervisor);
+    if let Err(e) = manifest_writer.await {
+        error!("Manifest writer failed: {e}");
+    }
+    if let Some(writer) = ledger_writer
+        && let Err(e) = writer.await
+    {
+        error!("Ledger writer failed: {e}");
+    }
+
+    if command_exit_code != 0 {
+        command_exit_code
+    } else if failed {
+        1
+    } else {
+        0
+    }
+}
+
+/// A pipeline's batch settings. Batching loops pick up a new value at the start of their next batch, closing the
+/// current batch under the old settings first.
+type SettingsWatch = watch::Receiver<Arc<BatchSettings>>;
+
+/// The result of a pipeline, tagged with its name.
+type PipelineFuture = Pin<Box<dyn Future<Output = (String, AnyResult<Option<i32>>)>>>;
+
+/// The running pipelines and the resources they share.
+struct Supervisor {
+    host_id: String,
+    clients: S3Clients,
+    upload_slots: Arc<Semaphore>,
+    ledger: Option<Ledger>,
+    manifests: Manifests,
+
+    /// The configuration of every pipeline that has been started.
+    config: Config,
+
+    /// Where to publish new settings for each pipeline, by name.
+    publishers: HashMap<String, watch::Sender<Arc<BatchSettings>>>,
+
+    running: FuturesUnordered<PipelineFuture>,
+}
+
+impl Supervisor {
+    /// Resolve a pipeline's destinations into batch settings.
+    async fn settings_for(&mut self, pipeline: &PipelineConfig) -> AnyResult<BatchSettings> {
+        let expected_bucket_owner = pipeline.expected_bucket_owner.clone();
+        let mut targets = Vec::new();
+        for destination in pipeline.destinations() {
+            targets.push(self.target_for(destination, expected_bucket_owner.as_deref()).await?);
+        }
+        let mut fallbacks = Vec::new();
+        for destination in pipeline.fallback_destinations.iter().cloned() {
+            let mut target = self.target_for(destination, expected_bucket_owner.as_deref()).await?;
+            target.object_name_pattern = mark_key(&target.object_name_pattern, FALLBACK_KEY_MARKER);
+            fallbacks.push(target);
+        }
+        let fallback = Fallback::new(
+            &pipeline.name,
+            pipeline.fallback_after.unwrap_or(DEFAULT_FALLBACK_AFTER),
+            pipeline.fallback_retry.unwrap_or_else(|| parse_duration(DEFAULT_FALLBACK_RETRY).unwrap()),
+        );
+
+        Ok(BatchSettings {
+            name: pipeline.name.clone(),
+            host_id: self.host_id.clone(),
+            max_size: pipeline.max_size,
+            max_duration: pipeline.max_duration,
+            temp_dir: pipeline.temp_dir.clone().unwrap_or_else(temp_dir),
+            min_targets: pipeline.min_destinations.unwrap_or(targets.len()),
+            targets,
+            fallbacks,
+            fallback,
+            compress: pipeline.compress,
+            checksum_algorithm: pipeline.checksum_algorithm,
+            verify: pipeline.verify,
+            no_overwrite: pipeline.no_overwrite,
+            acl: pipeline.acl.as_deref().map(ObjectCannedAcl::from),
+            expected_bucket_owner,
+            upload_slots: self.upload_slots.clone(),
+            ledger: self.ledger.clone(),
+            manifest: pipeline.manifest.clone(),
+            manifests: self.manifests.clone(),
+        })
+    }
+
+    /// Resolve one of a pipeline's destinations, finding the region of its bucket if it's in S3.
+    async fn target_for(&mut self, destination: DestinationConfig, expected_owner: Option<&str>) -> AnyResult<Target> {
+        // Validation has already checked the URL.
+        let (location, object_name_pattern) = parse_destination(&destination.destination)?;
+        let destination = match location {
+            Location::S3(bucket) => {
+                let region = destination.bucket_region.as_deref();
+                let s3 = match self.clients.for_bucket(&bucket, expected_owner, region).await {
+                    Ok(s3) => s3,
+                    Err(e) => bail!("Unable to determine the location of S3 bucket {bucket}: {e:?}"),
+                };
+                Destination::S3(S3Target {
+                    bucket,
+                    s3,
+                    bucket_region: destination.bucket_region,
+                    server_side_encryption: destination
+                        .server_side_encryption
+                        .as_deref()
+                        .map_or(ServerSideEncryption::Aes256, ServerSideEncryption::from),
+                    sse_kms_key_id: destination.sse_kms_key_id,
+                    storage_class: destination.storage_class.as_deref().map(StorageClass::from),
+                })
+            }
+            Location::Azure {
+                account,
+                container,
+            } => {
+                let options = self.config.azure.clone().unwrap_or_default();
+                Destination::Azure(AzureTarget::new(&account, &container, &options, reqwest::Client::new())?)
+            }
+            Location::Gcs(bucket) => {
+                let options = self.config.gcs.clone().unwrap_or_default();
+                Destination::Gcs(GcsTarget::new(&bucket, &options)?)
+            }
+            Location::File => Destination::File,
+        };
+
+        Ok(Target {
+            destination,
+            object_name_pattern,
+        })
+    }
+
+    /// Start recording uploads in the ledger. Must be called before any pipeline is started.
+    async fn start_ledger(&mut self, options: &LedgerOptions) -> AnyResult<JoinHandle<()>> {
+        let s3 = match &options.s3 {
+            Some(url) => {
+                let (bucket, _) = parse_s3_url(url)?;
+                match self.clients.for_bucket(&bucket, options.expected_bucket_owner.as_deref(), None).await {
+                    Ok(s3) => Some(s3),
+                    Err(e) => bail!("Unable to determine the location of S3 bucket {bucket}: {e:?}"),
+                }
+            }
+            None => None,
+        };
+
+        let (ledger, writer) = ledger::start(options, &self.host_id, s3).await?;
+        self.ledger = Some(ledger);
+        Ok(writer)
+    }
+
+    /// Resume the multipart uploads left unfinished in `temp_dirs` by an earlier run. Returns the ids of the uploads
+    /// saved there and the tasks finishing them. A spool file fanned out to several destinations may have an upload for
+    /// each; it is deleted once they have all finished, and kept if any of them fails or if any destination has no
+    /// upload to resume, since that destination may not have the batch. Spool files another process has locked are its
+    /// own to finish, and are left alone.
+    async fn resume_uploads(&mut self, temp_dirs: &[PathBuf]) -> (HashSet<String>, Vec<JoinHandle<()>>) {
+        let mut upload_ids = HashSet::new();
+        let mut spools = Vec::new();
+
+        for dir in temp_dirs {
+            for unfinished in spool::find_unfinished(dir).await {
+                let spool = &unfinished.spool;
+                let mut uploads = Vec::with_capacity(unfinished.uploads.len());
+                for state in unfinished.uploads {
+                    let (owner, region) = (state.expected_bucket_owner.as_deref(), state.bucket_region.as_deref());
+                    let s3 = match self.clients.for_bucket(&state.bucket, owner, region).await {
+                        Ok(s3) => Some(s3),
+                        Err(e) => {
+                            error!("Unable to resume upload of {spool:?}; can't locate bucket {}: {e:?}", state.bucket);
+                            None
+                        }
+                    };
+
+                    // Hold the object's manifest window open until the upload has finished.
+                    let window = match (&state.manifest_key, &state.manifest_pattern, &s3) {
+                        (Some(key), Some(pattern), Some(s3)) => {
+                            let window = ManifestWindow {
+                                bucket: state.bucket.clone(),
+                                key: key.clone(),
+                                pattern: pattern.clone(),
+                                s3: s3.clone(),
+                                acl: state.acl.as_deref().map(ObjectCannedAcl::from),
+                                expected_bucket_owner: state.expected_bucket_owner.clone(),
+                                server_side_encryption: state
+                                    .server_side_encryption
+                                    .as_deref()
+                                    .map_or(ServerSideEncryption::Aes256, ServerSideEncryption::from),
+                                sse_kms_key_id: state.sse_kms_key_id.clone(),
+                            };
+                            self.manifests.upload_started(&window);
+                            Some(window)
+                        }
+                        _ => None,
+                    };
+
+                    uploads.push((state, s3, window));
+                }
+                spools.push((unfinished.spool, unfinished.lock, uploads));
+            }
+
+            // Uploads other processes are still making aren't stale either.
+            upload_ids.extend(spool::saved_upload_ids(dir).await);
+        }
+
+        let mut tasks = Vec::new();
+        for (spool, lock, uploads) in spools {
+            // A fallback stands in for the main destination.
+            let target_count = uploads.iter().map(|(state, _, _)| state.target_count).max().unwrap_or(1);
+            let covered: HashSet<_> = uploads
+                .iter()
+                .map(|(state, _, _)| state.destination_index)
+                .map(|index| {
+                    if index < target_count {
+                        index
+                    } else {
+                        0
+                    }
+                })
+                .collect();
+            let pipeline = uploads[0].0.pipeline.clone();
+
+            let (host_id, slots, ledger) = (self.host_id.clone(), self.upload_slots.clone(), self.ledger.clone());
+            let manifests = self.manifests.clone();
+            tasks.push(tokio::spawn(async move {
+                // Failures have been logged already.
+                let (mut finished, mut delivered) = (true, 0);
+                for (state, s3, window) in uploads {
+                    let resumed = match s3 {
+                        Some(s3) => {
+                            let (host_id, slots, ledger, manifests) =
+                                (host_id.clone(), slots.clone(), ledger.clone(), manifests.clone());
+                            resume_upload(&spool, state, host_id, s3, slots, ledger, manifests, window).await.is_ok()
+                        }
+                        // Without a client, there's no manifest window either.
+                        None => false,
+                    };
+                    finished &= resumed;
+                    delivered += usize::from(resumed);
+                }
+
+                if finished && covered.len() == target_count {
+                    if let Err(e) = remove_file(&spool).await {
+                        warn!("Unable to remove {spool:?}: {e}");
+                    }
+                } else {
+                    let required = target_count;
+                    error!(
+                        event = "batch_kept", pipeline = pipeline.as_str(), delivered, required, path:? = spool;
+                        "Keeping {spool:?}; {delivered} of its {required} destinations are known to have it from resumed \
+                         uploads"
+                    );
+                }
+                drop(lock);
+            }));
+        }
+
+        (upload_ids, tasks)
+    }
+
+    /// Start a pipeline's input and batching loops.
+    fn start(&mut self, pipeline: PipelineConfig, settings: BatchSettings) {
+        let (publisher, settings) = watch::channel(Arc::new(settings));
+        let name = pipeline.name.clone();
+        let input = pipeline.input.clone();
+        let reloadable = self.config.source.is_some();
+
+        self.publishers.insert(name.clone(), publisher);
+        self.config.pipelines.push(pipeline);
+        self.running.push(Box::pin(async move { (name, run_pipeline(input, settings, reloadable).await) }));
+    }
+
+    /// Re-read the configuration file and apply it. If it can't be read, fails validation, or changes something that
+    /// needs a restart, it is rejected and the current configuration is kept.
+    async fn reload(&mut self) {
+        let Some(source) = self.config.source.clone() else {
+            return;
+        };
+
+        info!("Reloading configuration");
+        let new_config = match Config::reload(&source).and_then(|c| c.validate_reload(&self.config).map(|_| c)) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Keeping current configuration: {e}");
+                return;
+            }
+        };
+
+        // Resolve every destination before changing anything so a bad bucket doesn't leave us half-reloaded.
+        let mut changes = Vec::new();
+        for pipeline in new_config.pipelines {
+            if self.config.pipelines.contains(&pipeline) {
+                continue;
+            }
+
+            match self.settings_for(&pipeline).await {
+                Ok(settings) => changes.push((pipeline, settings)),
+                Err(e) => {
+                    error!("Keeping current configuration: {e}");
+                    return;
+                }
+            }
+        }
+
+        for (pipeline, settings) in changes {
+            match self.publishers.get(&pipeline.name) {
+                Some(publisher) => {
+                    info!("Pipeline {} changed; new batches will use the new settings", pipeline.name);
+                    publisher.send_replace(Arc::new(settings));
+                    if let Some(current) = self.config.pipelines.iter_mut().find(|p| p.name == pipeline.name) {
+                        *current = pipeline;
+                    }
+                }
+                None => {
+                    info!("Starting new pipeline {}", pipeline.name);
+                    self.start(pipeline, settings);
+                }
+            }
+        }
+
+        let (old_slots, new_slots) = (self.config.max_concurrent_uploads, new_config.max_concurrent_uploads);
+        if new_slots > old_slots {
+            self.upload_slots.add_permits(new_slots - old_slots);
+        } else if new_slots < old_slots {
+            // Only idle slots can be removed; any still in use are removed as they are released.
+            let mut excess = old_slots - new_slots;
+            excess -= self.upload_slots.forget_permits(excess);
+            if excess > 0 {
+                let upload_slots = self.upload_slots.clone();
+                tokio::spawn(async move {
+                    if let Ok(permits) = upload_slots.acquire_many(excess as u32).await {
+                        permits.forget();
+                    }
+                });
+            }
+        }
+        self.config.max_concurrent_uploads = new_slots;
+
+        info!("Configuration reloaded");
+    }
+}
+
+/// Notifies us when the configuration should be reloaded: on SIGHUP, if there is a configuration file to reload.
+struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: Option<tokio::signal::unix::Signal>,
+}
+
+impl ReloadSignal {
+    #[cfg(unix)]
+    fn new(enabled: bool) -> Self {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let hangup = if enabled {
+            match signal(SignalKind::hangup()) {
+                Ok(hangup) => Some(hangup),
+                Err(e) => {
+                    error!("Unable to listen for SIGHUP; configuration reloading is disabled: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Self {
+            hangup,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn new(_enabled: bool) -> Self {
+        Self {}
+    }
+
+    /// Wait for a reload request. Never completes if reloading is disabled.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(hangup) = &mut self.hangup
+            && hangup.recv().await.is_some()
+        {
+            return;
         }
-    });
+
+        pending().await
+    }
+}
+
+/// Start a pipeline's input and batch it to S3 until the input is exhausted. For a wrapped command, returns the
+/// command's exit code. `reloadable` is set when SIGHUP reloads the configuration rather than being forwarded to a
+/// wrapped command.
+async fn run_pipeline(input: InputConfig, settings: SettingsWatch, reloadable: bool) -> AnyResult<Option<i32>> {
+    let mut acks = None;
+    let reader: Pin<Box<dyn AsyncRead>> = match input {
+        InputConfig::Command {
+            command,
+        } => {
+            let exit_code = match wrapper::run_wrapped(command, settings, !reloadable).await {
+                Ok(exit_code) => exit_code,
+                Err(e) => {
+                    error!("Unable to run command: {e}");
+                    COMMAND_FAILED_EXIT_CODE
+                }
+            };
+            return Ok(Some(exit_code));
+        }
+        InputConfig::Syslog(options) => {
+            let (tx, rx) = channel(INPUT_CHANNEL_SIZE);
+            let max_partitions = options.max_partitions;
+            if let Err(e) = options.start(tx).await {
+                bail!("Unable to start syslog listener: {e}");
+            }
+            run_partitioned(rx, settings, max_partitions).await?;
+            return Ok(None);
+        }
+        InputConfig::Follow {
+            path,
+            state,
+        } => {
+            let state_path = state.unwrap_or_else(|| default_state_path(&settings.borrow().temp_dir, &path));
+            debug!("Following {path:?} with state in {state_path:?}");
+            let (tx, rx) = channel(INPUT_CHANNEL_SIZE);
+            tokio::spawn(FileFollower::new(path.into(), state_path, tx).run());
+            let reader = ChannelReader::new(rx);
+            acks = Some(reader.acks());
+            Box::pin(reader)
+        }
+        InputConfig::File {
+            path,
+        } => match File::open(&path).await {
+            Ok(f) => Box::pin(f),
+            Err(e) => bail!("Unable to open {path:?}: {e:?}"),
+        },
+        #[cfg(unix)]
+        InputConfig::Unix(options) => {
+            let listener = match UnixSocketListener::bind(&options) {
+                Ok(listener) => listener,
+                Err(e) => bail!("Unable to listen on Unix socket {:?}: {e}", options.path),
+            };
+            let (tx, rx) = channel(INPUT_CHANNEL_SIZE);
+            tokio::spawn(listener.serve(tx));
+            Box::pin(ChannelReader::new(rx))
+        }
+        InputConfig::Http(options) => {
+            let listener = match TcpListener::bind(options.addr).await {
+                Ok(listener) => listener,
+                Err(e) => bail!("Unable to listen for HTTP on {}: {e}", options.addr),
+            };
+            let (tx, rx) = channel(INPUT_CHANNEL_SIZE);
+            tokio::spawn(http_ingest::serve(listener, options, tx));
+            let reader = ChannelReader::new(rx);
+            acks = Some(reader.acks());
+            Box::pin(reader)
+        }
+        InputConfig::Stdin => Box::pin(stdin()),
+    };
+
+    run(reader, settings, TemplateVars::new(), acks).await?;
+    Ok(None)
+}
+
+/// S3 clients shared across pipelines: one per region, plus a cache of which region each bucket is in.
+struct S3Clients {
+    config: SdkConfig,
+    default: aws_sdk_s3::Client,
+    by_region: HashMap<Region, aws_sdk_s3::Client>,
+    bucket_regions: HashMap<String, Region>,
+}
+
+impl S3Clients {
+    fn new(config: SdkConfig) -> Self {
+        let default = aws_sdk_s3::Client::from_conf(s3_config(&config, None));
+        Self {
+            config,
+            default,
+            by_region: HashMap::new(),
+            bucket_regions: HashMap::new(),
+        }
+    }
+
+    /// Return a client for the region `bucket` is in. An access point ARN names its region; otherwise, if `region` is
+    /// given, it's taken as the bucket's region, and if not, the region is found as [`BucketKind`] describes. If
+    /// `expected_owner` is given, the lookup fails unless that account owns the bucket.
+    async fn for_bucket(
+        &mut self,
+        bucket: &str,
+        expected_owner: Option<&str>,
+        region: Option<&str>,
+    ) -> AnyResult<aws_sdk_s3::Client> {
+        let region = match (region, BucketKind::of(bucket)) {
+            (_, BucketKind::AccessPoint(region)) => Region::new(region.to_string()),
+            (Some(region), _) => {
+                let region = Region::new(region.to_string());
+                self.bucket_regions.insert(bucket.to_string(), region.clone());
+                region
+            }
+            (None, BucketKind::MultiRegionAccessPoint) => self.config.region().cloned().unwrap_or(REGION_US_EAST_1),
+            (None, BucketKind::Directory) => match self.config.region() {
+                Some(region) => region.clone(),
+                None => bail!("No region is configured for directory bucket {bucket}; set one with --bucket-region"),
+            },
+            (None, BucketKind::Bucket) => match self.bucket_regions.get(bucket) {
+                Some(region) => region.clone(),
+                None => {
+                    debug!("Getting bucket location for {bucket}");
+                    let region = get_bucket_region(&self.default, bucket, expected_owner).await?;
+                    self.bucket_regions.insert(bucket.to_string(), region.clone());
+                    region
+                }
+            },
+        };
+
+        let config = &self.config;
+        let client = self
+            .by_region
+            .entry(region)
+            .or_insert_with_key(|region| aws_sdk_s3::Client::from_conf(s3_config(config, Some(region.clone()))));
+        Ok(client.clone())
+    }
+}
+
+/// Load the AWS configuration from the environment. If `assume_role` is given, requests are made as that role, with
+/// the environment's credentials used only to assume it.
+async fn load_sdk_config(assume_role: Option<&AssumeRoleOptions>) -> SdkConfig {
+    let config = aws_config::load_from_env().await;
+    let Some(options) = assume_role else {
+        return config;
+    };
+
+    info!("Assuming role {}", options.role_arn);
+    let mut builder = AssumeRoleProvider::builder(&options.role_arn)
+        .configure(&config)
+        .session_name(options.session_name.as_deref().unwrap_or(DEFAULT_ROLE_SESSION_NAME));
+    if let Some(external_id) = &options.external_id {
+        builder = builder.external_id(external_id);
+    }
+    let provider = builder.build().await;
+    config.into_builder().credentials_provider(SharedCredentialsProvider::new(provider)).build()
+}
+
+/// Build the configuration for an S3 client, optionally overriding the region.
+fn s3_config(config: &SdkConfig, region: Option<Region>) -> aws_sdk_s3::Config {
+    let mut builder = aws_sdk_s3::config::Builder::from(config).interceptor(RetryCounter);
+    if let Some(region) = region {
+        builder = builder.region(region);
+    }
+    builder.build()
+}
+
+/// Find the region an S3 bucket is in.
+///
+/// HeadBucket reports the region in its `x-amz-bucket-region` header, including on the 301 S3 returns when the bucket
+/// is in another region and on a 403 when we may not list the bucket, so it's asked first. GetBucketLocation needs a
+/// permission least-privilege policies often leave out and isn't implemented by every S3-compatible store, so it's
+/// only the fallback.
+async fn get_bucket_region(s3: &aws_sdk_s3::Client, bucket: &str, expected_owner: Option<&str>) -> AnyResult<Region> {
+    let expected_owner = expected_owner.map(str::to_string);
+    let result = s3.head_bucket().bucket(bucket).set_expected_bucket_owner(expected_owner.clone()).send().await;
+    let region = match &result {
+        Ok(output) => output.bucket_region(),
+        Err(e) => e.raw_response().and_then(|response| response.headers().get(BUCKET_REGION_HEADER)),
+    };
+    if let Some(region) = region {
+        return Ok(Region::new(region.to_string()));
+    }
+    if let Err(e) = result {
+        debug!(
+            "HeadBucket didn't report the region of {bucket} ({}); trying GetBucketLocation",
+            S3RequestError::new(e)
+        );
+    }
+
+    let output = s3.get_bucket_location().bucket(bucket).set_expected_bucket_owner(expected_owner).send().await?;
+    Ok(match output.location_constraint() {
+        // No location constraint = us-east-1
+        None => REGION_US_EAST_1,
+        // EU = eu-west-1
+        Some(BucketLocationConstraint::Eu) => REGION_EU_WEST_1,
+        Some(loc) => Region::new(loc.as_str().to_string()),
+    })
 }
 
 /// The main loop of the program. Under normal conditions, this returns only when the input stream is closed.
-#[allow(clippy::too_many_arguments)]
+///
+/// `variables` are additional template variables (beyond the host id and timestamps) available to the object name
+/// pattern for every batch produced by this loop. When `settings` changes, the current batch is closed and sent
+/// under the old settings and the next batch uses the new ones. If the reader's producers want to hear about their
+/// input, `acks` holds their acknowledgements: those waiting on a sync are sent once the batch's file has been synced
+/// after the input was written to it, and each batch sends those waiting on an upload once it's uploaded.
 async fn run<R: AsyncRead>(
     reader: R,
-    host_id: &str,
-    max_size: u64,
-    max_duration: Duration,
-    temp_dir: &PathBuf,
-    bucket: &str,
-    bucket_region: Region,
-    object_name_pattern: &str,
-    compress: bool,
+    mut settings: SettingsWatch,
+    variables: TemplateVars,
+    acks: Option<PendingAcks>,
 ) -> AnyResult<()> {
     let mut reader = Box::pin(BufReader::with_capacity(READ_BUF_SIZE, reader));
     let mut send_futures = TaskQueue::new();
-    info!("Loop starting with max_size {max_size:?} and max_duration {max_duration:?}");
+    let mut watching = true;
+    let mut loop_metrics = LoopMetrics::new(&settings.borrow().name);
+    let uploaded = || acks.as_ref().map(PendingAcks::take_uploaded).unwrap_or_default();
 
     'outer: loop {
+        let current = settings.borrow_and_update().clone();
+        let BatchSettings {
+            name,
+            max_size,
+            max_duration,
+            temp_dir,
+            compress,
+            ..
+        } = current.as_ref();
+        let (max_size, max_duration, compress) = (*max_size, *max_duration, *compress);
+        info!("Batch for {name} starting with max_size {max_size:?} and max_duration {max_duration:?}");
+
         let mut current_size: u64 = 0;
+        let mut stats = BatchStats::default();
         let mut last_reported_size: u64 = 0;
         let mut buf: [u8; READ_BUF_SIZE] = [0; READ_BUF_SIZE];
 
         // Create a named temp file for recording data. We need to reopen this file for multipart uploads since
         // Rust doesn't let us dup() a file handle (yet).
         let (std_file, temp_path) = NamedTempFile::new_in(temp_dir)?.into_parts();
-        debug!("Opened log file {temp_path:?}");
+        info!(
+            event = "batch_opened",
+            pipeline = name.as_str(),
+            path:? = temp_path;
+            "Opened log file {temp_path:?}"
+        );
+        loop_metrics.batch_opened(temp_path.to_path_buf(), current.targets[0].destination.bucket());
 
         // Don't start the timer until the first byte is read. We initialize it here with a future that will never
         // complete.
         let mut timeout = MaybeTimeout::pending();
-        let tokio_file = File::from_std(std_file);
+        let checksums = current.checksum_algorithm.map(|algorithm| PartChecksums::new(algorithm, MAX_PART_SIZE));
+        let tokio_file = ChecksumFile::new(File::from_std(std_file), checksums);
 
         let mut file = if compress {
             MaybeCompressedFile::Gzip(GzipEncoder::with_quality(tokio_file, Level::Default))
@@ -281,29 +1633,32 @@ async fn run<R: AsyncRead>(
         };
 
         loop {
+            loop_metrics.set_in_flight(send_futures.len());
+
             select! {
                 _ = &mut timeout => {
-                    info!("Timeout hit; sending log file {temp_path:?} to S3");
                     // We've hit the timeout limit. Send the file to S3.
-                    match evaluate_pattern(object_name_pattern, host_id) {
-                        Ok(object_name) => send_futures.push(send_file(file, temp_path, host_id.to_string(), bucket.to_string(), bucket_region.clone(), object_name)),
-                        Err(e) => error!("Unable to generate object name for S3: {e}"),
+                    let upload = rotate_batch(file, temp_path, &current, &variables, stats, "max_duration", uploaded());
+                    if let Some(upload) = upload {
+                        send_futures.push(upload);
                     }
                     break;
                 }
 
                 read_result = reader.read(&mut buf) => {
                     // Incoming bytes from stdin/FIFO.
-                    let (flush_required, bad_reader) = match read_result {
+                    let (flush_reason, bad_reader) = match read_result {
                         Ok(0) => {
                             // Input stream is closed
                             debug!("No data returned; assuming input stream has closed");
-                            (true, true)
+                            (Some("end_of_input"), true)
                         }
                         Ok(n_read) => {
                             // Write the bytes to the temporary file
-                            match file.write_all(&buf[0..n_read]).await {
+                            match write_input(&mut file, &buf[0..n_read], acks.as_ref()).await {
                                 Ok(()) => {
+                                    loop_metrics.written(&buf[0..n_read]);
+                                    stats.written(&buf[0..n_read]);
                                     if current_size == 0 {
                                         // First byte written. Start the timer.
                                         timeout = MaybeTimeout::sleep(max_duration);
@@ -320,32 +1675,34 @@ async fn run<R: AsyncRead>(
                                         last_reported_size = current_size;
                                     }
 
-                                    (current_size >= max_size, false)
+                                    ((current_size >= max_size).then_some("max_size"), false)
                                 }
                                 Err(e) => {
                                     // Yikes! We've failed to write to the temp file -- data loss has occurred.
                                     error!("Failed to write {n_read:?} bytes to {temp_path:?}: {e:?}");
                                     error!("Forcing flush of file to S3");
-                                    (true, false)
+                                    (Some("write_error"), false)
                                 }
                             }
                         }
                         Err(e) => {
                             // Incoming stream has shut down.
                             info!("Incoming stream has shut down: {e:?}");
-                            (true, true)
+                            (Some("end_of_input"), true)
                         }
                     };
 
-                    if flush_required {
-                        info!("Size limit hit (or stream shutdown); sending log file {temp_path:?} to S3");
-                        // We need to flush to S3 -- either we're full or an issue occurred.
-                        match evaluate_pattern(object_name_pattern, host_id) {
-                            Ok(object_name) => {
-                                send_futures.push(send_file(file, temp_path, host_id.to_string(), bucket.to_string(), bucket_region.clone(), object_name));
-                            }
-                            Err(e) => error!("Unable to generate object name for S3: {e}"),
-                        };
+                    if let Some(reason) = flush_reason {
+                        if current_size == 0 {
+                            // The stream closed before anything was written to this batch; there's nothing to send.
+                            debug!("Discarding empty log file {temp_path:?}");
+                        } else if let Some(upload) =
+                            rotate_batch(file, temp_path, &current, &variables, stats, reason, uploaded())
+                        {
+                            // We need to flush to S3 -- either we're full or an issue occurred.
+                            send_futures.push(upload);
+                        }
+
                         if bad_reader {
                             break 'outer;
                         }
@@ -353,158 +1710,896 @@ async fn run<R: AsyncRead>(
                     }
                 }
 
+                result = settings.changed(), if watching => {
+                    if result.is_err() {
+                        // The settings can no longer change.
+                        watching = false;
+                        continue;
+                    }
+
+                    if current_size > 0
+                        && let Some(upload) = rotate_batch(
+                            file, temp_path, &current, &variables, stats, "settings_changed", uploaded(),
+                        )
+                    {
+                        send_futures.push(upload);
+                    }
+                    break;
+                }
+
                 result = send_futures.next() => {
                     debug!("send_future: {result:?}");
                     // One of the S3 jobs has completed.
                     match result {
-                        Some((path, object_name, result)) => debug!(
-                            "File {path:?} -> s3://{bucket}/{object_name}: {result:?}"),
+                        Some((path, destination, result)) => debug!("File {path:?} -> {destination}: {result:?}"),
                         None => debug!("Busy wait on send_futures"),
                     }
                 }
             }
-        }
-    }
+        }
+    }
+
+    // Drain any upload tasks.
+    while send_futures.len() > 0 {
+        match send_futures.next().await {
+            Some((path, destination, result)) => {
+                debug!("File {path:?} -> {destination}: {result:?}");
+            }
+            None => debug!("Busy wait on send_futures"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Write input to a batch's file. If any producers are waiting to hear that their input is on disk, the file is synced
+/// and they're told.
+async fn write_input(file: &mut MaybeCompressedFile, data: &[u8], acks: Option<&PendingAcks>) -> std::io::Result<()> {
+    file.write_all(data).await?;
+    let synced = acks.map(PendingAcks::take_synced).unwrap_or_default();
+    if !synced.is_empty() {
+        file.sync_data().await?;
+        for ack in synced {
+            // The producer may have given up waiting; that's fine.
+            let _ = ack.send(());
+        }
+    }
+    Ok(())
+}
+
+/// Close a batch for `reason` (e.g. `max_size`) and start sending it to each target under `settings`. Returns `None`,
+/// dropping the batch, if the object names can't be generated. The `uploaded` acknowledgements are sent once the batch
+/// has been sent, or with `false` if it couldn't be and its temp file was kept; if it's dropped, so are they.
+fn rotate_batch(
+    file: MaybeCompressedFile,
+    temp_path: TempPath,
+    settings: &Arc<BatchSettings>,
+    variables: &TemplateVars,
+    stats: BatchStats,
+    reason: &'static str,
+    uploaded: Vec<oneshot::Sender<bool>>,
+) -> Option<impl Future<Output = (OsString, String, AnyResult<()>)> + use<>> {
+    let now = OffsetDateTime::now_utc();
+    let patterns = settings.targets.iter().chain(&settings.fallbacks).map(|target| target.object_name_pattern.as_str());
+    let object_names = match evaluate_patterns(patterns, &settings.host_id, variables, now) {
+        Ok(object_names) => object_names,
+        Err(e) => {
+            error!("Unable to generate object name for S3: {e}");
+            return None;
+        }
+    };
+
+    // The batch belongs to the manifest window it was closed in. Manifests are written to the main destination.
+    let window = match (&settings.manifest, &settings.targets[0].destination) {
+        (Some(pattern), Destination::S3(target)) => {
+            match ManifestWindow::at(pattern, &settings.host_id, &target.bucket, &target.s3, now) {
+                Ok(mut window) => {
+                    window.acl = settings.acl.clone();
+                    window.expected_bucket_owner = settings.expected_bucket_owner.clone();
+                    window.server_side_encryption = target.server_side_encryption.clone();
+                    window.sse_kms_key_id = target.sse_kms_key_id.clone();
+                    settings.manifests.upload_started(&window);
+                    Some(window)
+                }
+                Err(e) => {
+                    error!("Unable to generate manifest name for S3: {e}");
+                    None
+                }
+            }
+        }
+        // Validation only allows manifests for S3 destinations.
+        _ => None,
+    };
+
+    let bytes = stats.raw_bytes;
+    let urls: Vec<_> = settings.targets.iter().zip(&object_names).map(|(t, name)| t.destination.url(name)).collect();
+    info!(
+        event = "batch_rotated",
+        pipeline = settings.name.as_str(),
+        reason,
+        bucket = settings.targets[0].destination.bucket(),
+        key = object_names[0].as_str(),
+        bytes,
+        path:? = temp_path;
+        "Sending log file {temp_path:?} ({bytes} bytes, {reason}) to {}",
+        urls.join(", ")
+    );
+    let upload = send_file(file, temp_path, settings.clone(), object_names, stats, window);
+    Some(async move {
+        let sent = upload.await;
+        for ack in uploaded {
+            // The producer may have gone away; that's fine.
+            let _ = ack.send(sent.2.is_ok());
+        }
+        sent
+    })
+}
+
+/// A batching loop started by [`run_partitioned`] for one combination of template variables.
+struct Partition {
+    sender: Sender<Vec<u8>>,
+    last_record: Instant,
+}
+
+/// Route records that carry their own template variables (e.g. the syslog facility) into separate batches. Each
+/// distinct combination of the variables referenced by the object name pattern gets its own batching loop, so records
+/// are never written under another record's key. If a settings change alters which variables the pattern references,
+/// every loop is closed and new ones are started as records arrive. Returns when the channel is closed and every loop
+/// has finished.
+///
+/// A loop that hasn't had a record for the pipeline's maximum duration is closed, sending its last batch. Once
+/// `max_partitions` loops are running, records that would start another go to a catch-all loop for their
+/// [`overflow_vars`] instead, so senders can't start an unbounded number of loops.
+async fn run_partitioned(
+    mut records: Receiver<(TemplateVars, Vec<u8>)>,
+    mut settings: SettingsWatch,
+    max_partitions: usize,
+) -> AnyResult<()> {
+    let mut referenced = settings.borrow_and_update().referenced_variables()?;
+    let mut partitions: HashMap<TemplateVars, Partition> = HashMap::new();
+    let mut loops = TaskQueue::new();
+    let mut watching = true;
+    let mut next_sweep = Instant::now() + settings.borrow().max_duration;
+
+    loop {
+        select! {
+            record = records.recv() => {
+                let Some((variables, data)) = record else {
+                    break;
+                };
+
+                let mut key: TemplateVars =
+                    variables.into_iter().filter(|(name, _)| referenced.iter().any(|r| r == name)).collect();
+                if partitions.len() >= max_partitions && !partitions.contains_key(&key) {
+                    key = overflow_vars(key);
+                }
+
+                let partition = partitions.entry(key).or_insert_with_key(|key| {
+                    debug!("Starting batching loop for {key:?}");
+                    let (tx, rx) = channel(INPUT_CHANNEL_SIZE);
+                    loops.push(tokio::spawn(run(ChannelReader::new(rx), settings.clone(), key.clone(), None)));
+                    Partition {
+                        sender: tx,
+                        last_record: Instant::now(),
+                    }
+                });
+                partition.last_record = Instant::now();
+
+                if partition.sender.send(data).await.is_err() {
+                    error!("Batching loop has exited; dropping record");
+                }
+            }
+
+            _ = tokio::time::sleep_until(next_sweep.into()) => {
+                // Close the loops that have gone idle; each sends its last batch and exits.
+                let max_duration = settings.borrow().max_duration;
+                partitions.retain(|key, partition| {
+                    let active = partition.last_record.elapsed() < max_duration;
+                    if !active {
+                        debug!("Closing idle batching loop for {key:?}");
+                    }
+                    active
+                });
+                next_sweep = Instant::now() + max_duration;
+            }
+
+            Some(result) = loops.next() => {
+                if let Err(e) = result? {
+                    error!("Batching loop failed: {e}");
+                }
+            }
+
+            result = settings.changed(), if watching => {
+                if result.is_err() {
+                    watching = false;
+                    continue;
+                }
+
+                let now_referenced = settings.borrow_and_update().referenced_variables()?;
+                if now_referenced != referenced {
+                    debug!(
+                        "Template variables changed from {referenced:?} to {now_referenced:?}; restarting batching loops"
+                    );
+                    partitions.clear();
+                    referenced = now_referenced;
+                }
+            }
+        }
+    }
+
+    // Close every partition's input and wait for its final batch to be sent.
+    drop(partitions);
+    while loops.len() > 0 {
+        if let Some(Err(e)) = loops.next().await.transpose()? {
+            error!("Batching loop failed: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a temporary file to each of its targets.
+/// This is a wrapper that records the path and destination URL for the return value so the main routine can log it.
+/// The upload waits for a slot in the shared upload budget before starting. Each copy is recorded in the ledger, if
+/// there is one, and the main destination's in its manifest `window`, if it has one. If fewer targets than the
+/// pipeline requires receive the batch, the temp file is kept rather than deleted. `object_names` holds the name for
+/// each target followed by the name for each fallback.
+async fn send_file(
+    file: MaybeCompressedFile,
+    path: TempPath,
+    settings: Arc<BatchSettings>,
+    object_names: Vec<String>,
+    stats: BatchStats,
+    mut window: Option<ManifestWindow>,
+) -> (OsString, String, AnyResult<()>) {
+    let os_path = path.as_os_str().to_os_string();
+    let urls: Vec<_> = settings.targets.iter().zip(&object_names).map(|(t, name)| t.destination.url(name)).collect();
+    let destination = urls.join(", ");
+
+    // Lock the temp file so another process sharing the temp directory doesn't resume its uploads while they're live.
+    let _lock = match SpoolLock::try_acquire(&path).await {
+        Ok(lock) => lock,
+        Err(e) => {
+            warn!("Unable to lock {os_path:?}; another process may take over its uploads: {e}");
+            None
+        }
+    };
+    let bucket = settings.targets[0].destination.bucket();
+    let mut upload_metrics = UploadMetrics::new(&settings.name, bucket, path.to_path_buf());
+    let mut started = Instant::now();
+    let failed = |result| {
+        vec![Delivery {
+            target: &settings.targets[0],
+            object_name: &object_names[0],
+            main: true,
+            result,
+        }]
+    };
+    let deliveries = match settings.upload_slots.acquire().await {
+        Ok(_permit) => {
+            upload_metrics.started();
+            started = Instant::now();
+            let hash = settings.ledger.is_some() || window.is_some();
+            let sync =
+                settings.targets.iter().chain(&settings.fallbacks).any(|t| matches!(t.destination, Destination::File));
+            match close_batch(file, &path, hash, sync).await {
+                Ok(batch) => {
+                    let (path, settings, batch, stats, upload_metrics) =
+                        (&*path, &*settings, &batch, &stats, &upload_metrics);
+                    let (object_names, window) = (&object_names, window.as_ref());
+                    let uploads = settings.targets.iter().zip(object_names).enumerate().map(
+                        |(index, (target, name))| async move {
+                            if index == 0 && !settings.fallbacks.is_empty() {
+                                return send_main(path, settings, object_names, window, batch, stats, upload_metrics)
+                                    .await;
+                            }
+                            let window = window.filter(|_| index == 0);
+                            let result =
+                                do_send_file(path, settings, index, target, name, window, batch, stats, upload_metrics)
+                                    .await;
+                            vec![Delivery {
+                                target,
+                                object_name: name,
+                                main: index == 0,
+                                result,
+                            }]
+                        },
+                    );
+                    join_all(uploads).await.into_iter().flatten().collect()
+                }
+                Err(e) => failed(Err(e)),
+            }
+        }
+        Err(e) => failed(Err(e.into())),
+    };
+
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let pipeline = settings.name.as_str();
+    let mut delivered = 0;
+    let mut first_error = None;
+    for delivery in deliveries {
+        let target = delivery.target;
+        let (bucket, key) = (target.destination.bucket(), delivery.object_name);
+        let window = window.take_if(|_| delivery.main);
+        match delivery.result {
+            Ok(sent) => {
+                let (key, bytes) = (sent.key.as_str(), sent.size);
+                let url = target.destination.url(key);
+                info!(
+                    event = "upload_succeeded", pipeline, bucket, key, bytes, duration_ms;
+                    "Uploaded {os_path:?} ({bytes} bytes) to {url} in {duration_ms:.0} ms"
+                );
+                let record = LedgerRecord {
+                    pipeline: pipeline.to_string(),
+                    host_id: settings.host_id.clone(),
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                    etag: sent.e_tag,
+                    version_id: sent.version_id,
+                    raw_bytes: stats.raw_bytes,
+                    compressed_bytes: settings.compress.then_some(sent.size),
+                    lines: stats.lines,
+                    sha256: sent.sha256.unwrap_or_default(),
+                    first_byte_at: stats.first_byte_at.map(LedgerRecord::timestamp),
+                    last_byte_at: stats.last_byte_at.map(LedgerRecord::timestamp),
+                    uploaded_at: LedgerRecord::timestamp(SystemTime::now()),
+                };
+                if let Some(window) = window {
+                    settings.manifests.upload_finished(window, Some(ManifestObject::from(&record)));
+                }
+                if let Some(ledger) = &settings.ledger {
+                    ledger.record(record);
+                }
+                delivered += 1;
+            }
+            Err(e) => {
+                let url = target.destination.url(key);
+                let error_class = metrics::error_class(&e);
+                error!(
+                    event = "upload_failed", pipeline, bucket, key, duration_ms, error_class = error_class.as_str(),
+                    error:% = e;
+                    "Failed to upload {os_path:?} to {url}: {e}"
+                );
+                if let Some(window) = window {
+                    settings.manifests.upload_finished(window, None);
+                }
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    // Batches sent to a fallback aren't in the manifest's bucket.
+    if let Some(window) = window {
+        settings.manifests.upload_finished(window, None);
+    }
+
+    let result = match first_error {
+        Some(e) if delivered < settings.min_targets => {
+            // Keep the batch so it can be recovered by hand.
+            let required = settings.min_targets;
+            match path.keep() {
+                Ok(kept) => error!(
+                    event = "batch_kept", pipeline, delivered, required, path:? = kept;
+                    "{os_path:?} reached {delivered} of the {required} destinations required; keeping it"
+                ),
+                Err(e) => error!("Unable to keep {os_path:?}: {e}"),
+            }
+            Err(e)
+        }
+        _ => Ok(()),
+    };
+    upload_metrics.finished(&result);
+    (os_path, destination, result)
+}
+
+/// The outcome of sending a batch to a target or fallback.
+struct Delivery<'a> {
+    target: &'a Target,
+    object_name: &'a str,
+
+    /// Whether this is the pipeline's main destination, whose objects are listed in its manifests.
+    main: bool,
+    result: AnyResult<SentObject>,
+}
+
+/// Send a batch to the main target or, while that is unavailable, to the first of the fallbacks that takes it. Only the
+/// main target's copy belongs to the manifest `window`.
+async fn send_main<'a>(
+    path: &Path,
+    settings: &'a BatchSettings,
+    object_names: &'a [String],
+    window: Option<&ManifestWindow>,
+    batch: &ClosedBatch,
+    stats: &BatchStats,
+    upload_metrics: &UploadMetrics,
+) -> Vec<Delivery<'a>> {
+    let mut deliveries = Vec::new();
+    if settings.fallback.use_main() {
+        let (target, object_name) = (&settings.targets[0], object_names[0].as_str());
+        let result = do_send_file(path, settings, 0, target, object_name, window, batch, stats, upload_metrics).await;
+        let use_fallback = match &result {
+            Ok(_) => {
+                settings.fallback.main_succeeded();
+                false
+            }
+            Err(e) => settings.fallback.main_failed(&metrics::error_class(e)),
+        };
+        deliveries.push(Delivery {
+            target,
+            object_name,
+            main: true,
+            result,
+        });
+        if !use_fallback {
+            return deliveries;
+        }
+    }
+
+    // Fallbacks' multipart upload state is saved after the targets'.
+    let fallbacks = settings.fallbacks.iter().zip(&object_names[settings.targets.len()..]);
+    for (index, (target, object_name)) in fallbacks.enumerate() {
+        let index = settings.targets.len() + index;
+        let result = do_send_file(path, settings, index, target, object_name, None, batch, stats, upload_metrics).await;
+        let sent = result.is_ok();
+        deliveries.push(Delivery {
+            target,
+            object_name,
+            main: false,
+            result,
+        });
+        if sent {
+            break;
+        }
+    }
+    deliveries
+}
+
+/// What we know about an object once it has been uploaded.
+#[derive(Debug, Default)]
+struct SentObject {
+    /// The key the object was written to. With `--no-overwrite`, this may not be the key we started with.
+    key: String,
+
+    /// The size of the object as stored.
+    size: u64,
+
+    /// The SHA-256 of the object, in hex, if it was asked for.
+    sha256: Option<String>,
+    e_tag: Option<String>,
+    version_id: Option<String>,
+}
+
+/// A batch that has been closed, ready to be sent to its targets.
+struct ClosedBatch {
+    size: u64,
+
+    /// The SHA-256 of the file, in hex, if it was asked for.
+    sha256: Option<String>,
+
+    /// The checksums of the file's parts, with `--checksum-algorithm`.
+    checksums: Option<ObjectChecksums>,
+}
+
+/// Finish writing a batch's temp file. If `hash` is set, the file is hashed; if `sync` is set, it's flushed to disk.
+async fn close_batch(mut file: MaybeCompressedFile, path: &Path, hash: bool, sync: bool) -> AnyResult<ClosedBatch> {
+    // Stop writing to the file. If this is a compressed file, this will flush out any remaining bytes stored by the
+    // compression encoder.
+    file.shutdown().await?;
+
+    // Get the raw file and the checksums of what was written to it.
+    let (mut file, checksums) = match file {
+        MaybeCompressedFile::Gzip(gz) => gz.into_inner(),
+        MaybeCompressedFile::Uncompressed(f) => f,
+    }
+    .into_parts();
+
+    // Determine the actual file size.
+    let size = match file.seek(SeekFrom::End(0)).await {
+        Ok(size) => size,
+        Err(e) => {
+            error!("Unable to seek to end-of-file on {path:?}: {e:?}");
+            return Err(e.into());
+        }
+    };
+
+    // Go back to the beginning.
+    match file.seek(SeekFrom::Start(0)).await {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Unable to seek to start-of-file on {path:?}: {e:?}");
+            return Err(e.into());
+        }
+    }
+
+    let sha256 = if hash {
+        let sha256 = ledger::sha256_file(&mut file).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        Some(sha256)
+    } else {
+        None
+    };
+
+    // Make sure the batch is on disk before it's renamed into place at a file destination.
+    if sync {
+        file.sync_all().await?;
+    }
+
+    Ok(ClosedBatch {
+        size,
+        sha256,
+        checksums,
+    })
+}
+
+/// Write a closed temporary file to one of its targets, the one at `index`.
+/// This is the main guts, returning just the result. If the pipeline verifies uploads, the object is checked against
+/// the file before the file is deleted. `window` is the manifest window the object belongs to, if it's listed in one.
+#[allow(clippy::too_many_arguments)]
+async fn do_send_file(
+    path: &Path,
+    settings: &BatchSettings,
+    index: usize,
+    target: &Target,
+    object_name: &str,
+    window: Option<&ManifestWindow>,
+    batch: &ClosedBatch,
+    stats: &BatchStats,
+    upload_metrics: &UploadMetrics,
+) -> AnyResult<SentObject> {
+    let (key, e_tag, version_id) = match &target.destination {
+        Destination::S3(s3_target) => {
+            upload_to_s3(path, settings, index, s3_target, object_name, window, batch, stats, upload_metrics).await?
+        }
+        Destination::Azure(azure_target) => {
+            let (key, e_tag, version_id) = azure::write_batch(
+                azure_target,
+                path,
+                object_name,
+                batch.size,
+                &settings.host_id,
+                settings.no_overwrite,
+            )
+            .await?;
+            if settings.verify
+                && let Err(e) = azure::verify_batch(azure_target, &key, batch.size).await
+            {
+                if e.is::<VerificationFailed>() {
+                    error!("Batch written to {} is damaged: {e}", target.destination.url(&key));
+                    upload_metrics.verification_failed();
+                }
+                return Err(e);
+            }
+            (key, e_tag, version_id)
+        }
+        Destination::Gcs(gcs_target) => {
+            let (key, e_tag, generation) =
+                gcs::write_batch(gcs_target, path, object_name, batch.size, &settings.host_id, settings.no_overwrite)
+                    .await?;
+            if settings.verify
+                && let Err(e) = gcs::verify_batch(gcs_target, &key, batch.size).await
+            {
+                if e.is::<VerificationFailed>() {
+                    error!("Batch written to {} is damaged: {e}", target.destination.url(&key));
+                    upload_metrics.verification_failed();
+                }
+                return Err(e);
+            }
+            (key, e_tag, generation)
+        }
+        Destination::File => {
+            let key = local::write_batch(path, object_name, settings.no_overwrite).await?;
+            if settings.verify
+                && let Err(e) = local::verify_batch(&key, batch.size).await
+            {
+                if e.is::<VerificationFailed>() {
+                    error!("Batch written to {key:?} is damaged: {e}");
+                    upload_metrics.verification_failed();
+                }
+                return Err(e);
+            }
+            (key, None, None)
+        }
+    };
+
+    Ok(SentObject {
+        key,
+        size: batch.size,
+        sha256: batch.sha256.clone(),
+        e_tag: e_tag.map(|e_tag| e_tag.trim_matches('"').to_string()),
+        version_id,
+    })
+}
+
+/// Upload a temporary file to S3, retrying if it's damaged on the way and, with `--no-overwrite`, renaming it if the
+/// key is taken. Returns the key it was written to, its ETag and its version id.
+#[allow(clippy::too_many_arguments)]
+async fn upload_to_s3(
+    path: &Path,
+    settings: &BatchSettings,
+    index: usize,
+    target: &S3Target,
+    object_name: &str,
+    window: Option<&ManifestWindow>,
+    batch: &ClosedBatch,
+    stats: &BatchStats,
+    upload_metrics: &UploadMetrics,
+) -> AnyResult<(String, Option<String>, Option<String>)> {
+    let S3Target {
+        bucket,
+        s3,
+        ..
+    } = target;
+    let (size, checksums) = (batch.size, batch.checksums.as_ref());
+    let host_id = &settings.host_id;
+    let multipart = size > MAX_PART_SIZE;
+    let mut attempt = 1;
+    let mut renames = 0;
+    let object_name_base = object_name;
+    let mut object_name = object_name.to_string();
+    let (e_tag, version_id) = loop {
+        // Do we need to do a multi-part upload?
+        let result = if !multipart {
+            // No, keep it simple.
+            send_file_single(size, path, settings, target, &object_name, checksums).await
+        } else {
+            // Yep -- do the complexity needed by S3 here.
+            let state = MultipartState {
+                pipeline: settings.name.clone(),
+                bucket: bucket.clone(),
+                key: object_name.clone(),
+                upload_id: String::new(),
+                size,
+                part_size: MAX_PART_SIZE,
+                compressed: settings.compress,
+                checksum_algorithm: checksums.map(|c| c.algorithm),
+                no_overwrite: settings.no_overwrite,
+                acl: settings.acl.as_ref().map(|acl| acl.as_str().to_string()),
+                expected_bucket_owner: settings.expected_bucket_owner.clone(),
+                bucket_region: target.bucket_region.clone(),
+                destination_index: index,
+                target_count: settings.targets.len(),
+                verify: settings.verify,
+                manifest_key: window.map(|window| window.key.clone()),
+                manifest_pattern: window.map(|window| window.pattern.clone()),
+                server_side_encryption: match &target.server_side_encryption {
+                    ServerSideEncryption::Aes256 => None,
+                    sse => Some(sse.as_str().to_string()),
+                },
+                sse_kms_key_id: target.sse_kms_key_id.clone(),
+                storage_class: target.storage_class.as_ref().map(|class| class.as_str().to_string()),
+                stats: stats.clone(),
+                parts: Vec::new(),
+            };
+            send_file_multi(path, host_id.clone(), s3.clone(), checksums, state).await
+        };
+
+        // Make sure S3 has what we sent while we still have the temp file to send again.
+        let result = match result {
+            Ok((e_tag, version_id)) if settings.verify => {
+                let version_id_ref = version_id.as_deref();
+                let owner = settings.expected_bucket_owner.as_deref();
+                let verified =
+                    verify_upload(s3, bucket, owner, &object_name, version_id_ref, size, multipart, checksums);
+                match verified.await {
+                    Ok(()) => Ok((e_tag, version_id)),
+                    Err(e) => {
+                        if e.is::<VerificationFailed>() {
+                            error!("Uploaded object s3://{bucket}/{object_name} does not match {path:?}: {e}");
+                            upload_metrics.verification_failed();
+                            if settings.no_overwrite {
+                                let owner = settings.expected_bucket_owner.clone();
+                                delete_damaged_object(s3, bucket, &object_name, version_id_ref, owner).await;
+                            }
+                        }
+                        Err(e)
+                    }
+                }
+            }
+            result => result,
+        };
 
-    // Drain any upload tasks.
-    while send_futures.len() > 0 {
-        match send_futures.next().await {
-            Some((path, object_name, result)) => {
-                debug!("File {path:?} -> s3://{bucket}/{object_name}: {result:?}");
+        match result {
+            // Someone else has written to this key. Pick another one rather than overwrite their object.
+            Err(e)
+                if settings.no_overwrite
+                    && renames < MAX_KEY_RENAMES
+                    && metrics::error_class(&e) == "PreconditionFailed" =>
+            {
+                renames += 1;
+                let key = disambiguate_key(object_name_base, renames);
+                warn!(
+                    event = "object_exists", bucket = bucket.as_str(), key = object_name.as_str(),
+                    new_key = key.as_str();
+                    "s3://{bucket}/{object_name} already exists; uploading {path:?} to s3://{bucket}/{key} instead"
+                );
+                object_name = key;
             }
-            None => debug!("Busy wait on send_futures"),
-        }
-    }
 
-    Ok(())
-}
+            // The data was damaged on its way to S3. Send it again from the temp file.
+            Err(e)
+                if attempt < MAX_UPLOAD_ATTEMPTS
+                    && matches!(
+                        metrics::error_class(&e).as_str(),
+                        "checksum_mismatch" | "BadDigest" | "verification_mismatch"
+                    ) =>
+            {
+                warn!(
+                    event = "upload_retried", bucket = bucket.as_str(), key = object_name.as_str(), attempt;
+                    "Upload of {path:?} to s3://{bucket}/{object_name} failed its integrity check; retrying: {e}"
+                );
+                attempt += 1;
+            }
+            result => break result?,
+        }
+    };
 
-/// Write a temporary file to S3.
-/// This is a wrapper that records the path and object name for the return value so the main routine can log it.
-async fn send_file(
-    file: MaybeCompressedFile,
-    path: TempPath,
-    host_id: String,
-    bucket: String,
-    bucket_region: Region,
-    object_name: String,
-) -> (OsString, String, AnyResult<()>) {
-    (
-        path.as_os_str().to_os_string(),
-        object_name.clone(),
-        do_send_file(file, path, host_id, bucket, bucket_region, object_name).await,
-    )
+    Ok((object_name, e_tag, version_id))
 }
 
-/// Write a temporary file to S3.
-/// This is the main guts, returning just the result.
-async fn do_send_file(
-    mut file: MaybeCompressedFile,
-    path: TempPath,
-    host_id: String,
-    bucket: String,
-    bucket_region: Region,
-    object_name: String,
+/// Check an uploaded object with HeadObject: its size, and its checksum if we computed one and S3 reports it. If the
+/// bucket is versioned, the version we uploaded is checked.
+#[allow(clippy::too_many_arguments)]
+async fn verify_upload(
+    s3: &aws_sdk_s3::Client,
+    bucket: &str,
+    expected_bucket_owner: Option<&str>,
+    object_name: &str,
+    version_id: Option<&str>,
+    size: u64,
+    multipart: bool,
+    checksums: Option<&ObjectChecksums>,
 ) -> AnyResult<()> {
-    // Stop writing to the file. If this is a compressed file, this will flush out any remaining bytes stored by the
-    // compression encoder.
-    file.shutdown().await?;
-
-    // Get the raw file.
-    let mut file = match file {
-        MaybeCompressedFile::Gzip(gz) => gz.into_inner(),
-        MaybeCompressedFile::Uncompressed(f) => f,
-    };
-
-    // Determine the actual file size.
-    let size = match file.seek(SeekFrom::End(0)).await {
-        Ok(size) => size,
-        Err(e) => {
-            error!("Unable to seek to end-of-file on {path:?}: {e:?}");
-            return Err(e.into());
+    let mut request = s3
+        .head_object()
+        .bucket(bucket)
+        .key(object_name)
+        .set_version_id(version_id.map(str::to_string))
+        .set_expected_bucket_owner(expected_bucket_owner.map(str::to_string));
+    if checksums.is_some() {
+        request = request.checksum_mode(ChecksumMode::Enabled);
+    }
+    let output = request.send().await.map_err(S3RequestError::new)?;
+
+    let actual_size = output.content_length().unwrap_or_default();
+    if actual_size != size as i64 {
+        return Err(VerificationFailed {
+            what: "size",
+            expected: size.to_string(),
+            actual: actual_size.to_string(),
         }
-    };
+        .into());
+    }
 
-    // Go back to the beginning.
-    match file.seek(SeekFrom::Start(0)).await {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Unable to seek to start-of-file on {path:?}: {e:?}");
-            return Err(e.into());
+    if let Some(checksums) = checksums
+        && let Err(e) = checksums.verify(multipart, returned_checksum!(output, checksums.algorithm))
+    {
+        return Err(VerificationFailed {
+            what: "checksum",
+            expected: e.expected,
+            actual: e.actual,
         }
+        .into());
     }
 
-    // Do we need to do a multi-part upload?
-    if size <= MAX_PART_SIZE {
-        // No, keep it simple.
-        send_file_single(file, size, path, host_id, bucket, bucket_region, object_name).await
-    } else {
-        // Yep -- do the complexity needed by S3 here.
-        send_file_multi(file, size, path, host_id, bucket, bucket_region, object_name).await
+    debug!("Verified s3://{bucket}/{object_name}: {size} bytes");
+    Ok(())
+}
+
+/// Delete an object we wrote that doesn't match what we sent, so that with `--no-overwrite` the next attempt can write
+/// to the same key instead of leaving it behind under another. If the bucket is versioned, only our version is deleted.
+async fn delete_damaged_object(
+    s3: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+    version_id: Option<&str>,
+    expected_bucket_owner: Option<String>,
+) {
+    let result = s3
+        .delete_object()
+        .bucket(bucket)
+        .key(key)
+        .set_version_id(version_id.map(str::to_string))
+        .set_expected_bucket_owner(expected_bucket_owner)
+        .send()
+        .await;
+    match result {
+        Ok(_) => warn!(event = "object_deleted", bucket, key; "Deleted damaged object s3://{bucket}/{key}"),
+        Err(e) => error!("Failed to delete damaged object s3://{bucket}/{key}: {e:?}"),
     }
 }
 
-/// Upload the temp file to S3 in a single upload, using the PutObject API.
+/// Upload the temp file to S3 in a single upload, using the PutObject API. If `checksums` is given, the checksum is
+/// sent for S3 to check and the one S3 reports is checked against it. With `--no-overwrite`, the upload fails with
+/// `PreconditionFailed` if the object already exists. Returns the ETag and version id.
 async fn send_file_single(
-    file: File,
     size: u64,
-    path: TempPath,
-    host_id: String,
-    bucket: String,
-    bucket_region: Region,
-    object_name: String,
-) -> AnyResult<()> {
-    let config = aws_config::load_from_env().await.to_builder().region(bucket_region.clone()).build();
-    let s3 = aws_sdk_s3::Client::new(&config);
-    let byte_stream = FsBuilder::new().file(file).length(Length::Exact(size)).build().await?;
+    path: &Path,
+    settings: &BatchSettings,
+    target: &S3Target,
+    object_name: &str,
+    checksums: Option<&ObjectChecksums>,
+) -> AnyResult<(Option<String>, Option<String>)> {
+    let host_id = &settings.host_id;
+    let S3Target {
+        bucket,
+        s3,
+        server_side_encryption,
+        sse_kms_key_id,
+        storage_class,
+        ..
+    } = target;
+    let byte_stream = FsBuilder::new().path(path).length(Length::Exact(size)).build().await?;
 
     info!("Performing single upload for {path:?} of size {size:?}");
-    let result = s3.put_object()
-        .bucket(bucket.clone())
+    let mut request = s3.put_object()
+        .bucket(bucket)
         .body(byte_stream)
         .content_length(size as i64)
-        .key(object_name.clone())
-        // XXX -- allow encryption algorithm to be specified.
-        .server_side_encryption(ServerSideEncryption::Aes256)
+        .key(object_name)
+        .server_side_encryption(server_side_encryption.clone())
+        .set_ssekms_key_id(sse_kms_key_id.clone())
+        .set_storage_class(storage_class.clone())
         // XXX -- allow tagging to be specified.
-        .tagging(format!("HostId={host_id}"))
-        .send()
-        .await;
+        .set_tagging(object_tagging(bucket, host_id))
+        .set_acl(settings.acl.clone())
+        .set_expected_bucket_owner(settings.expected_bucket_owner.clone())
+        .set_if_none_match(settings.no_overwrite.then(|| "*".to_string()));
+    if let Some(checksums) = checksums {
+        request = request.checksum_algorithm(checksums.algorithm.as_s3());
+        request = with_checksum!(request, checksums.algorithm, checksums.expected(false));
+    }
+    let result = request.send().await;
 
     match result {
-        Ok(_) => Ok(()),
+        Ok(output) => {
+            if let Some(checksums) = checksums
+                && let Err(e) = checksums.verify(false, returned_checksum!(output, checksums.algorithm))
+            {
+                if settings.no_overwrite {
+                    let owner = settings.expected_bucket_owner.clone();
+                    delete_damaged_object(s3, bucket, object_name, output.version_id(), owner).await;
+                }
+                return Err(e.into());
+            }
+            Ok((output.e_tag, output.version_id))
+        }
         Err(e) => {
             error!("Failed to write to s3://{bucket}/{object_name}: {e:?}");
-            Err(e.into())
+            Err(S3RequestError::new(e).into())
         }
     }
 }
 
-/// Upload the temp file to S3 in multiple parts, using the CreateMultipartUpload API.
+/// Upload the temp file to S3 in multiple parts, using the CreateMultipartUpload API. If `checksums` is given, each
+/// part's checksum is sent for S3 to check and the composite checksum S3 reports is checked against ours. The
+/// upload's progress is saved next to the temp file so it can be resumed if we exit before it finishes. Returns the
+/// ETag and version id.
 async fn send_file_multi(
-    _file: File,
-    size: u64,
-    path: TempPath,
+    path: &Path,
     host_id: String,
-    bucket: String,
-    bucket_region: Region,
-    object_name: String,
-) -> AnyResult<()> {
-    let config = aws_config::load_from_env().await.to_builder().region(bucket_region.clone()).build();
-    let s3 = aws_sdk_s3::Client::new(&config);
-
+    s3: aws_sdk_s3::Client,
+    checksums: Option<&ObjectChecksums>,
+    mut state: MultipartState,
+) -> AnyResult<(Option<String>, Option<String>)> {
+    let (bucket, object_name, size) = (state.bucket.clone(), state.key.clone(), state.size);
     info!("Performing multipart upload for {path:?} of size {size}");
-    let result = s3.create_multipart_upload()
+    let sse = state.server_side_encryption.as_deref().map_or(ServerSideEncryption::Aes256, ServerSideEncryption::from);
+    let mut request = s3.create_multipart_upload()
         .bucket(bucket.clone())
         .key(object_name.clone())
-        // XXX -- allow encryption algorithm to be specified.
-        .server_side_encryption(ServerSideEncryption::Aes256)
+        .server_side_encryption(sse)
+        .set_ssekms_key_id(state.sse_kms_key_id.clone())
+        .set_storage_class(state.storage_class.as_deref().map(StorageClass::from))
         // XXX -- allow tagging to be specified.
-        .tagging(format!("HostId={host_id}"))
-        .send()
-        .await;
+        .set_tagging(object_tagging(&bucket, &host_id))
+        .set_acl(state.acl.as_deref().map(ObjectCannedAcl::from))
+        .set_expected_bucket_owner(state.expected_bucket_owner.clone());
+    if let Some(checksums) = checksums {
+        request = request.checksum_algorithm(checksums.algorithm.as_s3()).checksum_type(ChecksumType::Composite);
+    }
+    let result = request.send().await;
 
-    let upload_id = match result {
+    state.upload_id = match result {
         Ok(resp) => {
             match resp.upload_id {
                 Some(upload_id) => upload_id,
@@ -517,35 +2612,61 @@ async fn send_file_multi(
         }
         Err(e) => {
             error!("Unable to start multipart upload for s3://{bucket}/{object_name}: {e:?}");
-            return Err(e.into());
+            return Err(S3RequestError::new(e).into());
         }
     };
 
+    if let Err(e) = state.save(path).await {
+        warn!("Unable to save multipart upload state for {path:?}; it can't be resumed after a restart: {e}");
+    }
+
+    finish_multipart(path, &s3, &mut state, checksums, Vec::new()).await
+}
+
+/// Upload the parts of a multipart upload that aren't in `done` and complete it, or abort it if that fails. The
+/// upload isn't completed over an existing object if the state says not to overwrite. Each
+/// part S3 acknowledges is added to the state saved next to the temp file; the saved state is removed once the upload
+/// is complete or aborted. Returns the ETag and version id.
+async fn finish_multipart(
+    path: &Path,
+    s3: &aws_sdk_s3::Client,
+    state: &mut MultipartState,
+    checksums: Option<&ObjectChecksums>,
+    done: Vec<CompletedPart>,
+) -> AnyResult<(Option<String>, Option<String>)> {
+    let (bucket, object_name, upload_id, size) =
+        (state.bucket.clone(), state.key.clone(), state.upload_id.clone(), state.size);
     let mut start = 0;
     let mut part_number = 1; // Part numbers start at 1.
     let mut futures = FuturesOrdered::new();
 
     // Create a future for each part we need to upload.
     while start < size {
-        let end = min(start + MAX_PART_SIZE, size);
-        let os_path = path.as_os_str().to_os_string();
-        futures.push_back(send_file_part(
-            os_path,
-            bucket.clone(),
-            bucket_region.clone(),
-            object_name.clone(),
-            upload_id.clone(),
-            part_number,
-            start,
-            end,
-        ));
+        let end = min(start + state.part_size, size);
+        if !done.iter().any(|part| part.part_number == Some(part_number)) {
+            let os_path = path.as_os_str().to_os_string();
+            let checksum = checksums.and_then(|c| Some((c.algorithm, c.part(part_number)?)));
+            futures.push_back(send_file_part(
+                os_path,
+                s3.clone(),
+                bucket.clone(),
+                object_name.clone(),
+                upload_id.clone(),
+                part_number,
+                start,
+                end,
+                checksum,
+                state.expected_bucket_owner.clone(),
+            ));
+        }
 
         start = end;
         part_number += 1;
     }
 
     // We need to save information about the completed uploads for the CompleteMultipartUpload API.
-    let mut completed_parts = Vec::with_capacity((part_number - 1) as usize);
+    let mut completed_parts = done;
+    completed_parts.reserve((part_number - 1) as usize);
 
     // The error saved in case one of the multipart uploads failed.
     let mut saved_error = None;
@@ -555,14 +2676,21 @@ async fn send_file_multi(
         match futures.next().await {
             None => break,
             Some(result) => match result {
-                Ok((part_number, e_tag)) => {
-                    let cp = CompletedPart::builder().part_number(part_number).e_tag(e_tag).build();
+                Ok(cp) => {
+                    state.parts.push(SavedPart {
+                        part_number: cp.part_number.unwrap_or_default(),
+                        e_tag: cp.e_tag.clone().unwrap_or_default(),
+                    });
+                    if let Err(e) = state.save(path).await {
+                        warn!("Unable to save multipart upload state for {path:?}: {e}");
+                    }
                     completed_parts.push(cp);
                 }
                 Err(e) => saved_error = Some(e),
             },
         }
     }
+    completed_parts.sort_by_key(|part| part.part_number);
 
     if saved_error.is_none() {
         // All parts uploaded successfully. Close out the upload.
@@ -574,20 +2702,36 @@ async fn send_file_multi(
             .key(object_name.clone())
             .upload_id(upload_id.clone())
             .multipart_upload(cmu)
+            .set_expected_bucket_owner(state.expected_bucket_owner.clone())
+            .set_if_none_match(state.no_overwrite.then(|| "*".to_string()))
             .send()
             .await;
 
         match result {
-            Ok(_) => {
+            Ok(output) => {
+                state.remove(path).await;
+
+                // The object is complete, so there's nothing to abort if it came out wrong; it is overwritten by the
+                // next attempt, or deleted first if that attempt mustn't overwrite anything.
+                if let Some(checksums) = checksums
+                    && let Err(e) = checksums.verify(true, returned_checksum!(output, checksums.algorithm))
+                {
+                    error!("Multipart upload of s3://{bucket}/{object_name} does not match what we wrote: {e}");
+                    if state.no_overwrite {
+                        let owner = state.expected_bucket_owner.clone();
+                        delete_damaged_object(s3, &bucket, &object_name, output.version_id(), owner).await;
+                    }
+                    return Err(e.into());
+                }
                 debug!("Upload to s3://{bucket}/{object_name} succeeded");
-                return Ok(());
+                return Ok((output.e_tag, output.version_id));
             }
 
             Err(e) => {
                 error!(
                     "Failed to complete multipart upload of s3://{bucket}/{object_name} with upload_id={upload_id}: {e:?}"
                 );
-                saved_error = Some(e.into());
+                saved_error = Some(S3RequestError::new(e).into());
             }
         }
     }
@@ -596,57 +2740,275 @@ async fn send_file_multi(
 
     // Something happened with at least one part or the CompleteMultipartUpload API. Abort the upload so we are not
     // continually charged for the incompleted upload (which, at this point, won't succeed).
-    error!("At least one upload failed; aborting multipart upload");
+    let error_class = metrics::error_class(&saved_error);
+    warn!(
+        event = "multipart_aborted",
+        bucket = bucket.as_str(),
+        key = object_name.as_str(),
+        bytes = size,
+        upload_id = upload_id.as_str(),
+        error_class = error_class.as_str();
+        "At least one upload failed; aborting multipart upload of s3://{bucket}/{object_name}"
+    );
     let result = s3
         .abort_multipart_upload()
         .bucket(bucket.clone())
         .key(object_name.clone())
         .upload_id(upload_id.clone())
+        .set_expected_bucket_owner(state.expected_bucket_owner.clone())
         .send()
         .await;
 
     if let Err(e) = result {
         error!("Failed to delete multipart upload for s3://{bucket}/{object_name}, upload_id={upload_id}: {e:?}");
     }
+    state.remove(path).await;
 
     Err(saved_error)
 }
 
-/// Asynchronous task for uploading a part of a file.
+/// Finish a multipart upload left behind by an earlier run, from its spool file and saved state. Parts S3 still has
+/// with the ETags we saved are kept and the rest are uploaded. If the upload is gone (e.g. it was aborted), the file
+/// is uploaded again from the start. Like any other upload, the object is verified if the pipeline verifies uploads,
+/// and is recorded in the ledger, its manifest `window` and the upload metrics. The spool file is left for the caller
+/// to delete once every upload of it has finished; if S3 can't be asked about the upload, its state is left for the
+/// next run too.
+#[allow(clippy::too_many_arguments)]
+async fn resume_upload(
+    spool: &Path,
+    mut state: MultipartState,
+    host_id: String,
+    s3: aws_sdk_s3::Client,
+    upload_slots: Arc<Semaphore>,
+    ledger: Option<Ledger>,
+    manifests: Manifests,
+    window: Option<ManifestWindow>,
+) -> AnyResult<()> {
+    let mut upload_metrics = UploadMetrics::new(&state.pipeline, &state.bucket, spool.to_path_buf());
+    let result = match upload_slots.acquire().await {
+        Ok(_permit) => {
+            upload_metrics.started();
+            finish_resumed_upload(spool, &mut state, &host_id, s3, &upload_metrics).await
+        }
+        Err(e) => Err(e.into()),
+    };
+
+    // The object is listed by its SHA-256, which is only needed for the ledger or a manifest.
+    let result = match result {
+        Ok((e_tag, version_id)) if ledger.is_some() || window.is_some() => match File::open(spool).await {
+            Ok(mut file) => match ledger::sha256_file(&mut file).await {
+                Ok(sha256) => Ok(Some((e_tag, version_id, sha256))),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        },
+        Ok(_) => Ok(None),
+        Err(e) => Err(e),
+    };
+
+    let record = match &result {
+        Ok(Some((e_tag, version_id, sha256))) => Some(LedgerRecord {
+            pipeline: state.pipeline.clone(),
+            host_id,
+            bucket: state.bucket.clone(),
+            key: state.key.clone(),
+            etag: e_tag.as_ref().map(|e_tag| e_tag.trim_matches('"').to_string()),
+            version_id: version_id.clone(),
+            raw_bytes: state.stats.raw_bytes,
+            compressed_bytes: state.compressed.then_some(state.size),
+            lines: state.stats.lines,
+            sha256: sha256.clone(),
+            first_byte_at: state.stats.first_byte_at.map(LedgerRecord::timestamp),
+            last_byte_at: state.stats.last_byte_at.map(LedgerRecord::timestamp),
+            uploaded_at: LedgerRecord::timestamp(SystemTime::now()),
+        }),
+        _ => None,
+    };
+    if let Some(window) = window {
+        manifests.upload_finished(window, record.as_ref().map(ManifestObject::from));
+    }
+    if let (Some(ledger), Some(record)) = (&ledger, record) {
+        ledger.record(record);
+    }
+
+    let result = result.map(|_| ());
+    upload_metrics.finished(&result);
+    result
+}
+
+/// Upload the parts of a resumed multipart upload that S3 doesn't have and complete it, or upload the spool file again
+/// if S3 no longer has the upload, then verify the object if asked to. Returns the ETag and version id.
+async fn finish_resumed_upload(
+    spool: &Path,
+    state: &mut MultipartState,
+    host_id: &str,
+    s3: aws_sdk_s3::Client,
+    upload_metrics: &UploadMetrics,
+) -> AnyResult<(Option<String>, Option<String>)> {
+    let (bucket, key) = (state.bucket.clone(), state.key.clone());
+    info!(
+        event = "upload_resumed", pipeline = state.pipeline.as_str(), bucket = bucket.as_str(), key = key.as_str(),
+        upload_id = state.upload_id.as_str();
+        "Resuming multipart upload of {spool:?} to s3://{bucket}/{key}"
+    );
+
+    let checksums = match state.checksum_algorithm {
+        Some(algorithm) => Some(spool::checksum_file(spool, algorithm, state.part_size).await?),
+        None => None,
+    };
+    let uploaded = list_uploaded_parts(&s3, state).await?;
+    let started = Instant::now();
+    let result = match uploaded {
+        Some(uploaded) => {
+            state.parts.retain(|part| uploaded.get(&part.part_number) == Some(&part.e_tag));
+            let done = state
+                .parts
+                .iter()
+                .map(|part| {
+                    let completed = CompletedPart::builder().part_number(part.part_number).e_tag(&part.e_tag);
+                    match checksums.as_ref().and_then(|c| Some((c.algorithm, c.part(part.part_number)?))) {
+                        Some((algorithm, value)) => with_checksum!(completed, algorithm, value).build(),
+                        None => completed.build(),
+                    }
+                })
+                .collect::<Vec<_>>();
+            debug!("{} of {} parts of s3://{bucket}/{key} were already uploaded", done.len(), state.part_count());
+            finish_multipart(spool, &s3, state, checksums.as_ref(), done).await
+        }
+        None => {
+            warn!("Multipart upload of s3://{bucket}/{key} no longer exists; uploading {spool:?} again");
+            state.parts.clear();
+            send_file_multi(spool, host_id.to_string(), s3.clone(), checksums.as_ref(), state.clone()).await
+        }
+    };
+
+    // Check the object as a new upload would be checked. The spool file is kept if it doesn't match.
+    let owner = state.expected_bucket_owner.as_deref();
+    let result = match result {
+        Ok((e_tag, version_id)) if state.verify => {
+            let version_id_ref = version_id.as_deref();
+            match verify_upload(&s3, &bucket, owner, &key, version_id_ref, state.size, true, checksums.as_ref()).await {
+                Ok(()) => Ok((e_tag, version_id)),
+                Err(e) => {
+                    if e.is::<VerificationFailed>() {
+                        error!("Uploaded object s3://{bucket}/{key} does not match {spool:?}: {e}");
+                        upload_metrics.verification_failed();
+                        if state.no_overwrite {
+                            delete_damaged_object(&s3, &bucket, &key, version_id_ref, owner.map(str::to_string)).await;
+                        }
+                    }
+                    Err(e)
+                }
+            }
+        }
+        result => result,
+    };
+
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let (pipeline, bytes) = (state.pipeline.as_str(), state.size);
+    match result {
+        Ok(sent) => {
+            info!(
+                event = "upload_succeeded", pipeline, bucket = bucket.as_str(), key = key.as_str(), bytes, duration_ms;
+                "Uploaded {spool:?} ({bytes} bytes) to s3://{bucket}/{key} in {duration_ms:.0} ms"
+            );
+            Ok(sent)
+        }
+        Err(e) => {
+            let error_class = metrics::error_class(&e);
+            error!(
+                event = "upload_failed", pipeline, bucket = bucket.as_str(), key = key.as_str(), duration_ms,
+                error_class = error_class.as_str(), error:% = e;
+                "Failed to resume upload of {spool:?} to s3://{bucket}/{key}: {e}"
+            );
+            Err(e)
+        }
+    }
+}
+
+/// Ask S3 which parts of a multipart upload it has, returning each part's ETag by part number, or `None` if the
+/// upload no longer exists.
+async fn list_uploaded_parts(
+    s3: &aws_sdk_s3::Client,
+    state: &MultipartState,
+) -> AnyResult<Option<HashMap<i32, String>>> {
+    let mut uploaded = HashMap::new();
+    let mut marker = None;
+    loop {
+        let result = s3
+            .list_parts()
+            .bucket(&state.bucket)
+            .key(&state.key)
+            .upload_id(&state.upload_id)
+            .set_expected_bucket_owner(state.expected_bucket_owner.clone())
+            .set_part_number_marker(marker)
+            .send()
+            .await;
+        let output = match result {
+            Ok(output) => output,
+            Err(e) => {
+                let e = S3RequestError::new(e);
+                if e.class == "NoSuchUpload" {
+                    return Ok(None);
+                }
+                return Err(e.into());
+            }
+        };
+
+        for part in output.parts() {
+            if let (Some(part_number), Some(e_tag)) = (part.part_number(), part.e_tag()) {
+                uploaded.insert(part_number, e_tag.to_string());
+            }
+        }
+
+        if !output.is_truncated().unwrap_or_default() {
+            return Ok(Some(uploaded));
+        }
+        marker = output.next_part_number_marker;
+    }
+}
+
+/// Asynchronous task for uploading a part of a file, with its checksum and expected bucket owner if given. Returns
+/// the part's entry for the CompleteMultipartUpload API.
 #[allow(clippy::too_many_arguments)]
 async fn send_file_part(
     path: OsString,
+    s3: aws_sdk_s3::Client,
     bucket: String,
-    bucket_region: Region,
     object_name: String,
     upload_id: String,
     part_number: i32,
     start: u64,
     end: u64,
-) -> AnyResult<(i32, String)> {
+    checksum: Option<(ChecksumAlgorithm, String)>,
+    expected_bucket_owner: Option<String>,
+) -> AnyResult<CompletedPart> {
     let size = end - start;
     debug!("Uploading {path:?} byte range {start} to {end} with upload_id {upload_id}");
 
-    let config = aws_config::load_from_env().await.to_builder().region(bucket_region.clone()).build();
-    let s3 = aws_sdk_s3::Client::new(&config);
     let byte_stream = FsBuilder::new().path(path).offset(start).length(Length::Exact(size)).build().await?;
 
-    let result = s3
+    let mut request = s3
         .upload_part()
         .bucket(bucket.clone())
         .key(object_name.clone())
         .upload_id(upload_id.clone())
         .part_number(part_number)
         .content_length(size as i64)
-        .body(byte_stream)
-        .send()
-        .await;
+        .set_expected_bucket_owner(expected_bucket_owner)
+        .body(byte_stream);
+    let mut part = CompletedPart::builder().part_number(part_number);
+    if let Some((algorithm, value)) = checksum {
+        request = with_checksum!(request.checksum_algorithm(algorithm.as_s3()), algorithm, value.clone());
+        part = with_checksum!(part, algorithm, value);
+    }
+    let result = request.send().await;
 
     match result {
-        Ok(result) => Ok((part_number, result.e_tag.unwrap())),
+        Ok(result) => Ok(part.e_tag(result.e_tag.unwrap()).build()),
         Err(e) => {
             error!("Failed to write to s3://{bucket}/{object_name}: {e:?}");
-            Err(e.into())
+            Err(S3RequestError::new(e).into())
         }
     }
 }
@@ -715,22 +3077,29 @@ fn get_host_id_from_ethernet_ip() -> Option<String> {
     None
 }
 
-/// Parse an S3 URL in the format `s3://bucket/path`. Both `bucket` and `path` must be non-empty.
+/// Parse an S3 URL in the format `s3://bucket/path`. Both `bucket` and `path` must be non-empty. In place of the bucket
+/// name, an access point or multi-region access point ARN can be given, e.g.
+/// `s3://arn:aws:s3:us-west-2:123456789012:accesspoint/logs/path`; the `s3://` can then be left off.
 fn parse_s3_url(s3_url: &str) -> Result<(String, String), InvalidS3URL> {
-    if s3_url.len() < S3_PROTO_PREFIX.len() || !s3_url.starts_with(S3_PROTO_PREFIX) {
+    let bucket_and_prefix = if s3_url.starts_with(ARN_PREFIX) {
+        s3_url
+    } else if let Some(rest) = s3_url.strip_prefix(S3_PROTO_PREFIX) {
+        rest
+    } else {
         return Err(InvalidS3URL::InvalidURLFormat("URL must begin with 's3://'".to_string(), s3_url.to_string()));
-    }
+    };
 
-    let bucket_and_prefix = s3_url.split_at(S3_PROTO_PREFIX.len()).1;
-    let mut parts_iter = bucket_and_prefix.splitn(2, '/');
-    let bucket = match parts_iter.next() {
-        Some(s) => s,
-        None => {
-            return Err(InvalidS3URL::InvalidURLFormat("bucket/path cannot be empty".to_string(), s3_url.to_string()));
-        }
+    let (bucket, object_name_pattern) = if bucket_and_prefix.starts_with(ARN_PREFIX) {
+        split_access_point_arn(bucket_and_prefix).ok_or_else(|| {
+            InvalidS3URL::InvalidURLFormat(
+                "ARN must be an access point ARN, arn:<partition>:s3:<region>:<account>:accesspoint/<name>".to_string(),
+                s3_url.to_string(),
+            )
+        })?
+    } else {
+        bucket_and_prefix.split_once('/').unwrap_or((bucket_and_prefix, ""))
     };
 
-    let object_name_pattern = parts_iter.next().unwrap_or("");
     if bucket.is_empty() {
         Err(InvalidS3URL::InvalidURLFormat("bucket/path cannot be empty".to_string(), s3_url.to_string()))
     } else if object_name_pattern.is_empty() {
@@ -740,26 +3109,154 @@ fn parse_s3_url(s3_url: &str) -> Result<(String, String), InvalidS3URL> {
     }
 }
 
-/// Evaluate an S3 object name, replacing variables enclosed in braces.
+/// What a destination URL names, besides the object name template.
+#[derive(Debug, PartialEq)]
+enum Location {
+    /// An S3 bucket or access point.
+    S3(String),
+
+    /// A container in an Azure storage account.
+    Azure {
+        account: String,
+        container: String,
+    },
+
+    /// A Google Cloud Storage bucket.
+    Gcs(String),
+
+    /// The local filesystem.
+    File,
+}
+
+/// Parse a destination: an S3 URL as for [`parse_s3_url`], `azblob://account/container/path` for Azure Blob Storage,
+/// `gs://bucket/path` for Google Cloud Storage, or `file:///path` for the local filesystem. Returns where it is and
+/// the object name template, which for a file is an absolute path.
+fn parse_destination(url: &str) -> Result<(Location, String), InvalidS3URL> {
+    if let Some(rest) = url.strip_prefix(GCS_PROTO_PREFIX) {
+        let (bucket, pattern) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() || pattern.is_empty() {
+            return Err(InvalidS3URL::InvalidURLFormat("bucket and path cannot be empty".to_string(), url.to_string()));
+        }
+        return Ok((Location::Gcs(bucket.to_string()), pattern.to_string()));
+    }
+
+    if let Some(rest) = url.strip_prefix(AZURE_PROTO_PREFIX) {
+        let mut parts = rest.splitn(3, '/');
+        let (account, container, pattern) = (parts.next(), parts.next(), parts.next());
+        let (Some(account), Some(container), Some(pattern)) = (account, container, pattern) else {
+            return Err(InvalidS3URL::InvalidURLFormat(
+                "URL must be azblob://account/container/path".to_string(),
+                url.to_string(),
+            ));
+        };
+        if account.is_empty() || container.is_empty() || pattern.is_empty() {
+            return Err(InvalidS3URL::InvalidURLFormat(
+                "account, container and path cannot be empty".to_string(),
+                url.to_string(),
+            ));
+        }
+        let location = Location::Azure {
+            account: account.to_string(),
+            container: container.to_string(),
+        };
+        return Ok((location, pattern.to_string()));
+    }
+
+    let Some(path) = url.strip_prefix(FILE_PROTO_PREFIX) else {
+        return parse_s3_url(url).map(|(bucket, pattern)| (Location::S3(bucket), pattern));
+    };
+
+    if !path.starts_with('/') {
+        Err(InvalidS3URL::InvalidURLFormat("file path must be absolute".to_string(), url.to_string()))
+    } else if path.ends_with('/') {
+        Err(InvalidS3URL::InvalidURLFormat("file path cannot be a directory".to_string(), url.to_string()))
+    } else {
+        Ok((Location::File, path.to_string()))
+    }
+}
+
+/// Split an access point ARN followed by an object path into the ARN and the path. The ARN's resource,
+/// `accesspoint/<name>`, contains a slash of its own.
+fn split_access_point_arn(arn_and_path: &str) -> Option<(&str, &str)> {
+    let fields: Vec<&str> = arn_and_path.splitn(6, ':').collect();
+    let [_, partition, "s3", _, account, resource] = fields[..] else {
+        return None;
+    };
+    let name_and_path = resource.strip_prefix("accesspoint/")?;
+    let name = name_and_path.split('/').next().unwrap_or_default();
+    if partition.is_empty() || account.is_empty() || name.is_empty() {
+        return None;
+    }
+
+    let arn_len = arn_and_path.len() - name_and_path.len() + name.len();
+    Some((&arn_and_path[..arn_len], arn_and_path.get(arn_len + 1..).unwrap_or_default()))
+}
+
+/// What the bucket part of an S3 URL names, which decides how we find the region to send its requests to.
+#[derive(Debug, PartialEq)]
+enum BucketKind<'a> {
+    /// A general purpose bucket, or an access point alias. Its region is looked up.
+    Bucket,
+
+    /// An access point ARN, which names its region.
+    AccessPoint(&'a str),
+
+    /// A multi-region access point ARN. Requests are signed with SigV4A for every region, so any region will do.
+    MultiRegionAccessPoint,
+
+    /// An S3 Express One Zone directory bucket, e.g. `logs--usw2-az1--x-s3`. Requests go to a zonal endpoint in the
+    /// client's region, so the region can't be looked up.
+    Directory,
+}
+
+impl<'a> BucketKind<'a> {
+    fn of(bucket: &'a str) -> Self {
+        if bucket.starts_with(ARN_PREFIX) {
+            match bucket.split(':').nth(3) {
+                Some("") | None => Self::MultiRegionAccessPoint,
+                Some(region) => Self::AccessPoint(region),
+            }
+        } else if bucket.ends_with(DIRECTORY_BUCKET_SUFFIX) {
+            Self::Directory
+        } else {
+            Self::Bucket
+        }
+    }
+}
+
+/// The tags for the objects we write to `bucket`, in the URL query form S3 takes. Directory buckets don't support object
+/// tags, so objects written to them aren't tagged.
+fn object_tagging(bucket: &str, host_id: &str) -> Option<String> {
+    (BucketKind::of(bucket) != BucketKind::Directory).then(|| format!("HostId={host_id}"))
+}
+
+/// The template variables available to every object name; inputs may provide more.
+const TEMPLATE_VARIABLES: &[&str] = &["host_id", "year", "month", "day", "hour", "minute", "second", "unique"];
+
+/// Evaluate S3 object names at `now`, replacing variables enclosed in braces. Every name gets the same `{unique}`, so
+/// the copies of a batch written to several destinations can be matched up.
 /// For example, given `host_id = "localhost"`, `"foo {host_id}"` becomes `"foo localhost"`.
 ///
 /// Ideally, we would use a library that provides the runtime equivalent of Rust's `format!` macro, but the
 /// `runtime_fmt`
-fn evaluate_pattern(pattern: &str, host_id: &str) -> Result<String, InvalidS3URL> {
-    let now = OffsetDateTime::now_utc();
+fn evaluate_patterns<'a>(
+    patterns: impl IntoIterator<Item = &'a str>,
+    host_id: &str,
+    extra: &[(&str, String)],
+    now: OffsetDateTime,
+) -> Result<Vec<String>, InvalidS3URL> {
     let mut unique: [u8; 15] = [0; 15];
     fastrand::fill(&mut unique);
-    evaluate_pattern_at(pattern, host_id, now, unique)
+    patterns.into_iter().map(|pattern| evaluate_pattern_at(pattern, host_id, extra, now, unique)).collect()
 }
 
 fn evaluate_pattern_at(
     pattern: &str,
     host_id: &str,
+    extra: &[(&str, String)],
     now: OffsetDateTime,
     unique: [u8; 15],
 ) -> Result<String, InvalidS3URL> {
-    let mut result = Vec::<char>::with_capacity(pattern.len() * 2);
-    let mut p_iter = pattern.chars();
     let mut variables = HashMap::new();
     let unique = base32::encode(
         base32::Alphabet::Rfc4648 {
@@ -776,6 +3273,43 @@ fn evaluate_pattern_at(
     variables.insert("minute", format!("{:02}", now.minute()));
     variables.insert("second", format!("{:02}", now.second()));
     variables.insert("unique", unique);
+    for (name, value) in extra {
+        variables.insert(*name, value.clone());
+    }
+
+    expand_template(pattern, |var_name| variables.get(var_name).cloned())
+}
+
+/// Make an alternative to `key` for `--no-overwrite`: `-n` is added to the last path component, before its
+/// extension(s), so `logs/web1.log.gz` becomes `logs/web1-1.log.gz`.
+fn disambiguate_key(key: &str, n: u32) -> String {
+    mark_key(key, &format!("-{n}"))
+}
+
+/// Add `marker` to the last path component of `key` (or a template), before its extension(s).
+fn mark_key(key: &str, marker: &str) -> String {
+    let name_start = key.rfind('/').map_or(0, |slash| slash + 1);
+    let ext_start = key[name_start..].find('.').map_or(key.len(), |dot| name_start + dot);
+    format!("{}{marker}{}", &key[..ext_start], &key[ext_start..])
+}
+
+/// Return the names of the variables referenced by a template.
+fn template_variable_names(pattern: &str) -> Result<Vec<String>, InvalidS3URL> {
+    let mut names = Vec::new();
+    expand_template(pattern, |var_name| {
+        names.push(var_name.to_string());
+        Some(String::new())
+    })?;
+    Ok(names)
+}
+
+/// Expand the variables enclosed in braces in a template, looking up each variable's value with `lookup`.
+fn expand_template<F>(pattern: &str, mut lookup: F) -> Result<String, InvalidS3URL>
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut result = Vec::<char>::with_capacity(pattern.len() * 2);
+    let mut p_iter = pattern.chars();
 
     while let Some(c) = p_iter.next() {
         // Is this the start of a brace?
@@ -801,7 +3335,7 @@ fn evaluate_pattern_at(
 
                 let var_name_untrimmed = var_name.into_iter().collect::<String>();
                 let var_name = var_name_untrimmed.trim();
-                let repl = match variables.get(var_name) {
+                let repl = match lookup(var_name) {
                     Some(r) => r,
                     None => {
                         return Err(InvalidS3URL::InvalidTemplateSyntax(format!(
@@ -869,6 +3403,7 @@ mod test {
             crate::evaluate_pattern_at(
                 "test {host_id} {year}-{month}-{day}T{hour}:{minute}:{second}Z {unique}",
                 host_id,
+                &[],
                 now,
                 unique
             )
@@ -880,6 +3415,7 @@ mod test {
             crate::evaluate_pattern_at(
                 "test {{host_id}} {{year}}-{{month}}-{{day}}T{{hour}}:{{minute}}:{{second}}Z {{unique}}",
                 host_id,
+                &[],
                 now,
                 unique
             )
@@ -888,21 +3424,29 @@ mod test {
         );
 
         assert_eq!(
-            crate::evaluate_pattern_at("test {host_id", host_id, now, unique).unwrap_err(),
+            crate::evaluate_pattern_at("test {host_id", host_id, &[], now, unique).unwrap_err(),
             crate::InvalidS3URL::InvalidTemplateSyntax("Unmatched '{'".to_string())
         );
 
         assert_eq!(
-            crate::evaluate_pattern_at("test {", host_id, now, unique).unwrap_err(),
+            crate::evaluate_pattern_at("test {", host_id, &[], now, unique).unwrap_err(),
             crate::InvalidS3URL::InvalidTemplateSyntax("Unmatched '{'".to_string())
         );
 
         assert_eq!(
-            crate::evaluate_pattern_at("test host_id}", host_id, now, unique).unwrap_err(),
+            crate::evaluate_pattern_at("test host_id}", host_id, &[], now, unique).unwrap_err(),
             crate::InvalidS3URL::InvalidTemplateSyntax("Unmatched '}'".to_string())
         );
     }
 
+    #[test]
+    fn test_disambiguate_key() {
+        assert_eq!(crate::disambiguate_key("logs/web1.log.gz", 1), "logs/web1-1.log.gz");
+        assert_eq!(crate::disambiguate_key("logs.d/web1", 2), "logs.d/web1-2");
+        assert_eq!(crate::disambiguate_key("web1.log", 10), "web1-10.log");
+        assert_eq!(crate::mark_key("logs/{host_id}.{unique}.log", "-fallback"), "logs/{host_id}-fallback.{unique}.log");
+    }
+
     #[test]
     fn test_parse_s3_url() {
         assert_eq!(
@@ -937,6 +3481,79 @@ mod test {
                 "s3:bucket/path".to_string()
             )
         );
+
+        // Access point ARNs contain a slash of their own, and can be given without the s3://.
+        let arn = "arn:aws:s3:us-west-2:123456789012:accesspoint/logs";
+        assert_eq!(
+            crate::parse_s3_url(&format!("s3://{arn}/web/{{unique}}.log")).unwrap(),
+            (arn.to_string(), "web/{unique}.log".to_string())
+        );
+        assert_eq!(crate::parse_s3_url(&format!("{arn}/a:b")).unwrap(), (arn.to_string(), "a:b".to_string()));
+        assert_eq!(
+            crate::parse_s3_url("arn:aws:s3::123456789012:accesspoint/mfzwi23gnjvgw.mrap/x").unwrap().0,
+            "arn:aws:s3::123456789012:accesspoint/mfzwi23gnjvgw.mrap"
+        );
+        assert!(crate::parse_s3_url(&format!("s3://{arn}")).is_err());
+        assert!(crate::parse_s3_url("s3://arn:aws:s3:us-west-2:123456789012:bucket/logs/x").is_err());
+        assert!(crate::parse_s3_url("arn:aws:sqs:us-west-2:123456789012:accesspoint/logs/x").is_err());
+    }
+
+    #[test]
+    fn test_parse_destination() {
+        use crate::Location;
+
+        assert_eq!(
+            crate::parse_destination("s3://bucket/path").unwrap(),
+            (Location::S3("bucket".to_string()), "path".to_string())
+        );
+        assert_eq!(
+            crate::parse_destination("file:///var/log/archive/{year}/{host_id}.log").unwrap(),
+            (Location::File, "/var/log/archive/{year}/{host_id}.log".to_string())
+        );
+        assert!(crate::parse_destination("file://archive/{host_id}.log").is_err());
+        assert!(crate::parse_destination("file:///var/log/archive/").is_err());
+
+        assert_eq!(
+            crate::parse_destination("azblob://logsacct/logs/web/{year}/{host_id}.log").unwrap(),
+            (
+                Location::Azure {
+                    account: "logsacct".to_string(),
+                    container: "logs".to_string(),
+                },
+                "web/{year}/{host_id}.log".to_string()
+            )
+        );
+        assert!(crate::parse_destination("azblob://logsacct/logs").is_err());
+        assert!(crate::parse_destination("azblob:///logs/x.log").is_err());
+
+        assert_eq!(
+            crate::parse_destination("gs://logs/web/{host_id}.log").unwrap(),
+            (Location::Gcs("logs".to_string()), "web/{host_id}.log".to_string())
+        );
+        assert!(crate::parse_destination("gs://logs").is_err());
+    }
+
+    #[test]
+    fn test_bucket_kind() {
+        use crate::BucketKind;
+
+        assert_eq!(BucketKind::of("logs"), BucketKind::Bucket);
+        assert_eq!(BucketKind::of("logs-ab12cd-s3alias"), BucketKind::Bucket);
+        assert_eq!(
+            BucketKind::of("arn:aws:s3:us-west-2:123456789012:accesspoint/logs"),
+            BucketKind::AccessPoint("us-west-2")
+        );
+        assert_eq!(
+            BucketKind::of("arn:aws:s3::123456789012:accesspoint/mfzwi23gnjvgw.mrap"),
+            BucketKind::MultiRegionAccessPoint
+        );
+        assert_eq!(BucketKind::of("logs--usw2-az1--x-s3"), BucketKind::Directory);
+    }
+
+    #[test]
+    fn test_object_tagging() {
+        assert_eq!(crate::object_tagging("logs", "web1").as_deref(), Some("HostId=web1"));
+        assert_eq!(crate::object_tagging("logs--usw2-az1--x-s3", "web1"), None);
     }
 
     #[test]
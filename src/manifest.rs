@@ -0,0 +1,411 @@
+use {
+    crate::{
+        error::{InvalidS3URL, S3RequestError},
+        evaluate_pattern_at,
+        ledger::LedgerRecord,
+        mark_key,
+    },
+    anyhow::{Result as AnyResult, bail},
+    aws_sdk_s3::{
+        Client,
+        primitives::ByteStream,
+        types::{ObjectCannedAcl, ServerSideEncryption},
+    },
+    log::{debug, error, info},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        fmt::{Debug, Formatter, Result as FmtResult},
+        time::{Duration, SystemTime},
+    },
+    time::OffsetDateTime,
+    tokio::{
+        select,
+        sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        task::JoinHandle,
+        time::{Instant, interval},
+    },
+};
+
+/// The variables a manifest template may use. Anything else would split a window's objects across several manifests.
+pub(crate) const MANIFEST_VARIABLES: &[&str] = &["host_id", "year", "month", "day", "hour", "minute", "second"];
+
+/// How often we check whether a window has ended.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait before retrying a manifest that couldn't be written.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Added to the name of a manifest written for a window that hadn't ended, e.g. `11.partial.json`.
+const PARTIAL_MARKER: &str = ".partial";
+
+/// The manifest an upload belongs to: the manifest template evaluated at the time the batch was closed.
+#[derive(Clone, Debug)]
+pub(crate) struct ManifestWindow {
+    pub bucket: String,
+    pub key: String,
+
+    /// The manifest template, used to tell when the window has ended.
+    pub pattern: String,
+
+    /// A client for the bucket's region.
+    pub s3: Client,
+
+    /// The pipeline's canned ACL and expected bucket owner, applied to the manifest as to its objects.
+    pub acl: Option<ObjectCannedAcl>,
+    pub expected_bucket_owner: Option<String>,
+
+    /// The main destination's server-side encryption and KMS key, applied to the manifest as to its objects.
+    pub server_side_encryption: ServerSideEncryption,
+    pub sse_kms_key_id: Option<String>,
+}
+
+impl ManifestWindow {
+    /// The window that an object created at `now` belongs to.
+    pub fn at(
+        pattern: &str,
+        host_id: &str,
+        bucket: &str,
+        s3: &Client,
+        now: OffsetDateTime,
+    ) -> Result<Self, InvalidS3URL> {
+        Ok(Self {
+            bucket: bucket.to_string(),
+            key: evaluate_pattern_at(pattern, host_id, &[], now, [0; 15])?,
+            pattern: pattern.to_string(),
+            s3: s3.clone(),
+            acl: None,
+            expected_bucket_owner: None,
+            server_side_encryption: ServerSideEncryption::Aes256,
+            sse_kms_key_id: None,
+        })
+    }
+}
+
+/// One data object in a manifest.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct ManifestObject {
+    pub pipeline: String,
+    pub key: String,
+
+    /// The size of the object as stored.
+    pub bytes: u64,
+
+    /// The size of the data before compression.
+    pub raw_bytes: u64,
+    pub lines: u64,
+    pub sha256: String,
+    pub etag: Option<String>,
+    pub version_id: Option<String>,
+    pub uploaded_at: String,
+}
+
+impl From<&LedgerRecord> for ManifestObject {
+    fn from(record: &LedgerRecord) -> Self {
+        Self {
+            pipeline: record.pipeline.clone(),
+            key: record.key.clone(),
+            bytes: record.compressed_bytes.unwrap_or(record.raw_bytes),
+            raw_bytes: record.raw_bytes,
+            lines: record.lines,
+            sha256: record.sha256.clone(),
+            etag: record.etag.clone(),
+            version_id: record.version_id.clone(),
+            uploaded_at: record.uploaded_at.clone(),
+        }
+    }
+}
+
+/// The contents of a manifest object.
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    host_id: String,
+    bucket: String,
+    generated_at: String,
+    objects: Vec<ManifestObject>,
+}
+
+enum ManifestEvent {
+    Started(ManifestWindow),
+    Finished(ManifestWindow, Option<ManifestObject>),
+}
+
+/// Tells the manifest writer about uploads. Cloned into every pipeline's settings.
+#[derive(Clone, Debug)]
+pub(crate) struct Manifests {
+    events: UnboundedSender<ManifestEvent>,
+}
+
+impl Manifests {
+    /// A batch in `window` has been closed and will be uploaded. The window's manifest won't be written until
+    /// `upload_finished` is called for it.
+    pub fn upload_started(&self, window: &ManifestWindow) {
+        let _ = self.events.send(ManifestEvent::Started(window.clone()));
+    }
+
+    /// An upload in `window` has finished. `object` is `None` if it failed.
+    pub fn upload_finished(&self, window: ManifestWindow, object: Option<ManifestObject>) {
+        if self.events.send(ManifestEvent::Finished(window, object)).is_err() {
+            error!("Manifest writer has exited; object will be missing from its manifest");
+        }
+    }
+}
+
+impl Debug for ManifestEvent {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Started(window) => write!(f, "Started(s3://{}/{})", window.bucket, window.key),
+            Self::Finished(window, _) => write!(f, "Finished(s3://{}/{})", window.bucket, window.key),
+        }
+    }
+}
+
+/// Start the manifest writer. The returned task finishes once every `Manifests` handle has been dropped and the
+/// remaining manifests have been written.
+pub(crate) fn start(host_id: &str) -> (Manifests, JoinHandle<()>) {
+    let (events, receiver) = unbounded_channel();
+    let writer = tokio::spawn(write_manifests(receiver, host_id.to_string()));
+    (
+        Manifests {
+            events,
+        },
+        writer,
+    )
+}
+
+/// A window whose manifest hasn't been written yet.
+struct WindowState {
+    window: ManifestWindow,
+
+    /// Uploads that have started but not finished.
+    pending: usize,
+    objects: Vec<ManifestObject>,
+
+    /// Don't try writing before this, after a failure.
+    retry_at: Option<Instant>,
+}
+
+/// The manifest writer task. A window's manifest is written once the window has ended and every upload in it has
+/// finished, so its presence marks the window as complete. Windows still open when we exit are written then, under a
+/// partial name.
+async fn write_manifests(mut receiver: UnboundedReceiver<ManifestEvent>, host_id: String) {
+    let mut windows: HashMap<(String, String), WindowState> = HashMap::new();
+    let mut check = interval(CHECK_INTERVAL);
+
+    loop {
+        select! {
+            event = receiver.recv() => match event {
+                Some(event) => handle_event(&mut windows, event),
+                None => break,
+            },
+
+            _ = check.tick(), if !windows.is_empty() => {
+                // Pick up batches closed before this tick so they aren't left out of a window we're about to close.
+                while let Ok(event) = receiver.try_recv() {
+                    handle_event(&mut windows, event);
+                }
+
+                let now = OffsetDateTime::now_utc();
+                let ended: Vec<_> = windows
+                    .iter()
+                    .filter(|(_, state)| state.pending == 0 && state.retry_at.is_none_or(|at| at <= Instant::now()))
+                    .filter(|(_, state)| {
+                        let window = &state.window;
+                        evaluate_pattern_at(&window.pattern, &host_id, &[], now, [0; 15])
+                            .is_ok_and(|key| key != window.key)
+                    })
+                    .map(|(id, _)| id.clone())
+                    .collect();
+
+                for id in ended {
+                    let state = windows.get_mut(&id).unwrap();
+                    match write_manifest(&host_id, &state.window, &state.objects, false).await {
+                        Ok(()) => {
+                            windows.remove(&id);
+                        }
+                        Err(e) => {
+                            error!("Unable to write manifest s3://{}/{}: {e}", id.0, id.1);
+                            state.retry_at = Some(Instant::now() + RETRY_DELAY);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Every upload has finished. Write what we have for the windows that are still open as partial manifests, so
+    // they aren't mistaken for complete ones. If we're restarted before a window ends, its partial manifest is merged
+    // into the complete one when that's written.
+    for ((bucket, key), state) in windows {
+        if state.objects.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = write_manifest(&host_id, &state.window, &state.objects, true).await {
+            error!("Unable to write partial manifest for s3://{bucket}/{key}: {e}");
+        }
+    }
+}
+
+fn handle_event(windows: &mut HashMap<(String, String), WindowState>, event: ManifestEvent) {
+    debug!("Manifest event: {event:?}");
+    match event {
+        ManifestEvent::Started(window) => {
+            let id = (window.bucket.clone(), window.key.clone());
+            windows
+                .entry(id)
+                .or_insert_with(|| WindowState {
+                    window,
+                    pending: 0,
+                    objects: Vec::new(),
+                    retry_at: None,
+                })
+                .pending += 1;
+        }
+        ManifestEvent::Finished(window, object) => {
+            let id = (window.bucket, window.key);
+            if let Some(state) = windows.get_mut(&id) {
+                state.pending -= 1;
+                state.objects.extend(object);
+            }
+        }
+    }
+}
+
+/// Write a manifest listing `objects`, along with any objects already in it or its partial manifest from an earlier
+/// run. If `partial` is set, the manifest is written under the partial name; otherwise, the partial manifest is deleted
+/// once the complete one has been written.
+async fn write_manifest(
+    host_id: &str,
+    window: &ManifestWindow,
+    objects: &[ManifestObject],
+    partial: bool,
+) -> AnyResult<()> {
+    let ManifestWindow {
+        bucket,
+        key: complete_key,
+        s3,
+        acl,
+        expected_bucket_owner,
+        server_side_encryption,
+        sse_kms_key_id,
+        ..
+    } = window;
+    let partial_key = mark_key(complete_key, PARTIAL_MARKER);
+    let key = if partial {
+        &partial_key
+    } else {
+        complete_key
+    };
+
+    let mut all_objects = read_manifest(window, complete_key).await?.unwrap_or_default();
+    let earlier_partial = read_manifest(window, &partial_key).await?;
+    for object in earlier_partial.iter().flatten().chain(objects) {
+        if !all_objects.iter().any(|o| o.key == object.key) {
+            all_objects.push(object.clone());
+        }
+    }
+
+    let manifest = Manifest {
+        host_id: host_id.to_string(),
+        bucket: bucket.clone(),
+        generated_at: LedgerRecord::timestamp(SystemTime::now()),
+        objects: all_objects,
+    };
+
+    s3.put_object()
+        .bucket(bucket)
+        .key(key)
+        .content_type("application/json")
+        .server_side_encryption(server_side_encryption.clone())
+        .set_ssekms_key_id(sse_kms_key_id.clone())
+        .set_acl(acl.clone())
+        .set_expected_bucket_owner(expected_bucket_owner.clone())
+        .body(ByteStream::from(serde_json::to_vec(&manifest)?))
+        .send()
+        .await
+        .map_err(S3RequestError::new)?;
+
+    info!(
+        event = "manifest_written", bucket = bucket.as_str(), key = key.as_str(), objects = manifest.objects.len();
+        "Wrote manifest s3://{bucket}/{key} with {} objects", manifest.objects.len()
+    );
+
+    if !partial && earlier_partial.is_some() {
+        let delete = s3.delete_object().bucket(bucket).key(&partial_key);
+        if let Err(e) = delete.set_expected_bucket_owner(expected_bucket_owner.clone()).send().await {
+            error!("Unable to delete partial manifest s3://{bucket}/{partial_key}: {}", S3RequestError::new(e));
+        }
+    }
+    Ok(())
+}
+
+/// Read the objects listed in the manifest at `key` in the window's bucket, if there is one.
+async fn read_manifest(window: &ManifestWindow, key: &str) -> AnyResult<Option<Vec<ManifestObject>>> {
+    let request = window.s3.get_object().bucket(&window.bucket).key(key);
+    match request.set_expected_bucket_owner(window.expected_bucket_owner.clone()).send().await {
+        Ok(output) => {
+            let body = output.body.collect().await?.into_bytes();
+            match serde_json::from_slice::<Manifest>(&body) {
+                Ok(existing) => Ok(Some(existing.objects)),
+                Err(e) => bail!("Existing manifest s3://{}/{key} is not valid: {e}", window.bucket),
+            }
+        }
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+        Err(e) => Err(S3RequestError::new(e).into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{ManifestEvent, ManifestObject, ManifestWindow, PARTIAL_MARKER, handle_event},
+        crate::mark_key,
+        aws_config::{BehaviorVersion, Region},
+        aws_sdk_s3::{Client, Config},
+        std::collections::HashMap,
+        time::macros::datetime,
+    };
+
+    fn object(key: &str) -> ManifestObject {
+        ManifestObject {
+            pipeline: "web".to_string(),
+            key: key.to_string(),
+            bytes: 10,
+            raw_bytes: 20,
+            lines: 2,
+            sha256: "00".to_string(),
+            etag: None,
+            version_id: None,
+            uploaded_at: "2026-10-18T11:00:01.000Z".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_manifest_windows() {
+        let s3 = Client::from_conf(
+            Config::builder().behavior_version(BehaviorVersion::latest()).region(Region::new("us-east-1")).build(),
+        );
+        let pattern = "_manifest/{host_id}/{year}/{month}/{day}/{hour}.json";
+        let before = ManifestWindow::at(pattern, "web1", "logs", &s3, datetime!(2026-10-18 10:59:59 UTC)).unwrap();
+        let after = ManifestWindow::at(pattern, "web1", "logs", &s3, datetime!(2026-10-18 11:00:00 UTC)).unwrap();
+        assert_eq!(before.key, "_manifest/web1/2026/10/18/10.json");
+        assert_eq!(after.key, "_manifest/web1/2026/10/18/11.json");
+        assert_eq!(mark_key(&after.key, PARTIAL_MARKER), "_manifest/web1/2026/10/18/11.partial.json");
+
+        let mut windows = HashMap::new();
+        handle_event(&mut windows, ManifestEvent::Started(before.clone()));
+        handle_event(&mut windows, ManifestEvent::Started(before.clone()));
+        handle_event(&mut windows, ManifestEvent::Started(after.clone()));
+        handle_event(&mut windows, ManifestEvent::Finished(before.clone(), Some(object("a.log"))));
+
+        let id = ("logs".to_string(), before.key.clone());
+        assert_eq!(windows[&id].pending, 1);
+
+        // A failed upload isn't listed, but no longer holds up the manifest.
+        handle_event(&mut windows, ManifestEvent::Finished(before, None));
+        assert_eq!(windows[&id].pending, 0);
+        assert_eq!(windows[&id].objects, vec![object("a.log")]);
+        assert_eq!(windows.len(), 2);
+    }
+}
@@ -0,0 +1,566 @@
+use {
+    crate::{
+        error::{ChecksumMismatch, S3RequestError, StorageRequestError, VerificationFailed},
+        statsd,
+    },
+    anyhow::Error as AnyError,
+    aws_sdk_s3::config::{ConfigBag, Intercept, RuntimeComponents, interceptors::BeforeTransmitInterceptorContextRef},
+    aws_smithy_types::config_bag::{Storable, StoreReplace},
+    bytes::Bytes,
+    http_body_util::Full,
+    hyper::{
+        Method, Request, Response, StatusCode,
+        body::Incoming,
+        header::{CONTENT_TYPE, HeaderValue},
+        server::conn::http1,
+        service::service_fn,
+    },
+    hyper_util::rt::TokioIo,
+    log::{debug, error, info},
+    std::{
+        collections::BTreeMap,
+        convert::Infallible,
+        fmt::Write,
+        io::Error as IOError,
+        path::PathBuf,
+        sync::{Mutex, MutexGuard},
+        time::{Duration, Instant},
+    },
+    tokio::{fs::metadata, net::TcpListener},
+};
+
+/// The path Prometheus scrapes.
+const METRICS_PATH: &str = "/metrics";
+
+/// The prefix for every metric name.
+const PREFIX: &str = "stream_logs_to_s3";
+
+/// Upper bounds of the upload latency histogram buckets, in seconds.
+const UPLOAD_DURATION_BUCKETS: [f64; 12] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// A metric's name, help text, and how to read its value.
+type MetricDef<T, V> = (&'static str, &'static str, fn(&T) -> V);
+
+/// The process-wide metrics registry.
+pub(crate) static METRICS: Metrics = Metrics::new();
+
+/// Counters and gauges for every pipeline, rendered in the Prometheus text format.
+pub(crate) struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    pipelines: BTreeMap<String, PipelineCounters>,
+
+    /// Upload failures, by pipeline and error class.
+    failures: BTreeMap<(String, String), u64>,
+
+    /// S3 requests retried by the SDK.
+    retries: u64,
+
+    /// The batch currently open in each batching loop, by loop id.
+    loops: BTreeMap<u64, LoopState>,
+
+    /// The spool file of each upload that hasn't finished, by upload id.
+    uploads: BTreeMap<u64, (String, PathBuf)>,
+
+    next_id: u64,
+}
+
+#[derive(Default)]
+struct PipelineCounters {
+    ingested_bytes: u64,
+    ingested_lines: u64,
+    batches_created: u64,
+    batches_uploaded: u64,
+    verification_failures: u64,
+    upload_duration: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Cumulative counts for each bucket in `UPLOAD_DURATION_BUCKETS`.
+    buckets: [u64; UPLOAD_DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Gauges {
+    buffer_bytes: f64,
+    buffer_age: f64,
+    in_flight: f64,
+    spool_bytes: f64,
+}
+
+struct LoopState {
+    pipeline: String,
+    path: Option<PathBuf>,
+    size: u64,
+    first_write: Option<Instant>,
+    in_flight: usize,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                pipelines: BTreeMap::new(),
+                failures: BTreeMap::new(),
+                retries: 0,
+                loops: BTreeMap::new(),
+                uploads: BTreeMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // A panic while holding the lock leaves the counters usable; keep going.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Count an S3 request retried by the SDK.
+    pub fn retried(&self) {
+        self.lock().retries += 1;
+        statsd::count("upload_retries", 1, &[]);
+    }
+
+    /// Render every metric in the Prometheus text exposition format. The spool files are measured first, without the
+    /// lock held, so their sizes may be a moment older than the other metrics.
+    pub async fn render(&self) -> String {
+        let spool_files: Vec<(String, PathBuf)> = {
+            let inner = self.lock();
+            let open = inner.loops.values().filter_map(|state| Some((state.pipeline.clone(), state.path.clone()?)));
+            open.chain(inner.uploads.values().cloned()).collect()
+        };
+        let mut spool_bytes: BTreeMap<String, u64> = BTreeMap::new();
+        for (pipeline, path) in spool_files {
+            *spool_bytes.entry(pipeline).or_default() += file_size(&path).await;
+        }
+
+        let inner = self.lock();
+        let mut out = String::new();
+
+        let counters: [MetricDef<PipelineCounters, u64>; 5] = [
+            ("ingested_bytes_total", "Bytes read from inputs.", |p| p.ingested_bytes),
+            ("ingested_lines_total", "Newline-terminated lines read from inputs.", |p| p.ingested_lines),
+            ("batches_created_total", "Batches that received data.", |p| p.batches_created),
+            ("batches_uploaded_total", "Batches uploaded to S3.", |p| p.batches_uploaded),
+            ("verification_failures_total", "Uploaded objects that didn't match the spool file when checked.", |p| {
+                p.verification_failures
+            }),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, "counter", help);
+            for (pipeline, counters) in &inner.pipelines {
+                sample(&mut out, name, &[("pipeline", pipeline)], value(counters));
+            }
+        }
+
+        header(&mut out, "upload_failures_total", "counter", "Failed batch uploads, by error class.");
+        for ((pipeline, class), count) in &inner.failures {
+            sample(&mut out, "upload_failures_total", &[("pipeline", pipeline), ("class", class)], *count);
+        }
+
+        header(&mut out, "upload_retries_total", "counter", "S3 requests retried by the SDK.");
+        sample(&mut out, "upload_retries_total", &[], inner.retries);
+
+        header(&mut out, "upload_duration_seconds", "histogram", "Time taken to upload a batch to S3.");
+        for (pipeline, counters) in &inner.pipelines {
+            let histogram = &counters.upload_duration;
+            for (bound, count) in UPLOAD_DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                let le = bound.to_string();
+                sample(&mut out, "upload_duration_seconds_bucket", &[("pipeline", pipeline), ("le", &le)], count);
+            }
+            let labels = [("pipeline", pipeline.as_str())];
+            sample(&mut out, "upload_duration_seconds_bucket", &[labels[0], ("le", "+Inf")], histogram.count);
+            sample(&mut out, "upload_duration_seconds_sum", &labels, histogram.sum);
+            sample(&mut out, "upload_duration_seconds_count", &labels, histogram.count);
+        }
+
+        // Gauges are summed over a pipeline's batching loops; a syslog pipeline has one per partition.
+        let mut gauges: BTreeMap<&str, Gauges> =
+            inner.pipelines.keys().map(|p| (p.as_str(), Gauges::default())).collect();
+        let now = Instant::now();
+        for state in inner.loops.values() {
+            let gauges = gauges.entry(&state.pipeline).or_default();
+            gauges.buffer_bytes += state.size as f64;
+            if let Some(first_write) = state.first_write {
+                gauges.buffer_age = gauges.buffer_age.max(now.duration_since(first_write).as_secs_f64());
+            }
+            gauges.in_flight += state.in_flight as f64;
+        }
+        for (pipeline, bytes) in &spool_bytes {
+            gauges.entry(pipeline).or_default().spool_bytes = *bytes as f64;
+        }
+
+        let gauge_defs: [MetricDef<Gauges, f64>; 4] = [
+            ("buffer_bytes", "Bytes written to open batches, before compression.", |g| g.buffer_bytes),
+            ("buffer_age_seconds", "Age of the oldest open batch.", |g| g.buffer_age),
+            ("uploads_in_flight", "Batches waiting for or being uploaded.", |g| g.in_flight),
+            ("spool_bytes", "Disk space used by spool files.", |g| g.spool_bytes),
+        ];
+        for (name, help, value) in gauge_defs {
+            header(&mut out, name, "gauge", help);
+            for (pipeline, gauges) in &gauges {
+                sample(&mut out, name, &[("pipeline", pipeline)], value(gauges));
+            }
+        }
+
+        out
+    }
+}
+
+impl Inner {
+    fn pipeline(&mut self, name: &str) -> &mut PipelineCounters {
+        if !self.pipelines.contains_key(name) {
+            self.pipelines.insert(name.to_string(), PipelineCounters::default());
+        }
+        self.pipelines.get_mut(name).unwrap()
+    }
+
+    fn register(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in UPLOAD_DURATION_BUCKETS.iter().zip(self.buckets.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Metrics for one batching loop, recorded from `run`'s select loop and pushed to StatsD if configured. The loop's
+/// gauges are removed when this is dropped.
+pub(crate) struct LoopMetrics {
+    id: u64,
+    pipeline: String,
+
+    /// The bucket the open batch will be written to, for StatsD tags.
+    bucket: String,
+}
+
+impl LoopMetrics {
+    pub fn new(pipeline: &str) -> Self {
+        let mut inner = METRICS.lock();
+        inner.pipeline(pipeline);
+        let id = inner.register();
+        inner.loops.insert(
+            id,
+            LoopState {
+                pipeline: pipeline.to_string(),
+                path: None,
+                size: 0,
+                first_write: None,
+                in_flight: 0,
+            },
+        );
+        Self {
+            id,
+            pipeline: pipeline.to_string(),
+            bucket: String::new(),
+        }
+    }
+
+    fn tags(&self) -> [(&str, &str); 2] {
+        [("pipeline", &self.pipeline), ("bucket", &self.bucket)]
+    }
+
+    fn update(&self, f: impl FnOnce(&mut LoopState)) {
+        if let Some(state) = METRICS.lock().loops.get_mut(&self.id) {
+            f(state);
+        }
+    }
+
+    /// A new, empty batch destined for `bucket` has been opened at `path`.
+    pub fn batch_opened(&mut self, path: PathBuf, bucket: &str) {
+        if self.bucket != bucket {
+            self.bucket = bucket.to_string();
+        }
+        self.update(|state| {
+            state.path = Some(path);
+            state.size = 0;
+            state.first_write = None;
+        });
+    }
+
+    /// `data` has been written to the open batch.
+    pub fn written(&self, data: &[u8]) {
+        let lines = data.iter().filter(|&&b| b == b'\n').count() as u64;
+        let mut inner = METRICS.lock();
+        let counters = inner.pipeline(&self.pipeline);
+        counters.ingested_bytes += data.len() as u64;
+        counters.ingested_lines += lines;
+
+        let mut created = false;
+        if let Some(state) = inner.loops.get_mut(&self.id) {
+            state.size += data.len() as u64;
+            if state.first_write.is_none() {
+                state.first_write = Some(Instant::now());
+                created = true;
+            }
+        }
+
+        if created {
+            inner.pipeline(&self.pipeline).batches_created += 1;
+        }
+        drop(inner);
+
+        let tags = self.tags();
+        statsd::count("ingested_bytes", data.len() as u64, &tags);
+        statsd::count("ingested_lines", lines, &tags);
+        if created {
+            statsd::count("batches_created", 1, &tags);
+        }
+    }
+
+    /// The number of this loop's batches waiting for or being uploaded.
+    pub fn set_in_flight(&self, in_flight: usize) {
+        let mut inner = METRICS.lock();
+        let Some(state) = inner.loops.get_mut(&self.id) else {
+            return;
+        };
+        if state.in_flight == in_flight {
+            return;
+        }
+        state.in_flight = in_flight;
+
+        let pipeline_total: usize =
+            inner.loops.values().filter(|state| state.pipeline == self.pipeline).map(|state| state.in_flight).sum();
+        drop(inner);
+        statsd::gauge("uploads_in_flight", pipeline_total as u64, &self.tags());
+    }
+}
+
+impl Drop for LoopMetrics {
+    fn drop(&mut self) {
+        METRICS.lock().loops.remove(&self.id);
+    }
+}
+
+/// Metrics for one batch upload, pushed to StatsD if configured. The spool file counts towards disk usage until this is
+/// dropped.
+pub(crate) struct UploadMetrics {
+    id: u64,
+    pipeline: String,
+    bucket: String,
+    started: Instant,
+}
+
+impl UploadMetrics {
+    pub fn new(pipeline: &str, bucket: &str, path: PathBuf) -> Self {
+        let mut inner = METRICS.lock();
+        let id = inner.register();
+        inner.uploads.insert(id, (pipeline.to_string(), path));
+        Self {
+            id,
+            pipeline: pipeline.to_string(),
+            bucket: bucket.to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    /// The upload has been given a slot and is starting now. Latency is measured from here.
+    pub fn started(&mut self) {
+        self.started = Instant::now();
+    }
+
+    /// The uploaded object didn't match the spool file. The upload may be retried.
+    pub fn verification_failed(&self) {
+        METRICS.lock().pipeline(&self.pipeline).verification_failures += 1;
+        statsd::count("verification_failures", 1, &[("pipeline", &self.pipeline), ("bucket", &self.bucket)]);
+    }
+
+    /// Record the outcome of the upload.
+    pub fn finished(self, result: &Result<(), AnyError>) {
+        let elapsed = self.started.elapsed();
+        let tags = [("pipeline", self.pipeline.as_str()), ("bucket", self.bucket.as_str())];
+        let mut inner = METRICS.lock();
+        match result {
+            Ok(()) => {
+                let counters = inner.pipeline(&self.pipeline);
+                counters.batches_uploaded += 1;
+                counters.upload_duration.observe(elapsed.as_secs_f64());
+                drop(inner);
+                statsd::count("batches_uploaded", 1, &tags);
+                statsd::timing("upload_duration", elapsed.as_secs_f64() * 1000.0, &tags);
+            }
+            Err(e) => {
+                let class = error_class(e);
+                *inner.failures.entry((self.pipeline.clone(), class.clone())).or_default() += 1;
+                drop(inner);
+                statsd::count("upload_failures", 1, &[tags[0], tags[1], ("class", &class)]);
+            }
+        }
+    }
+}
+
+impl Drop for UploadMetrics {
+    fn drop(&mut self) {
+        METRICS.lock().uploads.remove(&self.id);
+    }
+}
+
+/// A short, bounded description of why an upload failed: the service's error code or failure kind for request failures,
+/// `checksum_mismatch` if S3 reported a different checksum than ours, `verification_mismatch` if the uploaded object
+/// didn't match when checked, `io` for local file errors, and `other` for anything else.
+pub(crate) fn error_class(e: &AnyError) -> String {
+    if let Some(e) = e.downcast_ref::<S3RequestError>() {
+        e.class.clone()
+    } else if let Some(e) = e.downcast_ref::<StorageRequestError>() {
+        e.class.clone()
+    } else if e.is::<ChecksumMismatch>() {
+        "checksum_mismatch".to_string()
+    } else if e.is::<VerificationFailed>() {
+        "verification_mismatch".to_string()
+    } else if e.is::<IOError>() {
+        "io".to_string()
+    } else {
+        "other".to_string()
+    }
+}
+
+/// Counts retries of S3 requests by noticing attempts after the first for each operation.
+#[derive(Debug)]
+pub(crate) struct RetryCounter;
+
+/// Marks an operation that has already made an attempt.
+#[derive(Clone, Debug)]
+struct Attempted;
+
+impl Storable for Attempted {
+    type Storer = StoreReplace<Self>;
+}
+
+impl Intercept for RetryCounter {
+    fn name(&self) -> &'static str {
+        "RetryCounter"
+    }
+
+    fn read_before_attempt(
+        &self,
+        _context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), aws_sdk_s3::error::BoxError> {
+        if cfg.load::<Attempted>().is_some() {
+            METRICS.retried();
+        } else {
+            cfg.interceptor_state().store_put(Attempted);
+        }
+        Ok(())
+    }
+}
+
+/// Serve `GET /metrics` until the process exits.
+pub(crate) async fn serve(listener: TcpListener) {
+    match listener.local_addr() {
+        Ok(addr) => info!("Serving metrics on http://{addr}{METRICS_PATH}"),
+        Err(e) => debug!("Serving metrics on an unknown address: {e}"),
+    }
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept metrics connection: {e}");
+                // Don't spin if we've run out of file descriptors.
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(handle)).await {
+                debug!("Metrics connection from {peer} ended with error: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET | &Method::HEAD, METRICS_PATH) => (StatusCode::OK, METRICS.render().await),
+        (_, METRICS_PATH) => (StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n".to_string()),
+        _ => (StatusCode::NOT_FOUND, "not found\n".to_string()),
+    };
+
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    let content_type = if status == StatusCode::OK {
+        "text/plain; version=0.0.4; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Ok(response)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    let _ = write!(out, "{PREFIX}_{name}");
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (key, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            let _ = write!(out, "{key}=\"{value}\"");
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+async fn file_size(path: &PathBuf) -> u64 {
+    metadata(path).await.map(|m| m.len()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LoopMetrics, METRICS, UploadMetrics};
+
+    #[tokio::test]
+    async fn test_render() {
+        let metrics = LoopMetrics::new("render-test");
+        metrics.written(b"one\ntwo\n");
+        metrics.written(b"three\n");
+        metrics.set_in_flight(2);
+        let upload = UploadMetrics::new("render-test", "bucket", "/nonexistent".into());
+        upload.verification_failed();
+        upload.finished(&Ok(()));
+        UploadMetrics::new("render-test", "bucket", "/nonexistent".into()).finished(&Err(anyhow::anyhow!("boom")));
+
+        let text = METRICS.render().await;
+        for expected in [
+            "stream_logs_to_s3_ingested_bytes_total{pipeline=\"render-test\"} 14\n",
+            "stream_logs_to_s3_ingested_lines_total{pipeline=\"render-test\"} 3\n",
+            "stream_logs_to_s3_batches_created_total{pipeline=\"render-test\"} 1\n",
+            "stream_logs_to_s3_batches_uploaded_total{pipeline=\"render-test\"} 1\n",
+            "stream_logs_to_s3_verification_failures_total{pipeline=\"render-test\"} 1\n",
+            "stream_logs_to_s3_upload_failures_total{pipeline=\"render-test\",class=\"other\"} 1\n",
+            "stream_logs_to_s3_upload_duration_seconds_count{pipeline=\"render-test\"} 1\n",
+            "stream_logs_to_s3_buffer_bytes{pipeline=\"render-test\"} 14\n",
+            "stream_logs_to_s3_uploads_in_flight{pipeline=\"render-test\"} 2\n",
+        ] {
+            assert!(text.contains(expected), "missing {expected:?} in:\n{text}");
+        }
+
+        drop(metrics);
+        assert!(METRICS.render().await.contains("stream_logs_to_s3_buffer_bytes{pipeline=\"render-test\"} 0\n"));
+    }
+}
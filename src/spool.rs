@@ -0,0 +1,393 @@
+use {
+    crate::{
+        checksum::{ChecksumAlgorithm, ObjectChecksums, PartChecksums},
+        ledger::BatchStats,
+    },
+    log::{debug, error, warn},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        ffi::OsString,
+        fs::File as StdFile,
+        io::{Error as IOError, ErrorKind},
+        path::{Path, PathBuf},
+    },
+    tokio::{
+        fs::{File, read, read_dir, remove_file, rename, write},
+        io::AsyncReadExt,
+    },
+};
+
+/// The suffix added to a spool file's name for its multipart upload state.
+const SIDECAR_SUFFIX: &str = ".mpu.json";
+
+/// The progress of a multipart upload, saved next to the spool file being uploaded so the upload can be finished
+/// after a restart instead of starting over.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct MultipartState {
+    pub pipeline: String,
+    pub bucket: String,
+    pub key: String,
+    pub upload_id: String,
+
+    /// The size of the spool file and the size of each part but the last.
+    pub size: u64,
+    pub part_size: u64,
+    pub compressed: bool,
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+
+    /// Whether to fail rather than complete the upload over an existing object.
+    pub no_overwrite: bool,
+
+    /// The canned ACL for the object and the account that must own the bucket, if any.
+    pub acl: Option<String>,
+    pub expected_bucket_owner: Option<String>,
+
+    /// The bucket's region, if it was configured, so resuming doesn't have to look it up.
+    #[serde(default)]
+    pub bucket_region: Option<String>,
+
+    /// Which of the pipeline's destinations this upload is for; a batch fanned out to several has a state for each.
+    #[serde(default)]
+    pub destination_index: usize,
+
+    /// How many destinations the batch was fanned out to, not counting fallbacks. Any without an upload to resume may
+    /// not have the batch, so the spool file is only deleted if each of them has one.
+    #[serde(default = "default_target_count")]
+    pub target_count: usize,
+
+    /// Whether to check the object with HeadObject once the upload is complete.
+    #[serde(default)]
+    pub verify: bool,
+
+    /// The key and template of the manifest window the object belongs to, if it's for the main destination of a
+    /// pipeline that writes manifests.
+    #[serde(default)]
+    pub manifest_key: Option<String>,
+    #[serde(default)]
+    pub manifest_pattern: Option<String>,
+
+    /// The server-side encryption (AES256 if not given), KMS key and storage class for the object.
+    #[serde(default)]
+    pub server_side_encryption: Option<String>,
+    #[serde(default)]
+    pub sse_kms_key_id: Option<String>,
+    #[serde(default)]
+    pub storage_class: Option<String>,
+    pub stats: BatchStats,
+
+    /// The parts S3 has acknowledged.
+    pub parts: Vec<SavedPart>,
+}
+
+/// A part S3 has acknowledged.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct SavedPart {
+    pub part_number: i32,
+    pub e_tag: String,
+}
+
+/// An exclusive lock on a spool file, held by the process uploading it so no other process sharing the temp directory
+/// resumes its uploads. The lock is released when this is dropped.
+#[derive(Debug)]
+pub(crate) struct SpoolLock(#[allow(dead_code)] StdFile);
+
+impl SpoolLock {
+    /// Lock `spool`, returning `None` if another process holds the lock.
+    pub async fn try_acquire(spool: &Path) -> Result<Option<Self>, IOError> {
+        let file = File::open(spool).await?.into_std().await;
+        Ok(try_lock(&file)?.then_some(Self(file)))
+    }
+}
+
+#[cfg(unix)]
+fn try_lock(file: &StdFile) -> Result<bool, IOError> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(std::fs::TryLockError::WouldBlock) => Ok(false),
+        Err(std::fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Elsewhere, file locks are mandatory and would keep the upload itself from reading the spool file.
+#[cfg(not(unix))]
+fn try_lock(_file: &StdFile) -> Result<bool, IOError> {
+    Ok(true)
+}
+
+/// The multipart uploads left unfinished for a spool file, with the lock that keeps other processes from resuming them.
+#[derive(Debug)]
+pub(crate) struct Unfinished {
+    pub spool: PathBuf,
+    pub uploads: Vec<MultipartState>,
+    pub lock: SpoolLock,
+}
+
+/// State saved before the target count was, which only fanned out to one destination.
+fn default_target_count() -> usize {
+    1
+}
+
+impl MultipartState {
+    /// The number of parts the spool file is split into.
+    pub fn part_count(&self) -> i32 {
+        self.size.div_ceil(self.part_size) as i32
+    }
+
+    /// Save the state next to `spool`. The state is written to a temporary name first so a crash never leaves a
+    /// partial file behind.
+    pub async fn save(&self, spool: &Path) -> Result<(), IOError> {
+        let sidecar = sidecar_path(spool, self.destination_index);
+        let mut temp = sidecar.clone().into_os_string();
+        temp.push(".tmp");
+        write(&temp, serde_json::to_vec(self)?).await?;
+        rename(&temp, &sidecar).await
+    }
+
+    /// Remove the state saved next to `spool`, once the upload has completed or been aborted.
+    pub async fn remove(&self, spool: &Path) {
+        remove_sidecar(&sidecar_path(spool, self.destination_index)).await;
+    }
+}
+
+/// The state for the upload of `spool` to the destination at `index`: `<spool>.mpu.json` for the main destination,
+/// and `<spool>.<index>.mpu.json` for the others.
+fn sidecar_path(spool: &Path, index: usize) -> PathBuf {
+    let mut sidecar = OsString::from(spool.as_os_str());
+    if index > 0 {
+        sidecar.push(format!(".{index}"));
+    }
+    sidecar.push(SIDECAR_SUFFIX);
+    PathBuf::from(sidecar)
+}
+
+async fn remove_sidecar(sidecar: &Path) {
+    if let Err(e) = remove_file(sidecar).await
+        && e.kind() != ErrorKind::NotFound
+    {
+        warn!("Unable to remove multipart upload state {sidecar:?}: {e}");
+    }
+}
+
+/// Find the multipart upload state in a spool directory, by spool file.
+async fn find_sidecars(dir: &Path) -> BTreeMap<PathBuf, Vec<PathBuf>> {
+    let mut sidecars: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    let mut entries = match read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Unable to look for unfinished uploads in {dir:?}: {e}");
+            return sidecars;
+        }
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let sidecar = entry.path();
+        let Some(spool) = sidecar.to_str().and_then(|s| s.strip_suffix(SIDECAR_SUFFIX)) else {
+            continue;
+        };
+
+        // Strip the destination index, if there is one. Spool files have no dots but the leading one.
+        let spool = match spool.rsplit_once('.') {
+            Some((spool, index)) if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) => spool,
+            _ => spool,
+        };
+        sidecars.entry(PathBuf::from(spool)).or_default().push(sidecar);
+    }
+
+    sidecars
+}
+
+/// Read the multipart upload state in `sidecar`, or `None` if it's gone or can't be read.
+async fn read_sidecar(sidecar: &Path) -> Option<MultipartState> {
+    match read(sidecar).await.map(|data| serde_json::from_slice::<MultipartState>(&data)) {
+        Ok(Ok(state)) => Some(state),
+        Ok(Err(e)) => {
+            error!("Ignoring invalid multipart upload state {sidecar:?}: {e}");
+            None
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            error!("Unable to read multipart upload state {sidecar:?}: {e}");
+            None
+        }
+    }
+}
+
+/// The ids of the multipart uploads saved in a spool directory, whether they're left for the next run to resume or
+/// still being uploaded by another process.
+pub(crate) async fn saved_upload_ids(dir: &Path) -> Vec<String> {
+    let mut upload_ids = Vec::new();
+    for sidecar in find_sidecars(dir).await.into_values().flatten() {
+        if let Some(state) = read_sidecar(&sidecar).await {
+            upload_ids.push(state.upload_id);
+        }
+    }
+    upload_ids
+}
+
+/// Find the multipart uploads left unfinished in a spool directory, grouped by spool file. A spool file another
+/// process has locked is still being uploaded by it, and is left alone. State whose spool file is gone or no longer the
+/// size it was can't be resumed, and is removed.
+pub(crate) async fn find_unfinished(dir: &Path) -> Vec<Unfinished> {
+    let mut unfinished = Vec::new();
+    for (spool, sidecars) in find_sidecars(dir).await {
+        let lock = match SpoolLock::try_acquire(&spool).await {
+            Ok(Some(lock)) => lock,
+            Ok(None) => {
+                debug!("{spool:?} is locked by another process; leaving its uploads to it");
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                warn!("Spool file for unfinished uploads {sidecars:?} is missing; not resuming them");
+                for sidecar in &sidecars {
+                    remove_sidecar(sidecar).await;
+                }
+                continue;
+            }
+            Err(e) => {
+                error!("Unable to lock {spool:?} to resume its uploads: {e}");
+                continue;
+            }
+        };
+        let size = tokio::fs::metadata(&spool).await.map(|m| m.len()).ok();
+
+        // The state is read once the lock is held, so it can't be from an upload that has since finished.
+        let mut uploads = Vec::new();
+        for sidecar in sidecars {
+            let Some(state) = read_sidecar(&sidecar).await else {
+                continue;
+            };
+
+            if size == Some(state.size) {
+                debug!("Found unfinished upload of {spool:?} to s3://{}/{}", state.bucket, state.key);
+                uploads.push(state);
+            } else {
+                warn!("Spool file for unfinished upload {sidecar:?} is missing or has changed; not resuming it");
+                remove_sidecar(&sidecar).await;
+            }
+        }
+
+        if !uploads.is_empty() {
+            unfinished.push(Unfinished {
+                spool,
+                uploads,
+                lock,
+            });
+        }
+    }
+
+    unfinished
+}
+
+/// Compute the part checksums of a spool file, as they were computed when it was written.
+pub(crate) async fn checksum_file(
+    path: &Path,
+    algorithm: ChecksumAlgorithm,
+    part_size: u64,
+) -> Result<ObjectChecksums, IOError> {
+    let mut file = File::open(path).await?;
+    let mut checksums = PartChecksums::new(algorithm, part_size);
+    let mut buf = vec![0; 65536];
+    loop {
+        let n_read = file.read(&mut buf).await?;
+        if n_read == 0 {
+            break;
+        }
+        checksums.update(&buf[..n_read]);
+    }
+    Ok(checksums.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{MultipartState, SavedPart, SpoolLock, find_unfinished},
+        crate::{checksum::ChecksumAlgorithm, ledger::BatchStats},
+        std::{fs::write, path::Path},
+        tempfile::tempdir,
+    };
+
+    /// The spool files and states of the unfinished uploads in `dir`, in destination order.
+    async fn unfinished(dir: &Path) -> Vec<(std::path::PathBuf, Vec<MultipartState>)> {
+        find_unfinished(dir)
+            .await
+            .into_iter()
+            .map(|mut unfinished| {
+                unfinished.uploads.sort_by_key(|state| state.destination_index);
+                (unfinished.spool, unfinished.uploads)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_find_unfinished() {
+        let dir = tempdir().unwrap();
+        let spool = dir.path().join(".tmpabc");
+        write(&spool, b"0123456789").unwrap();
+
+        let mut state = MultipartState {
+            pipeline: "web".to_string(),
+            bucket: "logs".to_string(),
+            key: "web/1.log".to_string(),
+            upload_id: "upload-1".to_string(),
+            size: 10,
+            part_size: 4,
+            compressed: false,
+            checksum_algorithm: Some(ChecksumAlgorithm::Crc32c),
+            no_overwrite: true,
+            acl: Some("bucket-owner-full-control".to_string()),
+            expected_bucket_owner: None,
+            bucket_region: Some("us-west-2".to_string()),
+            destination_index: 0,
+            target_count: 2,
+            verify: true,
+            manifest_key: Some("_manifest/web1/2026/10/18/11.json".to_string()),
+            manifest_pattern: Some("_manifest/{host_id}/{year}/{month}/{day}/{hour}.json".to_string()),
+            server_side_encryption: None,
+            sse_kms_key_id: None,
+            storage_class: None,
+            stats: BatchStats::default(),
+            parts: Vec::new(),
+        };
+        assert_eq!(state.part_count(), 3);
+        state.save(&spool).await.unwrap();
+        state.parts.push(SavedPart {
+            part_number: 1,
+            e_tag: "\"abc\"".to_string(),
+        });
+        state.save(&spool).await.unwrap();
+
+        assert_eq!(unfinished(dir.path()).await, vec![(spool.clone(), vec![state.clone()])]);
+
+        // Uploads of the same spool file to other destinations are saved alongside.
+        let mut extra = state.clone();
+        extra.destination_index = 1;
+        extra.bucket = "archive".to_string();
+        (extra.manifest_key, extra.manifest_pattern) = (None, None);
+        extra.storage_class = Some("GLACIER_IR".to_string());
+        extra.save(&spool).await.unwrap();
+        assert!(dir.path().join(".tmpabc.1.mpu.json").exists());
+
+        assert_eq!(unfinished(dir.path()).await, vec![(spool.clone(), vec![state.clone(), extra.clone()])]);
+
+        // A spool file another process has locked is still being uploaded by it, and is left alone, even if it has
+        // changed.
+        #[cfg(unix)]
+        {
+            let lock = SpoolLock::try_acquire(&spool).await.unwrap().unwrap();
+            assert!(SpoolLock::try_acquire(&spool).await.unwrap().is_none());
+            assert!(find_unfinished(dir.path()).await.is_empty());
+            write(&spool, b"01234").unwrap();
+            assert!(find_unfinished(dir.path()).await.is_empty());
+            assert!(dir.path().join(".tmpabc.mpu.json").exists());
+            assert!(dir.path().join(".tmpabc.1.mpu.json").exists());
+            drop(lock);
+        }
+
+        // A spool file that has changed can't be resumed, and its state is cleaned up.
+        write(&spool, b"01234").unwrap();
+        assert!(find_unfinished(dir.path()).await.is_empty());
+        assert!(!dir.path().join(".tmpabc.mpu.json").exists());
+        assert!(!dir.path().join(".tmpabc.1.mpu.json").exists());
+    }
+}
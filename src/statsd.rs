@@ -0,0 +1,182 @@
+use {
+    clap::ValueEnum,
+    log::{debug, info},
+    serde::Deserialize,
+    std::{
+        fmt::Write,
+        io::{Error as IOError, ErrorKind},
+        net::{ToSocketAddrs, UdpSocket},
+        sync::OnceLock,
+    },
+};
+
+/// The default prefix for metric names.
+pub(crate) const DEFAULT_PREFIX: &str = "stream_logs_to_s3";
+
+/// The sink metrics are pushed to, if configured.
+static STATSD: OnceLock<StatsdSink> = OnceLock::new();
+
+/// The line format to send.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StatsdFormat {
+    /// Plain StatsD. Tags are dropped.
+    Statsd,
+
+    /// DogStatsD, with tags.
+    #[default]
+    Dogstatsd,
+}
+
+/// Where and how to push metrics.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StatsdOptions {
+    /// The agent's address, e.g. `127.0.0.1:8125`.
+    pub addr: String,
+    #[serde(default)]
+    pub format: StatsdFormat,
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+fn default_prefix() -> String {
+    DEFAULT_PREFIX.to_string()
+}
+
+/// A connected, non-blocking UDP socket. Sends never wait: if the agent is down or the socket buffer is full, the
+/// metric is dropped.
+struct StatsdSink {
+    socket: UdpSocket,
+    format: StatsdFormat,
+    prefix: String,
+
+    /// Tags added to every metric, already formatted.
+    global_tags: String,
+}
+
+/// Start pushing metrics to the agent in `options`, tagging every metric with `host_id`.
+pub(crate) fn init(options: &StatsdOptions, host_id: &str) -> Result<(), IOError> {
+    let addr = options
+        .addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| IOError::new(ErrorKind::NotFound, format!("{} did not resolve", options.addr)))?;
+    let bind_addr = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(addr)?;
+    socket.set_nonblocking(true)?;
+
+    let mut global_tags = String::new();
+    push_tag(&mut global_tags, "host_id", host_id);
+
+    let sink = StatsdSink {
+        socket,
+        format: options.format,
+        prefix: options.prefix.clone(),
+        global_tags,
+    };
+
+    if STATSD.set(sink).is_err() {
+        debug!("StatsD sink already initialized");
+    } else {
+        info!("Sending metrics to StatsD at {addr}");
+    }
+    Ok(())
+}
+
+/// Add `value` to a counter.
+pub(crate) fn count(name: &str, value: u64, tags: &[(&str, &str)]) {
+    send(name, &value.to_string(), "c", tags);
+}
+
+/// Set a gauge.
+pub(crate) fn gauge(name: &str, value: u64, tags: &[(&str, &str)]) {
+    send(name, &value.to_string(), "g", tags);
+}
+
+/// Record a duration, in milliseconds.
+pub(crate) fn timing(name: &str, millis: f64, tags: &[(&str, &str)]) {
+    send(name, &format!("{millis:.3}"), "ms", tags);
+}
+
+fn send(name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+    let Some(sink) = STATSD.get() else {
+        return;
+    };
+
+    let line = sink.format_line(name, value, kind, tags);
+    if let Err(e) = sink.socket.send(line.as_bytes()) {
+        // Expected while the agent is down; don't flood the log.
+        debug!("Dropped StatsD metric {name}: {e}");
+    }
+}
+
+impl StatsdSink {
+    fn format_line(&self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) -> String {
+        let mut line = format!("{}.{name}:{value}|{kind}", self.prefix);
+        if self.format == StatsdFormat::Dogstatsd {
+            let mut all_tags = self.global_tags.clone();
+            for (key, value) in tags {
+                push_tag(&mut all_tags, key, value);
+            }
+            if !all_tags.is_empty() {
+                let _ = write!(line, "|#{all_tags}");
+            }
+        }
+        line
+    }
+}
+
+/// Append `key:value` to a comma-separated tag list, replacing characters that would break the line format.
+fn push_tag(tags: &mut String, key: &str, value: &str) {
+    if !tags.is_empty() {
+        tags.push(',');
+    }
+    let value: String = value
+        .chars()
+        .map(|c| {
+            if matches!(c, ',' | '|' | '#' | '\n') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let _ = write!(tags, "{key}:{value}");
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{StatsdFormat, StatsdSink, push_tag},
+        std::net::UdpSocket,
+    };
+
+    #[test]
+    fn test_format_line() {
+        let mut global_tags = String::new();
+        push_tag(&mut global_tags, "host_id", "i-0123,4567");
+        let mut sink = StatsdSink {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            format: StatsdFormat::Dogstatsd,
+            prefix: "slts".to_string(),
+            global_tags,
+        };
+
+        assert_eq!(
+            sink.format_line("ingested_bytes", "42", "c", &[("pipeline", "web"), ("bucket", "logs")]),
+            "slts.ingested_bytes:42|c|#host_id:i-0123_4567,pipeline:web,bucket:logs"
+        );
+
+        sink.format = StatsdFormat::Statsd;
+        assert_eq!(
+            sink.format_line("upload_duration", "1.500", "ms", &[("pipeline", "web")]),
+            "slts.upload_duration:1.500|ms"
+        );
+    }
+}
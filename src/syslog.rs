@@ -0,0 +1,584 @@
+use {
+    crate::TemplateVars,
+    log::{debug, error, info},
+    serde::{Deserialize, Serialize},
+    std::{io::Error as IOError, net::SocketAddr},
+    tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::mpsc::Sender,
+    },
+};
+
+/// The template variables each record provides; see [`SyslogMessage::template_vars`].
+pub(crate) const SYSLOG_VARIABLES: &[&str] = &["facility", "severity", "hostname"];
+
+/// The default limit on the number of batches built at once; see [`SyslogOptions::max_partitions`].
+pub(crate) const DEFAULT_MAX_PARTITIONS: usize = 1000;
+
+/// The `{hostname}` given to messages that arrive once the partition limit has been reached.
+const OVERFLOW_HOSTNAME: &str = "other";
+
+/// Facility names, indexed by facility code (RFC 5424 section 6.2.1).
+const FACILITY_NAMES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+/// Severity names, indexed by severity code.
+const SEVERITY_NAMES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+/// The priority assumed for messages without a PRI part (user.notice), per RFC 3164 section 4.3.3.
+const DEFAULT_PRI: u8 = 13;
+
+/// The largest message we accept over UDP.
+const MAX_UDP_MESSAGE_SIZE: usize = 65535;
+
+/// The largest message we accept over TCP. Octet counts larger than this are treated as a framing error.
+const MAX_TCP_MESSAGE_SIZE: usize = 1 << 20;
+
+/// The size of reads from TCP connections.
+const TCP_READ_SIZE: usize = 65536;
+
+/// Month abbreviations used by RFC 3164 timestamps.
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// The syslog listeners to run.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SyslogOptions {
+    pub udp: Vec<SocketAddr>,
+    pub tcp: Vec<SocketAddr>,
+    pub json: bool,
+
+    /// The most batching loops to run at once, one for each combination of the variables the template uses.
+    pub max_partitions: usize,
+}
+
+impl Default for SyslogOptions {
+    fn default() -> Self {
+        Self {
+            udp: Vec::new(),
+            tcp: Vec::new(),
+            json: false,
+            max_partitions: DEFAULT_MAX_PARTITIONS,
+        }
+    }
+}
+
+impl SyslogOptions {
+    pub fn is_empty(&self) -> bool {
+        self.udp.is_empty() && self.tcp.is_empty()
+    }
+
+    /// Bind every listener, then serve them in the background. Records are sent to `sender` along with their facility,
+    /// severity and hostname as template variables. Binding errors are returned before anything is spawned.
+    pub async fn start(self, sender: Sender<(TemplateVars, Vec<u8>)>) -> Result<(), IOError> {
+        let mut udp_sockets = Vec::with_capacity(self.udp.len());
+        for addr in &self.udp {
+            udp_sockets.push(UdpSocket::bind(addr).await?);
+            info!("Listening for syslog on udp://{addr}");
+        }
+
+        let mut tcp_listeners = Vec::with_capacity(self.tcp.len());
+        for addr in &self.tcp {
+            tcp_listeners.push(TcpListener::bind(addr).await?);
+            info!("Listening for syslog on tcp://{addr}");
+        }
+
+        for socket in udp_sockets {
+            tokio::spawn(serve_udp(socket, self.json, sender.clone()));
+        }
+
+        for listener in tcp_listeners {
+            tokio::spawn(serve_tcp(listener, self.json, sender.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+/// A parsed syslog message. Fields that are absent (or the RFC 5424 NILVALUE `-`) are `None`.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct SyslogMessage {
+    pub facility: &'static str,
+    pub severity: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proc_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub structured_data: Option<String>,
+    pub message: String,
+}
+
+impl SyslogMessage {
+    /// Parse an RFC 5424 or RFC 3164 message. This never fails: anything that doesn't look like syslog becomes the
+    /// message body of a user.notice message.
+    pub fn parse(data: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(data);
+        let text = text.trim_end_matches(['\r', '\n', '\0']);
+        let (pri, rest) = parse_pri(text);
+
+        let mut msg = Self {
+            facility: FACILITY_NAMES[(pri >> 3) as usize],
+            severity: SEVERITY_NAMES[(pri & 7) as usize],
+            timestamp: None,
+            hostname: None,
+            app_name: None,
+            proc_id: None,
+            msg_id: None,
+            structured_data: None,
+            message: String::new(),
+        };
+
+        match rest.strip_prefix("1 ") {
+            Some(rest) => msg.parse_rfc5424(rest),
+            None => msg.parse_rfc3164(rest),
+        }
+
+        msg
+    }
+
+    /// Parse the part of an RFC 5424 message following the version.
+    fn parse_rfc5424(&mut self, rest: &str) {
+        let mut fields = rest.splitn(6, ' ');
+        self.timestamp = nil_value(fields.next());
+        self.hostname = nil_value(fields.next());
+        self.app_name = nil_value(fields.next());
+        self.proc_id = nil_value(fields.next());
+        self.msg_id = nil_value(fields.next());
+        let rest = fields.next().unwrap_or("");
+
+        let (structured_data, message) = split_structured_data(rest);
+        self.structured_data = structured_data.map(str::to_string);
+        self.message = message.trim_start_matches('\u{feff}').to_string();
+    }
+
+    /// Parse the part of an RFC 3164 (BSD) message following the PRI. Senders vary widely here, so the timestamp and
+    /// hostname are only taken if they look right.
+    fn parse_rfc3164(&mut self, rest: &str) {
+        let mut rest = rest;
+
+        if is_rfc3164_timestamp(rest) {
+            self.timestamp = Some(rest[..15].to_string());
+            rest = rest[15..].trim_start_matches(' ');
+
+            // The hostname is the next word, unless that word is actually the tag.
+            if let Some((word, after)) = rest.split_once(' ')
+                && !word.is_empty()
+                && !word.ends_with(':')
+                && !word.contains('[')
+            {
+                self.hostname = Some(word.to_string());
+                rest = after;
+            }
+        }
+
+        // The tag is the program name, optionally followed by [pid], and terminated by a colon.
+        if let Some((tag, message)) = rest.split_once(": ")
+            && !tag.is_empty()
+            && !tag.contains(' ')
+        {
+            match tag.split_once('[') {
+                Some((app_name, pid)) => {
+                    self.app_name = Some(app_name.to_string());
+                    self.proc_id = Some(pid.trim_end_matches(']').to_string());
+                }
+                None => self.app_name = Some(tag.to_string()),
+            }
+            rest = message;
+        }
+
+        self.message = rest.to_string();
+    }
+
+    /// The template variables provided by this message. The hostname comes from the sender, so it's sanitized to keep
+    /// it from adding path components to the object name: anything but letters, digits, dots and hyphens becomes `_`,
+    /// as does a hostname of only dots (`.` or `..`).
+    pub fn template_vars(&self) -> TemplateVars {
+        let hostname = match &self.hostname {
+            Some(h) if h.chars().all(|c| c == '.') => "_".to_string(),
+            Some(h) => h
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect(),
+            None => "-".to_string(),
+        };
+
+        vec![("facility", self.facility.to_string()), ("severity", self.severity.to_string()), ("hostname", hostname)]
+    }
+}
+
+/// Replace the unbounded variables of a message that arrived once the partition limit was reached with a placeholder.
+/// Facilities and severities are few, so only the hostname is replaced.
+pub(crate) fn overflow_vars(mut vars: TemplateVars) -> TemplateVars {
+    for (name, value) in &mut vars {
+        if *name == "hostname" {
+            *value = OVERFLOW_HOSTNAME.to_string();
+        }
+    }
+    vars
+}
+
+/// Parse the `<PRI>` prefix, returning the priority and the remainder of the message.
+fn parse_pri(text: &str) -> (u8, &str) {
+    if let Some(rest) = text.strip_prefix('<')
+        && let Some((digits, rest)) = rest.split_once('>')
+        && !digits.is_empty()
+        && digits.len() <= 3
+        && let Ok(pri) = digits.parse::<u8>()
+        && pri <= 191
+    {
+        (pri, rest)
+    } else {
+        (DEFAULT_PRI, text)
+    }
+}
+
+/// Map the RFC 5424 NILVALUE to `None`.
+fn nil_value(field: Option<&str>) -> Option<String> {
+    match field {
+        None | Some("-") | Some("") => None,
+        Some(s) => Some(s.to_string()),
+    }
+}
+
+/// Split RFC 5424 structured data (one or more bracketed elements, or `-`) from the message that follows it.
+fn split_structured_data(rest: &str) -> (Option<&str>, &str) {
+    if let Some(message) = rest.strip_prefix('-') {
+        return (None, message.strip_prefix(' ').unwrap_or(message));
+    }
+
+    let bytes = rest.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len() && bytes[pos] == b'[' {
+        // Find the closing bracket, skipping escaped characters and quoted parameter values.
+        let mut in_quotes = false;
+        pos += 1;
+        while pos < bytes.len() {
+            match bytes[pos] {
+                b'\\' => pos += 1,
+                b'"' => in_quotes = !in_quotes,
+                b']' if !in_quotes => break,
+                _ => (),
+            }
+            pos += 1;
+        }
+        pos += 1;
+    }
+
+    let pos = pos.min(bytes.len());
+    if pos == 0 {
+        return (None, rest);
+    }
+
+    let message = &rest[pos..];
+    (Some(&rest[..pos]), message.strip_prefix(' ').unwrap_or(message))
+}
+
+/// Whether `text` starts with an RFC 3164 timestamp such as `Oct 11 22:14:15` (day padded with a space).
+fn is_rfc3164_timestamp(text: &str) -> bool {
+    let b = text.as_bytes();
+    b.len() >= 15
+        && text.is_char_boundary(15)
+        && text.get(..3).is_some_and(|month| MONTHS.contains(&month))
+        && b[3] == b' '
+        && (b[4] == b' ' || b[4].is_ascii_digit())
+        && b[5].is_ascii_digit()
+        && b[6] == b' '
+        && b[9] == b':'
+        && b[12] == b':'
+        && [7, 8, 10, 11, 13, 14].iter().all(|&i| b[i].is_ascii_digit())
+}
+
+/// Convert a received message into a record for the batching loop.
+fn to_record(data: &[u8], json: bool) -> Option<(TemplateVars, Vec<u8>)> {
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    if data.is_empty() {
+        return None;
+    }
+
+    let msg = SyslogMessage::parse(data);
+    let mut line = if json {
+        match serde_json::to_vec(&msg) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Unable to convert syslog message to JSON: {e}");
+                return None;
+            }
+        }
+    } else {
+        data.to_vec()
+    };
+    line.push(b'\n');
+
+    Some((msg.template_vars(), line))
+}
+
+/// Receive syslog messages over UDP; each datagram is one message.
+async fn serve_udp(socket: UdpSocket, json: bool, sender: Sender<(TemplateVars, Vec<u8>)>) {
+    let mut buf = vec![0u8; MAX_UDP_MESSAGE_SIZE];
+    loop {
+        let n_read = tokio::select! {
+            _ = sender.closed() => return,
+            result = socket.recv_from(&mut buf) => match result {
+                Ok((n_read, _)) => n_read,
+                Err(e) => {
+                    error!("Failed to receive syslog datagram: {e}");
+                    continue;
+                }
+            },
+        };
+
+        if let Some(record) = to_record(&buf[..n_read], json)
+            && sender.send(record).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Accept syslog connections over TCP.
+async fn serve_tcp(listener: TcpListener, json: bool, sender: Sender<(TemplateVars, Vec<u8>)>) {
+    loop {
+        tokio::select! {
+            _ = sender.closed() => return,
+            result = listener.accept() => match result {
+                Ok((stream, peer)) => {
+                    debug!("Accepted syslog connection from {peer}");
+                    tokio::spawn(handle_tcp(stream, json, sender.clone()));
+                }
+                Err(e) => error!("Failed to accept syslog connection: {e}"),
+            },
+        }
+    }
+}
+
+/// Read messages from a single TCP connection.
+async fn handle_tcp(mut stream: TcpStream, json: bool, sender: Sender<(TemplateVars, Vec<u8>)>) {
+    let mut framer = SyslogFramer::default();
+    let mut buf = vec![0u8; TCP_READ_SIZE];
+
+    loop {
+        let n_read = match stream.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n_read) => n_read,
+            Err(e) => {
+                error!("Failed to read from syslog connection: {e}");
+                break;
+            }
+        };
+
+        framer.push(&buf[..n_read]);
+        while let Some(frame) = framer.next_frame() {
+            if let Some(record) = to_record(&frame, json)
+                && sender.send(record).await.is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    if let Some(frame) = framer.finish()
+        && let Some(record) = to_record(&frame, json)
+    {
+        let _ = sender.send(record).await;
+    }
+}
+
+/// Splits a TCP byte stream into syslog messages. Both framings from RFC 6587 are accepted and can be mixed on the
+/// same connection: octet counting (`<length> <message>`) and non-transparent framing (newline-terminated).
+#[derive(Default)]
+pub(crate) struct SyslogFramer {
+    buf: Vec<u8>,
+}
+
+impl SyslogFramer {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Return the next complete message, if one is available.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        // Skip any blank lines between messages.
+        let start = self.buf.iter().position(|&b| b != b'\n' && b != b'\r').unwrap_or(self.buf.len());
+        self.buf.drain(..start);
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        if self.buf[0].is_ascii_digit() {
+            let digits = self.buf.iter().take_while(|b| b.is_ascii_digit()).count();
+            match self.buf.get(digits) {
+                // Still waiting for the rest of the length.
+                None if digits < 8 => return None,
+                Some(b' ') => {
+                    let length = std::str::from_utf8(&self.buf[..digits]).ok()?.parse::<usize>().ok();
+                    if let Some(length) = length.filter(|&l| l <= MAX_TCP_MESSAGE_SIZE) {
+                        if self.buf.len() < digits + 1 + length {
+                            return None;
+                        }
+                        let frame = self.buf[digits + 1..digits + 1 + length].to_vec();
+                        self.buf.drain(..digits + 1 + length);
+                        return Some(frame);
+                    }
+                }
+                // Not an octet count after all; fall through to newline framing.
+                _ => (),
+            }
+        }
+
+        match self.buf.iter().position(|&b| b == b'\n') {
+            Some(pos) => {
+                let mut frame: Vec<u8> = self.buf.drain(..=pos).collect();
+                frame.pop();
+                if frame.last() == Some(&b'\r') {
+                    frame.pop();
+                }
+                Some(frame)
+            }
+            None if self.buf.len() >= MAX_TCP_MESSAGE_SIZE => Some(std::mem::take(&mut self.buf)),
+            None => None,
+        }
+    }
+
+    /// Return whatever is left when the connection closes.
+    pub fn finish(mut self) -> Option<Vec<u8>> {
+        let frame = self.next_frame();
+        if frame.is_some() {
+            return frame;
+        }
+
+        if self.buf.is_empty() {
+            None
+        } else {
+            Some(self.buf)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SyslogFramer, SyslogMessage, overflow_vars};
+
+    #[test]
+    fn test_parse_rfc5424() {
+        let msg = SyslogMessage::parse(
+            br#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut="3" eventID="1011"] An application event"#,
+        );
+        assert_eq!(msg.facility, "local4");
+        assert_eq!(msg.severity, "notice");
+        assert_eq!(msg.timestamp.as_deref(), Some("2003-10-11T22:14:15.003Z"));
+        assert_eq!(msg.hostname.as_deref(), Some("mymachine.example.com"));
+        assert_eq!(msg.app_name.as_deref(), Some("evntslog"));
+        assert_eq!(msg.proc_id, None);
+        assert_eq!(msg.msg_id.as_deref(), Some("ID47"));
+        assert_eq!(msg.structured_data.as_deref(), Some(r#"[exampleSDID@32473 iut="3" eventID="1011"]"#));
+        assert_eq!(msg.message, "An application event");
+
+        let msg = SyslogMessage::parse(b"<34>1 2003-10-11T22:14:15.003Z host su - - - 'su root' failed");
+        assert_eq!((msg.facility, msg.severity), ("auth", "crit"));
+        assert_eq!(msg.structured_data, None);
+        assert_eq!(msg.message, "'su root' failed");
+    }
+
+    #[test]
+    fn test_parse_rfc3164() {
+        let msg = SyslogMessage::parse(b"<34>Oct 11 22:14:15 mymachine su[123]: 'su root' failed for lonvick");
+        assert_eq!((msg.facility, msg.severity), ("auth", "crit"));
+        assert_eq!(msg.timestamp.as_deref(), Some("Oct 11 22:14:15"));
+        assert_eq!(msg.hostname.as_deref(), Some("mymachine"));
+        assert_eq!(msg.app_name.as_deref(), Some("su"));
+        assert_eq!(msg.proc_id.as_deref(), Some("123"));
+        assert_eq!(msg.message, "'su root' failed for lonvick");
+
+        // No hostname, as sent by many local loggers.
+        let msg = SyslogMessage::parse(b"<13>Feb  5 17:32:18 cron: job done");
+        assert_eq!(msg.hostname, None);
+        assert_eq!(msg.app_name.as_deref(), Some("cron"));
+        assert_eq!(msg.message, "job done");
+
+        // Not syslog at all.
+        let msg = SyslogMessage::parse(b"just some text");
+        assert_eq!((msg.facility, msg.severity), ("user", "notice"));
+        assert_eq!(msg.message, "just some text");
+
+        // Multibyte text where the month would be isn't a timestamp.
+        let msg = SyslogMessage::parse("<13>abé 11 22:14:15 host: hi".as_bytes());
+        assert_eq!(msg.timestamp, None);
+        assert_eq!(msg.message, "abé 11 22:14:15 host: hi");
+    }
+
+    #[test]
+    fn test_template_vars() {
+        let hostname = |line: &str| {
+            let vars = SyslogMessage::parse(line.as_bytes()).template_vars();
+            vars.into_iter().find(|(name, _)| *name == "hostname").unwrap().1
+        };
+        assert_eq!(hostname("<34>Oct 11 22:14:15 web-1.example.com su: hello"), "web-1.example.com");
+        assert_eq!(hostname("<34>Oct 11 22:14:15 web/1 su: hello"), "web_1");
+
+        // A hostname of only dots would be a path component of its own.
+        assert_eq!(hostname("<34>Oct 11 22:14:15 .. su: hello"), "_");
+        assert_eq!(hostname("<34>1 2026-10-11T22:14:15Z . su - - - hello"), "_");
+        assert_eq!(hostname("<34>Oct 11 22:14:15 ...a su: hello"), "...a");
+    }
+
+    #[test]
+    fn test_overflow_vars() {
+        let msg = SyslogMessage::parse(b"<34>Oct 11 22:14:15 attacker-1234 su: hello");
+        let vars = overflow_vars(msg.template_vars());
+        assert_eq!(
+            vars,
+            vec![("facility", "auth".to_string()), ("severity", "crit".to_string()), ("hostname", "other".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut framer = SyslogFramer::default();
+        framer.push(b"10 <13>hello\n1");
+        assert_eq!(framer.next_frame().unwrap(), b"<13>hello\n");
+        assert_eq!(framer.next_frame(), None);
+        framer.push(b"0 <13>world!<14>newline framed\r\n<15>partial");
+        assert_eq!(framer.next_frame().unwrap(), b"<13>world!");
+        assert_eq!(framer.next_frame().unwrap(), b"<14>newline framed");
+        assert_eq!(framer.next_frame(), None);
+        assert_eq!(framer.finish().unwrap(), b"<15>partial");
+    }
+}
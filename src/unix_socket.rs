@@ -0,0 +1,251 @@
+use {
+    crate::async_utils::LineBuffer,
+    log::{debug, error, info},
+    nix::unistd::{Gid, Group, Uid, User, chown},
+    serde::{Deserialize, Deserializer, de::Error as DeError},
+    std::{
+        fs::{Permissions, remove_file, rename, set_permissions},
+        io::{Error as IOError, ErrorKind},
+        os::unix::fs::{FileTypeExt, PermissionsExt},
+        path::{Path, PathBuf},
+    },
+    tokio::{
+        io::AsyncReadExt,
+        net::{UnixDatagram, UnixListener, UnixStream},
+        sync::mpsc::Sender,
+    },
+};
+
+/// The largest datagram we accept. Anything larger is truncated by the kernel.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// The size of reads from stream connections.
+const STREAM_READ_SIZE: usize = 65536;
+
+/// How to set up the listening Unix domain socket.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UnixSocketOptions {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub datagram: bool,
+    #[serde(default, deserialize_with = "deserialize_socket_mode")]
+    pub mode: Option<u32>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// A bound Unix domain socket, ready to accept log data.
+pub(crate) enum UnixSocketListener {
+    Stream(UnixListener),
+    Datagram(UnixDatagram),
+}
+
+impl UnixSocketListener {
+    /// Bind the socket and apply the requested permissions and ownership. A stale socket left behind at the path by a
+    /// previous run is removed first; any other kind of file is left alone and reported as an error.
+    pub fn bind(options: &UnixSocketOptions) -> Result<Self, IOError> {
+        match std::fs::symlink_metadata(&options.path) {
+            Ok(m) if m.file_type().is_socket() => remove_file(&options.path)?,
+            Ok(_) => {
+                return Err(IOError::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{:?} exists and is not a socket", options.path),
+                ));
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        // Bind inside a private directory and only move the socket into place once its permissions and ownership are
+        // set, so nobody can connect to it in between. The directory sits next to the path so the rename can't cross
+        // filesystems.
+        let parent = match options.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let tmp_dir = tempfile::Builder::new().prefix(".stream-logs-to-s3-").tempdir_in(parent)?;
+        let tmp_path = tmp_dir.path().join("socket");
+
+        let listener = if options.datagram {
+            Self::Datagram(UnixDatagram::bind(&tmp_path)?)
+        } else {
+            Self::Stream(UnixListener::bind(&tmp_path)?)
+        };
+
+        secure_socket(&tmp_path, options)?;
+        rename(&tmp_path, &options.path)?;
+
+        info!("Listening on Unix socket {:?}", options.path);
+        Ok(listener)
+    }
+
+    /// Accept data until the receiving end of the channel is closed. Each stream connection is handled by its own task;
+    /// only complete lines are sent to the channel so concurrent writers never interleave within a line.
+    pub async fn serve(self, sender: Sender<Vec<u8>>) {
+        match self {
+            Self::Stream(listener) => loop {
+                match select_closed(&sender, listener.accept()).await {
+                    None => return,
+                    Some(Ok((stream, _))) => {
+                        tokio::spawn(handle_stream(stream, sender.clone()));
+                    }
+                    Some(Err(e)) => error!("Failed to accept Unix socket connection: {e}"),
+                }
+            },
+            Self::Datagram(socket) => {
+                let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+                loop {
+                    let record = match select_closed(&sender, socket.recv(&mut buf)).await {
+                        None => return,
+                        Some(Ok(0)) => continue,
+                        Some(Ok(n_read)) => datagram_record(&buf[..n_read]),
+                        Some(Err(e)) => {
+                            error!("Failed to receive from Unix datagram socket: {e}");
+                            continue;
+                        }
+                    };
+
+                    if sender.send(record).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Run `fut` unless the channel closes first.
+async fn select_closed<F: Future>(sender: &Sender<Vec<u8>>, fut: F) -> Option<F::Output> {
+    tokio::select! {
+        _ = sender.closed() => None,
+        result = fut => Some(result),
+    }
+}
+
+/// Read lines from a single stream connection.
+async fn handle_stream(mut stream: UnixStream, sender: Sender<Vec<u8>>) {
+    let mut lines = LineBuffer::new();
+    let mut buf = vec![0u8; STREAM_READ_SIZE];
+    debug!("Accepted Unix socket connection");
+
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n_read) => {
+                if let Some(data) = lines.push(&buf[..n_read])
+                    && sender.send(data).await.is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("Failed to read from Unix socket connection: {e}");
+                break;
+            }
+        }
+    }
+
+    if let Some(data) = lines.finish() {
+        let _ = sender.send(data).await;
+    }
+    debug!("Unix socket connection closed");
+}
+
+/// Apply the requested permissions and ownership to the socket at `path`.
+fn secure_socket(path: &Path, options: &UnixSocketOptions) -> Result<(), IOError> {
+    if let Some(mode) = options.mode {
+        set_permissions(path, Permissions::from_mode(mode))?;
+    }
+
+    let uid = options.owner.as_deref().map(lookup_user).transpose()?;
+    let gid = options.group.as_deref().map(lookup_group).transpose()?;
+    if uid.is_some() || gid.is_some() {
+        chown(path, uid, gid)?;
+    }
+
+    Ok(())
+}
+
+/// Each datagram is a single record; make sure it ends with a newline so records don't run together in the batch.
+fn datagram_record(data: &[u8]) -> Vec<u8> {
+    let mut record = data.to_vec();
+    if record.last() != Some(&b'\n') {
+        record.push(b'\n');
+    }
+    record
+}
+
+/// Resolve a user name or numeric uid.
+fn lookup_user(name: &str) -> Result<Uid, IOError> {
+    if let Ok(uid) = name.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+
+    match User::from_name(name)? {
+        Some(user) => Ok(user.uid),
+        None => Err(IOError::new(ErrorKind::NotFound, format!("Unknown user {name:?}"))),
+    }
+}
+
+/// Resolve a group name or numeric gid.
+fn lookup_group(name: &str) -> Result<Gid, IOError> {
+    if let Ok(gid) = name.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+
+    match Group::from_name(name)? {
+        Some(group) => Ok(group.gid),
+        None => Err(IOError::new(ErrorKind::NotFound, format!("Unknown group {name:?}"))),
+    }
+}
+
+/// Parse an octal file mode such as `660` or `0660`.
+pub(crate) fn parse_socket_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("Invalid octal file mode: {s}")),
+    }
+}
+
+/// Deserialize a socket mode written as an octal string, e.g. "0660".
+fn deserialize_socket_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    Option::<String>::deserialize(deserializer)?.map(|s| parse_socket_mode(&s)).transpose().map_err(DeError::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{UnixSocketListener, UnixSocketOptions},
+        std::{os::unix::fs::PermissionsExt, time::Duration},
+        tokio::{io::AsyncWriteExt, net::UnixStream, sync::mpsc::channel, time::timeout},
+    };
+
+    #[tokio::test]
+    async fn test_stream_writers_do_not_interleave() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = UnixSocketOptions {
+            path: dir.path().join("test.sock"),
+            datagram: false,
+            mode: Some(0o600),
+            owner: None,
+            group: None,
+        };
+        let listener = UnixSocketListener::bind(&options).unwrap();
+        assert_eq!(std::fs::metadata(&options.path).unwrap().permissions().mode() & 0o7777, 0o600);
+        let (tx, mut rx) = channel(16);
+        tokio::spawn(listener.serve(tx));
+
+        let mut a = UnixStream::connect(&options.path).await.unwrap();
+        let mut b = UnixStream::connect(&options.path).await.unwrap();
+        a.write_all(b"hello ").await.unwrap();
+        b.write_all(b"second writer\n").await.unwrap();
+        assert_eq!(timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap(), b"second writer\n");
+
+        a.write_all(b"world").await.unwrap();
+        drop(a);
+        assert_eq!(timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap(), b"hello world\n");
+    }
+}
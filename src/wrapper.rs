@@ -0,0 +1,134 @@
+use {
+    crate::{SettingsWatch, run},
+    anyhow::{Result as AnyResult, bail},
+    log::{debug, error, info},
+    std::{ffi::OsString, process::Stdio},
+    tokio::process::Command,
+};
+
+#[cfg(unix)]
+use {
+    nix::{
+        sys::signal::{Signal, kill},
+        unistd::Pid,
+    },
+    std::{io::Result as IOResult, os::unix::process::ExitStatusExt, process::ExitStatus},
+    tokio::{
+        process::Child,
+        select,
+        signal::unix::{SignalKind, signal},
+    },
+};
+
+/// The exit code used when the child's status can't be determined.
+const UNKNOWN_EXIT_CODE: i32 = 1;
+
+/// Launch `command` and ship its stdout and stderr as separate batches, with the `{stream}` template variable set to
+/// `stdout` or `stderr`. Signals sent to us are forwarded to the child, except SIGHUP when `forward_hangup` is unset
+/// because we use it to reload our configuration. Returns the child's exit code once it has exited and the final
+/// batches from both streams have been sent; a child killed by a signal is reported the way shells do, as 128 plus the
+/// signal number.
+pub(crate) async fn run_wrapped(
+    command: Vec<OsString>,
+    settings: SettingsWatch,
+    forward_hangup: bool,
+) -> AnyResult<i32> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("No command specified");
+    };
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    info!("Started {program:?} with pid {:?}", child.id());
+
+    // Both pipes are present since we asked for them above.
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    #[cfg(not(unix))]
+    let (wait, _) = (child.wait(), forward_hangup);
+
+    #[cfg(unix)]
+    let wait = wait_forwarding_signals(&mut child, forward_hangup);
+
+    let (status, stdout_result, stderr_result) = tokio::join!(
+        wait,
+        run(stdout, settings.clone(), vec![("stream", "stdout".to_string())], None),
+        run(stderr, settings, vec![("stream", "stderr".to_string())], None),
+    );
+
+    for (stream, result) in [("stdout", stdout_result), ("stderr", stderr_result)] {
+        if let Err(e) = result {
+            error!("Failed to ship {stream} of {program:?}: {e}");
+        }
+    }
+
+    let status = status?;
+    debug!("{program:?} exited with {status}");
+
+    #[cfg(unix)]
+    if let Some(signal) = status.signal() {
+        return Ok(128 + signal);
+    }
+
+    Ok(status.code().unwrap_or(UNKNOWN_EXIT_CODE))
+}
+
+/// Wait for the child to exit, relaying termination and user signals (and SIGHUP, if `forward_hangup` is set) to it
+/// meanwhile. Signals are only sent while the child hasn't been reaped, so its pid can't have been reused.
+#[cfg(unix)]
+async fn wait_forwarding_signals(child: &mut Child, forward_hangup: bool) -> IOResult<ExitStatus> {
+    let kinds = [
+        (SignalKind::hangup(), Signal::SIGHUP),
+        (SignalKind::interrupt(), Signal::SIGINT),
+        (SignalKind::quit(), Signal::SIGQUIT),
+        (SignalKind::terminate(), Signal::SIGTERM),
+        (SignalKind::user_defined1(), Signal::SIGUSR1),
+        (SignalKind::user_defined2(), Signal::SIGUSR2),
+    ];
+
+    let mut listeners = Vec::with_capacity(kinds.len());
+    for (kind, sig) in kinds {
+        if sig == Signal::SIGHUP && !forward_hangup {
+            continue;
+        }
+
+        match signal(kind) {
+            Ok(listener) => listeners.push((listener, sig)),
+            Err(e) => error!("Unable to listen for {sig}: {e}"),
+        }
+    }
+
+    if listeners.is_empty() {
+        return child.wait().await;
+    }
+
+    loop {
+        let next_signal = futures::future::select_all(
+            listeners.iter_mut().map(|(listener, sig)| Box::pin(async move { listener.recv().await.map(|_| *sig) })),
+        );
+
+        let received = select! {
+            status = child.wait() => return status,
+            (received, _, _) = next_signal => received,
+        };
+
+        match (received, child.id()) {
+            (Some(sig), Some(pid)) => {
+                let pid = Pid::from_raw(pid as i32);
+                debug!("Forwarding {sig} to child {pid}");
+                if let Err(e) = kill(pid, sig) {
+                    error!("Unable to forward {sig} to child {pid}: {e}");
+                }
+            }
+            // The child has been reaped, so its pid may belong to another process now.
+            (Some(_), None) => (),
+            // The signal driver has shut down; nothing more to forward.
+            (None, _) => return child.wait().await,
+        }
+    }
+}